@@ -56,6 +56,14 @@ pub fn init_heap(
     Ok(())
 }
 
+/*
+    Bytes of the heap in use right now
+ */
+pub fn heap_used() -> usize {
+    use x86_64::instructions::interrupts;
+
+    interrupts::without_interrupts(|| ALLOCATOR.lock().used())
+}
 
 pub struct Dummy;
 
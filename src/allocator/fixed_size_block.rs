@@ -1,6 +1,7 @@
 use core::alloc::{GlobalAlloc, Layout};
 use core::ptr::{self, NonNull};
 use core::mem;
+use x86_64::instructions::interrupts;
 
 use crate::allocator::bump::Locked;
 
@@ -13,6 +14,7 @@ struct ListNode {
 pub struct FixedSizeBlockAllocator {
     list_heads: [Option<&'static mut ListNode>; BLOCK_SIZES.len()],
     fallback_allocator: linked_list_allocator::Heap,
+    used: usize, // bytes handed out and not freed yet, counting whole blocks
 }
 
 impl FixedSizeBlockAllocator {
@@ -21,9 +23,14 @@ impl FixedSizeBlockAllocator {
         FixedSizeBlockAllocator {
             list_heads: [EMPTY; BLOCK_SIZES.len()],
             fallback_allocator: linked_list_allocator::Heap::empty(),
+            used: 0,
         }
     }
 
+    pub fn used(&self) -> usize {
+        self.used
+    }
+
     pub unsafe fn init(&mut self, heap_start: usize, heap_size: usize) {
         unsafe { self.fallback_allocator.init(heap_start, heap_size); }
     }
@@ -42,50 +49,68 @@ fn list_index(layout: &Layout) -> Option<usize> {
     BLOCK_SIZES.iter().position(|&s| s >= required_block_size)
 }
 
+// Small allocations take a whole block
+fn allocated_size(layout: &Layout) -> usize {
+    match list_index(layout) {
+        Some(index) => BLOCK_SIZES[index],
+        None => layout.size(),
+    }
+}
+
+// The heap lock is only held with interrupts disabled, a thread preempted while holding it would block all the others
 unsafe impl GlobalAlloc for Locked<FixedSizeBlockAllocator> {
     unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
-        let mut allocator = self.lock();
-        match list_index(&layout) {
-            Some(index) => {
-                match allocator.list_heads[index].take() {
-                    Some(node) => {
-                        allocator.list_heads[index] = node.next.take();
-                        node as *mut ListNode as *mut u8
-                    }
-                    None => {
-                        let block_size = BLOCK_SIZES[index];
-                        let block_align = block_size;
-                        let layout = Layout::from_size_align(block_size, block_align).unwrap();
-                        allocator.fallback_alloc(layout)
+        interrupts::without_interrupts(|| {
+            let mut allocator = self.lock();
+            let ptr = match list_index(&layout) {
+                Some(index) => {
+                    match allocator.list_heads[index].take() {
+                        Some(node) => {
+                            allocator.list_heads[index] = node.next.take();
+                            node as *mut ListNode as *mut u8
+                        }
+                        None => {
+                            let block_size = BLOCK_SIZES[index];
+                            let block_align = block_size;
+                            let layout = Layout::from_size_align(block_size, block_align).unwrap();
+                            allocator.fallback_alloc(layout)
+                        }
                     }
                 }
+                None => allocator.fallback_alloc(layout,)
+            };
+            if !ptr.is_null() {
+                allocator.used += allocated_size(&layout);
             }
-            None => allocator.fallback_alloc(layout,)
-        }
+            ptr
+        })
     }
 
     unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
-        let mut allocator = self.lock();
-        match list_index(&layout) {
-            Some(index) => {
-                let new_node = ListNode{
-                    next: allocator.list_heads[index].take(),
-                };
+        interrupts::without_interrupts(|| {
+            let mut allocator = self.lock();
+            allocator.used -= allocated_size(&layout);
+            match list_index(&layout) {
+                Some(index) => {
+                    let new_node = ListNode{
+                        next: allocator.list_heads[index].take(),
+                    };
 
-                assert!(mem::size_of::<ListNode>() <= BLOCK_SIZES[index]);
-                assert!(mem::align_of::<ListNode>() <= BLOCK_SIZES[index]);
-                let new_node_ptr = ptr as *mut ListNode;
-                unsafe {
-                    new_node_ptr.write(new_node);
-                    allocator.list_heads[index] = Some(&mut *new_node_ptr);
+                    assert!(mem::size_of::<ListNode>() <= BLOCK_SIZES[index]);
+                    assert!(mem::align_of::<ListNode>() <= BLOCK_SIZES[index]);
+                    let new_node_ptr = ptr as *mut ListNode;
+                    unsafe {
+                        new_node_ptr.write(new_node);
+                        allocator.list_heads[index] = Some(&mut *new_node_ptr);
+                    }
                 }
-            }
-            None => {
-                let ptr = NonNull::new(ptr).unwrap();
-                unsafe {
-                    allocator.fallback_allocator.deallocate(ptr, layout);
+                None => {
+                    let ptr = NonNull::new(ptr).unwrap();
+                    unsafe {
+                        allocator.fallback_allocator.deallocate(ptr, layout);
+                    }
                 }
             }
-        }
+        })
     }
 }
\ No newline at end of file
@@ -0,0 +1,253 @@
+/*
+    Linear framebuffer graphics
+
+    The framebuffer comes from the Bochs VBE extensions of QEMU's standard VGA (bga.rs),
+    or from plain VGA mode 13h (320x200, 256 colors) as a fallback (mode13h.rs).
+    Anything else which hands us a linear framebuffer (e.g. a newer bootloader) can use FrameBuffer::new.
+ */
+use crate::graphics::Surface;
+use x86_64::{
+    structures::paging::{mapper::MapToError, FrameAllocator, Mapper, Size4KiB},
+    PhysAddr, VirtAddr,
+};
+
+pub mod console;
+pub mod psf;
+mod bga;
+mod mode13h;
+
+// Virtual address where the framebuffer is mapped
+pub const FRAMEBUFFER_START: u64 = 0x_5555_0000_0000;
+
+/*
+    A 24-bit color, converted to the pixel format of the framebuffer when drawn
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Rgb {
+    pub r: u8,
+    pub g: u8,
+    pub b: u8,
+}
+
+impl Rgb {
+    pub const BLACK: Rgb = Rgb::new(0, 0, 0);
+    pub const WHITE: Rgb = Rgb::new(0xff, 0xff, 0xff);
+
+    pub const fn new(r: u8, g: u8, b: u8) -> Self {
+        Rgb { r, g, b }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PixelFormat {
+    Bgr,    // blue in the lowest byte, what VBE uses for 24 and 32 bits per pixel
+    Rgb,
+    Rgb332, // 8 bit palette set up as rrrgggbb, see mode13h.rs
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FrameBufferInfo {
+    pub width: usize,
+    pub height: usize,
+    pub stride: usize, // bytes per line, may be more than width * bytes_per_pixel
+    pub bytes_per_pixel: usize,
+    pub format: PixelFormat,
+}
+
+impl FrameBufferInfo {
+    pub fn size(&self) -> usize {
+        self.stride * self.height
+    }
+}
+
+#[derive(Debug)]
+pub enum FrameBufferError {
+    NoDevice,
+    UnsupportedMode,
+    MapFailed(MapToError<Size4KiB>),
+}
+
+impl From<MapToError<Size4KiB>> for FrameBufferError {
+    fn from(error: MapToError<Size4KiB>) -> Self {
+        FrameBufferError::MapFailed(error)
+    }
+}
+
+/*
+    Pixels of one or more pages of video memory.
+    Drawing goes to the back page, flip() shows it; with a single page the back page is the visible one.
+ */
+pub struct FrameBuffer {
+    buffer: &'static mut [u8],
+    info: FrameBufferInfo,
+    pages: usize,
+    back_page: usize,
+    show_page: Option<fn(usize)>, // makes the hardware scan out from a line of video memory
+}
+
+impl FrameBuffer {
+    /// # Safety
+    /// `start` must point to at least info.size() bytes of mapped framebuffer memory which nobody else uses
+    pub unsafe fn new(start: *mut u8, info: FrameBufferInfo) -> Self {
+        FrameBuffer {
+            buffer: unsafe { core::slice::from_raw_parts_mut(start, info.size()) },
+            info,
+            pages: 1,
+            back_page: 0,
+            show_page: None,
+        }
+    }
+
+    /*
+        `pages` pages of info.size() bytes back to back, `show_page` is called with the first line of a page
+     */
+    unsafe fn with_pages(start: *mut u8, info: FrameBufferInfo, pages: usize, show_page: fn(usize)) -> Self {
+        FrameBuffer {
+            buffer: unsafe { core::slice::from_raw_parts_mut(start, info.size() * pages) },
+            info,
+            pages,
+            back_page: 1,
+            show_page: Some(show_page),
+        }
+    }
+
+    pub fn info(&self) -> FrameBufferInfo {
+        self.info
+    }
+
+    pub fn pages(&self) -> usize {
+        self.pages
+    }
+
+    /*
+        Show the page which was drawn to, and draw to the next one from now on.
+        The new back page still holds an older frame, redraw it completely (or clear() it) before the next flip.
+     */
+    pub fn flip(&mut self) {
+        if let Some(show_page) = self.show_page {
+            show_page(self.back_page * self.info.height);
+            self.back_page = (self.back_page + 1) % self.pages;
+        }
+    }
+
+    /*
+        Move the lines [from, from + count) to start at line `to`, used to scroll text
+     */
+    pub fn copy_lines(&mut self, from: usize, to: usize, count: usize) {
+        let stride = self.info.stride;
+        let page = self.page_offset();
+        let count = count.min(self.info.height - from.max(to));
+        self.buffer.copy_within(page + from * stride..page + (from + count) * stride, page + to * stride);
+    }
+
+    fn page_offset(&self) -> usize {
+        self.back_page * self.info.size()
+    }
+
+    fn pixel_offset(&self, x: usize, y: usize) -> usize {
+        self.page_offset() + y * self.info.stride + x * self.info.bytes_per_pixel
+    }
+}
+
+impl Surface for FrameBuffer {
+    fn width(&self) -> usize {
+        self.info.width
+    }
+
+    fn height(&self) -> usize {
+        self.info.height
+    }
+
+    fn set_pixel(&mut self, x: usize, y: usize, color: Rgb) {
+        if x >= self.info.width || y >= self.info.height {
+            return;
+        }
+        let offset = self.pixel_offset(x, y);
+        let bytes = encode(self.info.format, color);
+        let pixel = &mut self.buffer[offset..offset + self.info.bytes_per_pixel];
+        // SyntaxTip: volatile writes, the compiler can not see that the device reads this memory
+        for (byte, &value) in pixel.iter_mut().zip(bytes.iter()) {
+            unsafe { core::ptr::write_volatile(byte, value) };
+        }
+    }
+
+    fn pixel(&self, x: usize, y: usize) -> Option<Rgb> {
+        if x >= self.info.width || y >= self.info.height {
+            return None;
+        }
+        let offset = self.pixel_offset(x, y);
+        Some(decode(self.info.format, &self.buffer[offset..offset + self.info.bytes_per_pixel]))
+    }
+}
+
+// Bytes of a pixel in memory order
+fn encode(format: PixelFormat, color: Rgb) -> [u8; 4] {
+    match format {
+        PixelFormat::Bgr => [color.b, color.g, color.r, 0],
+        PixelFormat::Rgb => [color.r, color.g, color.b, 0],
+        PixelFormat::Rgb332 => [(color.r & 0xe0) | (color.g & 0xe0) >> 3 | color.b >> 6, 0, 0, 0],
+    }
+}
+
+fn decode(format: PixelFormat, bytes: &[u8]) -> Rgb {
+    match format {
+        PixelFormat::Bgr => Rgb::new(bytes[2], bytes[1], bytes[0]),
+        PixelFormat::Rgb => Rgb::new(bytes[0], bytes[1], bytes[2]),
+        PixelFormat::Rgb332 => Rgb::new(bytes[0] & 0xe0, (bytes[0] << 3) & 0xe0, bytes[0] << 6),
+    }
+}
+
+/*
+    Switch to a width x height graphics mode with 32-bit pixels through the Bochs VBE extensions,
+    falls back to VGA mode 13h when they are not available.
+    Text mode output (vga_buffer) is not visible any more afterwards.
+ */
+pub fn init(
+    width: usize,
+    height: usize,
+    mapper: &mut impl Mapper<Size4KiB>,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+) -> Result<FrameBuffer, FrameBufferError> {
+    init_pages(width, height, 1, mapper, frame_allocator)
+}
+
+/*
+    Like init, with two pages of video memory for flicker free animation: draw the next frame, then flip().
+    Mode 13h has no room for a second page, its framebuffer has a single page where flip() does nothing.
+ */
+pub fn init_double_buffered(
+    width: usize,
+    height: usize,
+    mapper: &mut impl Mapper<Size4KiB>,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+) -> Result<FrameBuffer, FrameBufferError> {
+    init_pages(width, height, 2, mapper, frame_allocator)
+}
+
+fn init_pages(
+    width: usize,
+    height: usize,
+    pages: usize,
+    mapper: &mut impl Mapper<Size4KiB>,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+) -> Result<FrameBuffer, FrameBufferError> {
+    let ((phys_start, info), pages) = match bga::set_mode(width, height, 32, pages) {
+        Ok(mode) => (mode, pages),
+        Err(FrameBufferError::NoDevice) => (mode13h::set_mode(), 1),
+        Err(error) => return Err(error),
+    };
+
+    let virt_start = VirtAddr::new(FRAMEBUFFER_START);
+    let size = info.size() * pages;
+    crate::memory::map_physical_region(mapper, frame_allocator, phys_start, virt_start, size as u64)?;
+
+    let start = virt_start.as_mut_ptr();
+    Ok(if pages > 1 {
+        unsafe { FrameBuffer::with_pages(start, info, pages, bga::show_line) }
+    } else {
+        unsafe { FrameBuffer::new(start, info) }
+    })
+}
+
+// Used by bga.rs and mode13h.rs to report where the hardware put the framebuffer
+type Mode = (PhysAddr, FrameBufferInfo);
@@ -0,0 +1,136 @@
+/*
+    Bochs Graphics Adapter (VBE extensions of QEMU's standard VGA, Bochs and VirtualBox)
+
+    Registers are accessed through an index/data port pair like the CRTC,
+    the linear framebuffer lives at BAR 0 of the PCI display device.
+ */
+use x86_64::{instructions::port::Port, PhysAddr};
+use super::{FrameBufferError, FrameBufferInfo, Mode, PixelFormat};
+
+const INDEX_PORT: u16 = 0x01CE;
+const DATA_PORT: u16 = 0x01CF;
+
+const INDEX_ID: u16 = 0;
+const INDEX_XRES: u16 = 1;
+const INDEX_YRES: u16 = 2;
+const INDEX_BPP: u16 = 3;
+const INDEX_ENABLE: u16 = 4;
+const INDEX_VIRT_WIDTH: u16 = 6;
+const INDEX_VIRT_HEIGHT: u16 = 7;
+const INDEX_X_OFFSET: u16 = 8;
+const INDEX_Y_OFFSET: u16 = 9;
+
+const ID_MIN: u16 = 0xB0C0;
+const ID_MAX: u16 = 0xB0C5;
+const MAX_XRES: usize = 2560;
+const MAX_YRES: usize = 1600;
+
+const ENABLED: u16 = 0x01;
+const LFB_ENABLED: u16 = 0x40;
+
+// Where Bochs puts the framebuffer when we can not find the PCI device
+const DEFAULT_LFB_ADDRESS: u64 = 0xE000_0000;
+
+// (vendor, device) ids of BGA compatible PCI display devices
+const PCI_DEVICES: [(u16, u16); 2] = [
+    (0x1234, 0x1111), // QEMU standard VGA / Bochs
+    (0x80EE, 0xBEEF), // VirtualBox
+];
+
+fn write_register(index: u16, value: u16) {
+    let mut index_port: Port<u16> = Port::new(INDEX_PORT);
+    let mut data_port: Port<u16> = Port::new(DATA_PORT);
+    unsafe {
+        index_port.write(index);
+        data_port.write(value);
+    }
+}
+
+fn read_register(index: u16) -> u16 {
+    let mut index_port: Port<u16> = Port::new(INDEX_PORT);
+    let mut data_port: Port<u16> = Port::new(DATA_PORT);
+    unsafe {
+        index_port.write(index);
+        data_port.read()
+    }
+}
+
+pub fn is_available() -> bool {
+    (ID_MIN..=ID_MAX).contains(&read_register(INDEX_ID))
+}
+
+/*
+    `pages` screens of video memory are reserved below each other, see show_line
+ */
+pub fn set_mode(width: usize, height: usize, bits_per_pixel: u16, pages: usize) -> Result<Mode, FrameBufferError> {
+    if !is_available() {
+        return Err(FrameBufferError::NoDevice);
+    }
+    let virtual_height = height * pages;
+    if width > MAX_XRES || height > MAX_YRES || virtual_height > u16::MAX as usize || !matches!(bits_per_pixel, 24 | 32) {
+        return Err(FrameBufferError::UnsupportedMode);
+    }
+
+    // The mode can only be changed while the extensions are disabled
+    write_register(INDEX_ENABLE, 0);
+    write_register(INDEX_XRES, width as u16);
+    write_register(INDEX_YRES, height as u16);
+    write_register(INDEX_BPP, bits_per_pixel);
+    write_register(INDEX_VIRT_WIDTH, width as u16);
+    write_register(INDEX_VIRT_HEIGHT, virtual_height as u16);
+    write_register(INDEX_X_OFFSET, 0);
+    write_register(INDEX_Y_OFFSET, 0);
+    write_register(INDEX_ENABLE, ENABLED | LFB_ENABLED);
+
+    // The virtual height is cut down when there is not enough video memory for all pages
+    if read_register(INDEX_XRES) as usize != width
+        || read_register(INDEX_YRES) as usize != height
+        || (read_register(INDEX_VIRT_HEIGHT) as usize) < virtual_height
+    {
+        return Err(FrameBufferError::UnsupportedMode);
+    }
+
+    let bytes_per_pixel = bits_per_pixel as usize / 8;
+    let info = FrameBufferInfo {
+        width,
+        height,
+        stride: width * bytes_per_pixel,
+        bytes_per_pixel,
+        format: PixelFormat::Bgr,
+    };
+    Ok((lfb_address(), info))
+}
+
+/*
+    Scan out the screen starting at line `y` of the virtual screen, used for page flipping
+ */
+pub fn show_line(y: usize) {
+    write_register(INDEX_Y_OFFSET, y as u16);
+}
+
+fn lfb_address() -> PhysAddr {
+    for device in 0..32 {
+        let id = pci_config_read(0, device, 0, 0);
+        let (vendor, device_id) = ((id & 0xffff) as u16, (id >> 16) as u16);
+        if PCI_DEVICES.contains(&(vendor, device_id)) {
+            let bar0 = pci_config_read(0, device, 0, 0x10);
+            return PhysAddr::new(u64::from(bar0 & 0xffff_fff0)); // low bits are flags
+        }
+    }
+    PhysAddr::new(DEFAULT_LFB_ADDRESS)
+}
+
+// PCI configuration space access mechanism #1
+fn pci_config_read(bus: u8, device: u8, function: u8, offset: u8) -> u32 {
+    let address = 0x8000_0000
+        | u32::from(bus) << 16
+        | u32::from(device) << 11
+        | u32::from(function) << 8
+        | u32::from(offset & 0xfc);
+    let mut address_port: Port<u32> = Port::new(0xCF8);
+    let mut data_port: Port<u32> = Port::new(0xCFC);
+    unsafe {
+        address_port.write(address);
+        data_port.read()
+    }
+}
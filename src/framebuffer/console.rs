@@ -0,0 +1,224 @@
+use core::fmt;
+use crate::print_lock::PrintLock;
+use crate::vga_buffer::{ansi::{Action, Parser}, cp437};
+use crate::graphics::Surface;
+use super::{psf::{self, Font}, FrameBuffer, Rgb};
+
+const TAB_WIDTH: usize = 8;
+
+// The 16 VGA text mode colors, indexed like the ANSI colors (0..=7 normal, 8..=15 light)
+const PALETTE: [Rgb; 16] = [
+    Rgb::new(0x00, 0x00, 0x00), Rgb::new(0xaa, 0x00, 0x00), Rgb::new(0x00, 0xaa, 0x00), Rgb::new(0xaa, 0x55, 0x00),
+    Rgb::new(0x00, 0x00, 0xaa), Rgb::new(0xaa, 0x00, 0xaa), Rgb::new(0x00, 0xaa, 0xaa), Rgb::new(0xaa, 0xaa, 0xaa),
+    Rgb::new(0x55, 0x55, 0x55), Rgb::new(0xff, 0x55, 0x55), Rgb::new(0x55, 0xff, 0x55), Rgb::new(0xff, 0xff, 0x55),
+    Rgb::new(0x55, 0x55, 0xff), Rgb::new(0xff, 0x55, 0xff), Rgb::new(0x55, 0xff, 0xff), Rgb::new(0xff, 0xff, 0xff),
+];
+const DEFAULT_FOREGROUND: Rgb = PALETTE[11]; // yellow on black, like the text mode console
+const DEFAULT_BACKGROUND: Rgb = PALETTE[0];
+const BRIGHT: usize = 8;
+
+/*
+    Text console drawn with a bitmap font on a framebuffer,
+    the grid is as large as the resolution allows (128x48 characters at 1024x768 with an 8x16 font)
+ */
+pub struct FrameBufferConsole {
+    framebuffer: FrameBuffer,
+    font: Font,
+    columns: usize,
+    rows: usize,
+    column_position: usize,
+    row_position: usize,
+    foreground: Rgb,
+    background: Rgb,
+    bold: bool,
+    parser: Parser,
+}
+
+impl FrameBufferConsole {
+    pub fn new(framebuffer: FrameBuffer, font: Font) -> Self {
+        let info = framebuffer.info();
+        let mut console = FrameBufferConsole {
+            columns: info.width / font.width(),
+            rows: info.height / font.height(),
+            framebuffer,
+            font,
+            column_position: 0,
+            row_position: 0,
+            foreground: DEFAULT_FOREGROUND,
+            background: DEFAULT_BACKGROUND,
+            bold: false,
+            parser: Parser::new(),
+        };
+        console.clear();
+        console
+    }
+
+    // (columns, rows) of the text grid
+    pub fn size(&self) -> (usize, usize) {
+        (self.columns, self.rows)
+    }
+
+    pub fn cursor(&self) -> (usize, usize) {
+        (self.row_position, self.column_position)
+    }
+
+    pub fn set_color(&mut self, foreground: Rgb, background: Rgb) {
+        self.foreground = foreground;
+        self.background = background;
+    }
+
+    pub fn clear(&mut self) {
+        self.framebuffer.clear(self.background);
+        self.row_position = 0;
+        self.column_position = 0;
+    }
+
+    pub fn set_cursor(&mut self, row: usize, col: usize) {
+        self.row_position = row.min(self.rows - 1);
+        self.column_position = col.min(self.columns - 1);
+    }
+
+    pub fn framebuffer(&mut self) -> &mut FrameBuffer {
+        &mut self.framebuffer
+    }
+
+    pub fn write_string(&mut self, s: &str) {
+        for c in s.chars() {
+            match self.parser.advance(c) {
+                // The embedded font is in code page 437 order, like the VGA text mode font
+                Some(Action::Print(c)) => self.write_glyph(cp437::from_char(c).unwrap_or(0xfe)),
+                Some(Action::Execute(byte)) => self.execute(byte),
+                Some(Action::Csi(csi)) => match csi.final_byte {
+                    b'm' if !csi.private => self.apply_sgr(csi.params()),
+                    b'H' | b'f' => self.set_cursor(csi.param_or(0, 1) as usize - 1, csi.param_or(1, 1) as usize - 1),
+                    b'J' if csi.param_or(0, 0) >= 2 => {
+                        let cursor = self.cursor();
+                        self.clear();
+                        self.set_cursor(cursor.0, cursor.1);
+                    }
+                    b'K' if csi.param_or(0, 0) == 0 => {
+                        let width = self.columns - self.column_position;
+                        self.clear_cells(self.row_position, self.column_position, width);
+                    }
+                    _ => {} // cursor movement and the rest are only supported by the text mode console
+                },
+                Some(Action::Esc(_)) | None => {}
+            }
+        }
+    }
+
+    fn execute(&mut self, byte: u8) {
+        match byte {
+            b'\n' => self.new_line(),
+            b'\r' => self.column_position = 0,
+            b'\t' => self.column_position = ((self.column_position / TAB_WIDTH + 1) * TAB_WIDTH).min(self.columns),
+            0x08 => self.column_position = self.column_position.saturating_sub(1),
+            _ => {}
+        }
+    }
+
+    fn apply_sgr(&mut self, params: &[u16]) {
+        if params.is_empty() {
+            self.apply_sgr(&[0]);
+            return;
+        }
+
+        for &param in params {
+            let param = param as usize;
+            let bright = if self.bold { BRIGHT } else { 0 };
+            match param {
+                0 => {
+                    self.foreground = DEFAULT_FOREGROUND;
+                    self.background = DEFAULT_BACKGROUND;
+                    self.bold = false;
+                }
+                1 => self.bold = true,
+                22 => self.bold = false,
+                30..=37 => self.foreground = PALETTE[param - 30 + bright],
+                39 => self.foreground = DEFAULT_FOREGROUND,
+                40..=47 => self.background = PALETTE[param - 40],
+                49 => self.background = DEFAULT_BACKGROUND,
+                90..=97 => self.foreground = PALETTE[param - 90 + BRIGHT],
+                100..=107 => self.background = PALETTE[param - 100 + BRIGHT],
+                _ => {}
+            }
+        }
+    }
+
+    fn write_glyph(&mut self, glyph: u8) {
+        if self.column_position >= self.columns {
+            self.new_line();
+        }
+
+        let (width, height) = (self.font.width(), self.font.height());
+        let x = self.column_position * width;
+        let y = self.row_position * height;
+        for dy in 0..height {
+            for dx in 0..width {
+                let color = if self.font.pixel(glyph as usize, dx, dy) { self.foreground } else { self.background };
+                self.framebuffer.set_pixel(x + dx, y + dy, color);
+            }
+        }
+        self.column_position += 1;
+    }
+
+    fn new_line(&mut self) {
+        if self.row_position + 1 < self.rows {
+            self.row_position += 1;
+        } else {
+            let height = self.font.height();
+            self.framebuffer.copy_lines(height, 0, (self.rows - 1) * height);
+            self.clear_cells(self.rows - 1, 0, self.columns);
+        }
+        self.column_position = 0;
+    }
+
+    fn clear_cells(&mut self, row: usize, col: usize, count: usize) {
+        let (width, height) = (self.font.width(), self.font.height());
+        let (x, y) = ((col * width) as isize, (row * height) as isize);
+        self.framebuffer.fill_rect(x, y, count * width, height, self.background);
+    }
+}
+
+impl fmt::Write for FrameBufferConsole {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.write_string(s);
+        Ok(())
+    }
+}
+
+/*
+    Once set, print!/println! go here instead of the text mode console, which is not visible any more
+ */
+pub static CONSOLE: PrintLock<Option<FrameBufferConsole>> = PrintLock::new(None);
+
+pub fn init(framebuffer: FrameBuffer) {
+    use x86_64::instructions::interrupts;
+
+    let font = psf::Font::parse(psf::VGA_8X16).expect("invalid embedded font");
+    let console = FrameBufferConsole::new(framebuffer, font);
+    interrupts::without_interrupts(|| *CONSOLE.lock() = Some(console));
+}
+
+pub fn is_enabled() -> bool {
+    use x86_64::instructions::interrupts;
+
+    interrupts::without_interrupts(|| CONSOLE.lock().is_some())
+}
+
+/*
+    Print to the framebuffer console, returns false when it is not initialized
+ */
+#[doc(hidden)]
+pub fn _print(args: fmt::Arguments) -> bool {
+    use core::fmt::Write;
+    use x86_64::instructions::interrupts;
+
+    interrupts::without_interrupts(|| match CONSOLE.lock().as_mut() {
+        Some(console) => {
+            console.write_fmt(args).unwrap();
+            true
+        }
+        None => false,
+    })
+}
@@ -0,0 +1,121 @@
+/*
+    VGA mode 13h: 320x200 pixels, one byte per pixel, framebuffer at 0xA0000
+
+    Without a BIOS we have to program the VGA registers ourselves,
+    values from https://www.singlix.com/trdos/archive/vga/Graphics%20in%20pmode.pdf
+    The 256 color palette is loaded as rrrgggbb so 24-bit colors can be converted without a lookup.
+ */
+use x86_64::{instructions::port::Port, PhysAddr};
+use super::{FrameBufferInfo, Mode, PixelFormat};
+
+const WIDTH: usize = 320;
+const HEIGHT: usize = 200;
+const FRAMEBUFFER_ADDRESS: u64 = 0xA0000;
+
+const MISC_OUTPUT_PORT: u16 = 0x3C2;
+const SEQUENCER_PORT: u16 = 0x3C4;
+const CRTC_PORT: u16 = 0x3D4;
+const GRAPHICS_CONTROLLER_PORT: u16 = 0x3CE;
+const ATTRIBUTE_CONTROLLER_PORT: u16 = 0x3C0;
+const INPUT_STATUS_PORT: u16 = 0x3DA; // reading it resets the attribute controller index/data flip-flop
+const DAC_WRITE_INDEX_PORT: u16 = 0x3C8;
+const DAC_DATA_PORT: u16 = 0x3C9;
+
+const MISC_OUTPUT: u8 = 0x63;
+
+// Register values for indices 0, 1, 2, ...
+const SEQUENCER: [u8; 5] = [0x03, 0x01, 0x0F, 0x00, 0x0E];
+const CRTC: [u8; 25] = [
+    0x5F, 0x4F, 0x50, 0x82, 0x54, 0x80, 0xBF, 0x1F,
+    0x00, 0x41, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+    0x9C, 0x0E, 0x8F, 0x28, 0x40, 0x96, 0xB9, 0xA3,
+    0xFF,
+];
+const GRAPHICS_CONTROLLER: [u8; 9] = [0x00, 0x00, 0x00, 0x00, 0x00, 0x40, 0x05, 0x0F, 0xFF];
+const ATTRIBUTE_CONTROLLER: [u8; 21] = [
+    0x00, 0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07,
+    0x08, 0x09, 0x0A, 0x0B, 0x0C, 0x0D, 0x0E, 0x0F,
+    0x41, 0x00, 0x0F, 0x00, 0x00,
+];
+
+const CRTC_VERTICAL_SYNC_END: u8 = 0x11;
+const CRTC_PROTECT: u8 = 0x80; // bit 7 of vertical sync end write-protects CRTC registers 0..=7
+const ATTRIBUTE_ENABLE_DISPLAY: u8 = 0x20;
+
+fn write_indexed(port: u16, index: u8, value: u8) {
+    let mut index_port: Port<u8> = Port::new(port);
+    let mut data_port: Port<u8> = Port::new(port + 1);
+    unsafe {
+        index_port.write(index);
+        data_port.write(value);
+    }
+}
+
+fn read_indexed(port: u16, index: u8) -> u8 {
+    let mut index_port: Port<u8> = Port::new(port);
+    let mut data_port: Port<u8> = Port::new(port + 1);
+    unsafe {
+        index_port.write(index);
+        data_port.read()
+    }
+}
+
+pub fn set_mode() -> Mode {
+    let mut misc_output: Port<u8> = Port::new(MISC_OUTPUT_PORT);
+    unsafe { misc_output.write(MISC_OUTPUT) };
+
+    for (index, &value) in SEQUENCER.iter().enumerate() {
+        write_indexed(SEQUENCER_PORT, index as u8, value);
+    }
+
+    let protect = read_indexed(CRTC_PORT, CRTC_VERTICAL_SYNC_END);
+    write_indexed(CRTC_PORT, CRTC_VERTICAL_SYNC_END, protect & !CRTC_PROTECT);
+    for (index, &value) in CRTC.iter().enumerate() {
+        write_indexed(CRTC_PORT, index as u8, value);
+    }
+
+    for (index, &value) in GRAPHICS_CONTROLLER.iter().enumerate() {
+        write_indexed(GRAPHICS_CONTROLLER_PORT, index as u8, value);
+    }
+
+    // The attribute controller uses a single port for index and data
+    let mut input_status: Port<u8> = Port::new(INPUT_STATUS_PORT);
+    let mut attribute: Port<u8> = Port::new(ATTRIBUTE_CONTROLLER_PORT);
+    unsafe {
+        for (index, &value) in ATTRIBUTE_CONTROLLER.iter().enumerate() {
+            input_status.read();
+            attribute.write(index as u8);
+            attribute.write(value);
+        }
+        input_status.read();
+        attribute.write(ATTRIBUTE_ENABLE_DISPLAY);
+    }
+
+    load_rgb332_palette();
+
+    let info = FrameBufferInfo {
+        width: WIDTH,
+        height: HEIGHT,
+        stride: WIDTH,
+        bytes_per_pixel: 1,
+        format: PixelFormat::Rgb332,
+    };
+    (PhysAddr::new(FRAMEBUFFER_ADDRESS), info)
+}
+
+// The DAC takes 6 bits per channel
+fn load_rgb332_palette() {
+    let mut write_index: Port<u8> = Port::new(DAC_WRITE_INDEX_PORT);
+    let mut data: Port<u8> = Port::new(DAC_DATA_PORT);
+    unsafe {
+        write_index.write(0);
+        for color in 0..=255u16 {
+            let r = (color >> 5) * 63 / 7;
+            let g = ((color >> 2) & 0x07) * 63 / 7;
+            let b = (color & 0x03) * 63 / 3;
+            data.write(r as u8);
+            data.write(g as u8);
+            data.write(b as u8);
+        }
+    }
+}
@@ -0,0 +1,107 @@
+/*
+    PC Screen Font (PSF version 1 and 2) parser, the format of the Linux console fonts
+
+    Glyphs are bitmaps of `height` rows, each row padded to whole bytes, most significant bit on the left.
+ */
+
+const PSF1_MAGIC: [u8; 2] = [0x36, 0x04];
+const PSF1_MODE_512: u8 = 0x01;
+const PSF1_HEADER_SIZE: usize = 4;
+const PSF2_MAGIC: [u8; 4] = [0x72, 0xb5, 0x4a, 0x86];
+const PSF2_HEADER_SIZE: usize = 32;
+
+/*
+    The embedded font: the 8x16 VGA ROM font, glyphs in code page 437 order.
+    The glyphs are the TEXT_8X16_FONT table of the vga crate 0.2.9 (https://github.com/rust-osdev/vga,
+    src/fonts.rs, MIT or Apache-2.0), written out as a PSF1 file: the header 36 04 00 10
+    (magic, 256 glyphs without a Unicode table, 16 bytes per glyph) followed by the table's 4096 bytes.
+ */
+pub static VGA_8X16: &[u8] = include_bytes!("vga8x16.psf");
+
+#[derive(Debug, Clone, Copy)]
+pub struct Font {
+    glyphs: &'static [u8],
+    glyph_count: usize,
+    bytes_per_glyph: usize,
+    width: usize,
+    height: usize,
+}
+
+impl Font {
+    /*
+        None if the data is not a valid PSF1/PSF2 font
+     */
+    pub fn parse(data: &'static [u8]) -> Option<Font> {
+        if data.starts_with(&PSF2_MAGIC) {
+            Self::parse_psf2(data)
+        } else if data.starts_with(&PSF1_MAGIC) {
+            Self::parse_psf1(data)
+        } else {
+            None
+        }
+    }
+
+    fn parse_psf1(data: &'static [u8]) -> Option<Font> {
+        let mode = *data.get(2)?;
+        let height = *data.get(3)? as usize;
+        let glyph_count = if mode & PSF1_MODE_512 != 0 { 512 } else { 256 };
+        let glyphs = data.get(PSF1_HEADER_SIZE..PSF1_HEADER_SIZE + glyph_count * height)?;
+        Some(Font { glyphs, glyph_count, bytes_per_glyph: height, width: 8, height })
+    }
+
+    fn parse_psf2(data: &'static [u8]) -> Option<Font> {
+        let field = |index: usize| -> Option<usize> {
+            let bytes = data.get(index * 4..index * 4 + 4)?;
+            Some(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) as usize)
+        };
+        // magic, version, header size, flags, length, bytes per glyph, height, width
+        let header_size = field(2)?;
+        let glyph_count = field(4)?;
+        let bytes_per_glyph = field(5)?;
+        let height = field(6)?;
+        let width = field(7)?;
+        if header_size < PSF2_HEADER_SIZE || bytes_per_glyph < height * width.div_ceil(8) {
+            return None;
+        }
+        let glyphs = data.get(header_size..header_size + glyph_count * bytes_per_glyph)?;
+        Some(Font { glyphs, glyph_count, bytes_per_glyph, width, height })
+    }
+
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    pub fn height(&self) -> usize {
+        self.height
+    }
+
+    /*
+        Whether pixel (x, y) of glyph `index` is set, out of range glyphs are blank
+     */
+    pub fn pixel(&self, index: usize, x: usize, y: usize) -> bool {
+        if index >= self.glyph_count || x >= self.width || y >= self.height {
+            return false;
+        }
+        let bytes_per_row = self.width.div_ceil(8);
+        let byte = self.glyphs[index * self.bytes_per_glyph + y * bytes_per_row + x / 8];
+        byte & (0x80 >> (x % 8)) != 0
+    }
+}
+
+#[test_case]
+fn test_parse_embedded_font() {
+    let font = Font::parse(VGA_8X16).expect("invalid embedded font");
+    assert_eq!((font.width(), font.height()), (8, 16));
+
+    // The top of the 'A' glyph: ...#....
+    assert!(font.pixel(b'A' as usize, 3, 2));
+    assert!(!font.pixel(b'A' as usize, 2, 2));
+    // The space is blank
+    assert!((0..16).all(|y| (0..8).all(|x| !font.pixel(b' ' as usize, x, y))));
+}
+
+#[test_case]
+fn test_parse_invalid_font() {
+    assert!(Font::parse(&[0, 1, 2, 3]).is_none());
+    assert!(Font::parse(&[0x36, 0x04, 0x00, 16, 0]).is_none()); // truncated
+}
@@ -0,0 +1,364 @@
+/*
+    2D drawing primitives on top of anything with pixels:
+    the framebuffer, or a MemorySurface on the heap (sprites, off-screen drawing and tests).
+
+    Positions are signed so that shapes may stick out of the surface, everything is clipped to its edges.
+ */
+use alloc::{vec, vec::Vec};
+use crate::framebuffer::{psf::Font, Rgb};
+use crate::vga_buffer::cp437;
+
+pub trait Surface {
+    fn width(&self) -> usize;
+    fn height(&self) -> usize;
+
+    // Pixels outside of the surface are ignored
+    fn set_pixel(&mut self, x: usize, y: usize, color: Rgb);
+
+    // None outside of the surface
+    fn pixel(&self, x: usize, y: usize) -> Option<Rgb>;
+
+    fn plot(&mut self, x: isize, y: isize, color: Rgb) {
+        if x >= 0 && y >= 0 {
+            self.set_pixel(x as usize, y as usize, color);
+        }
+    }
+
+    fn clear(&mut self, color: Rgb) {
+        self.fill_rect(0, 0, self.width(), self.height(), color);
+    }
+
+    /*
+        Bresenham's line algorithm, both end points are drawn
+     */
+    fn draw_line(&mut self, x0: isize, y0: isize, x1: isize, y1: isize, color: Rgb) {
+        let dx = (x1 - x0).abs();
+        let dy = -(y1 - y0).abs();
+        let step_x = if x0 < x1 { 1 } else { -1 };
+        let step_y = if y0 < y1 { 1 } else { -1 };
+        let mut error = dx + dy;
+        let (mut x, mut y) = (x0, y0);
+
+        loop {
+            self.plot(x, y, color);
+            if x == x1 && y == y1 {
+                break;
+            }
+            let error2 = 2 * error;
+            if error2 >= dy {
+                error += dy;
+                x += step_x;
+            }
+            if error2 <= dx {
+                error += dx;
+                y += step_y;
+            }
+        }
+    }
+
+    fn draw_rect(&mut self, x: isize, y: isize, width: usize, height: usize, color: Rgb) {
+        if width == 0 || height == 0 {
+            return;
+        }
+        let right = x + width as isize - 1;
+        let bottom = y + height as isize - 1;
+        self.draw_line(x, y, right, y, color);
+        self.draw_line(x, bottom, right, bottom, color);
+        self.draw_line(x, y, x, bottom, color);
+        self.draw_line(right, y, right, bottom, color);
+    }
+
+    fn fill_rect(&mut self, x: isize, y: isize, width: usize, height: usize, color: Rgb) {
+        let x_start = x.max(0) as usize;
+        let y_start = y.max(0) as usize;
+        let x_end = (x + width as isize).clamp(0, self.width() as isize) as usize;
+        let y_end = (y + height as isize).clamp(0, self.height() as isize) as usize;
+        for row in y_start..y_end {
+            for col in x_start..x_end {
+                self.set_pixel(col, row, color);
+            }
+        }
+    }
+
+    /*
+        Midpoint circle algorithm, drawing the eight symmetric octants at once
+     */
+    fn draw_circle(&mut self, center_x: isize, center_y: isize, radius: usize, color: Rgb) {
+        let mut x = radius as isize;
+        let mut y = 0;
+        let mut error = 1 - x;
+
+        while x >= y {
+            for (dx, dy) in [(x, y), (y, x), (-y, x), (-x, y), (-x, -y), (-y, -x), (y, -x), (x, -y)] {
+                self.plot(center_x + dx, center_y + dy, color);
+            }
+            y += 1;
+            if error < 0 {
+                error += 2 * y + 1;
+            } else {
+                x -= 1;
+                error += 2 * (y - x) + 1;
+            }
+        }
+    }
+
+    // Same outline as draw_circle, filled with horizontal spans
+    fn fill_circle(&mut self, center_x: isize, center_y: isize, radius: usize, color: Rgb) {
+        let mut x = radius as isize;
+        let mut y = 0;
+        let mut error = 1 - x;
+
+        while x >= y {
+            for (half_width, dy) in [(x, y), (x, -y), (y, x), (y, -x)] {
+                let left = center_x - half_width;
+                self.fill_rect(left, center_y + dy, 2 * half_width as usize + 1, 1, color);
+            }
+            y += 1;
+            if error < 0 {
+                error += 2 * y + 1;
+            } else {
+                x -= 1;
+                error += 2 * (y - x) + 1;
+            }
+        }
+    }
+
+    /*
+        Copy a bitmap with its top left corner at (x, y), the parts outside of the surface are skipped
+     */
+    fn blit(&mut self, x: isize, y: isize, bitmap: &Bitmap) {
+        // Visible part of the bitmap, in bitmap coordinates
+        let col_start = (-x).max(0) as usize;
+        let row_start = (-y).max(0) as usize;
+        let col_end = (self.width() as isize - x).clamp(0, bitmap.width as isize) as usize;
+        let row_end = (self.height() as isize - y).clamp(0, bitmap.height as isize) as usize;
+
+        for row in row_start..row_end {
+            for col in col_start..col_end {
+                let target_x = (x + col as isize) as usize;
+                let target_y = (y + row as isize) as usize;
+                self.set_pixel(target_x, target_y, bitmap.pixels[row * bitmap.width + col]);
+            }
+        }
+    }
+
+    /*
+        Text with its top left corner at (x, y), the background is left alone when `background` is None
+     */
+    fn draw_text(&mut self, x: isize, y: isize, font: &Font, text: &str, foreground: Rgb, background: Option<Rgb>) {
+        let mut glyph_x = x;
+        for c in text.chars() {
+            let glyph = cp437::from_char(c).unwrap_or(0xfe) as usize;
+            for dy in 0..font.height() {
+                for dx in 0..font.width() {
+                    let (px, py) = (glyph_x + dx as isize, y + dy as isize);
+                    if font.pixel(glyph, dx, dy) {
+                        self.plot(px, py, foreground);
+                    } else if let Some(background) = background {
+                        self.plot(px, py, background);
+                    }
+                }
+            }
+            glyph_x += font.width() as isize;
+        }
+    }
+}
+
+/*
+    A rectangle of pixels in row order, borrowed from a MemorySurface or a static
+ */
+#[derive(Debug, Clone, Copy)]
+pub struct Bitmap<'a> {
+    width: usize,
+    height: usize,
+    pixels: &'a [Rgb],
+}
+
+impl<'a> Bitmap<'a> {
+    pub fn new(width: usize, height: usize, pixels: &'a [Rgb]) -> Self {
+        assert_eq!(pixels.len(), width * height, "bitmap size does not match its pixels");
+        Bitmap { width, height, pixels }
+    }
+
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    pub fn height(&self) -> usize {
+        self.height
+    }
+}
+
+/*
+    Surface on the heap, 3 bytes per pixel, keep it small: the heap is only HEAP_SIZE bytes
+ */
+pub struct MemorySurface {
+    width: usize,
+    height: usize,
+    pixels: Vec<Rgb>,
+}
+
+impl MemorySurface {
+    pub fn new(width: usize, height: usize) -> Self {
+        MemorySurface {
+            width,
+            height,
+            pixels: vec![Rgb::BLACK; width * height],
+        }
+    }
+
+    pub fn as_bitmap(&self) -> Bitmap<'_> {
+        Bitmap::new(self.width, self.height, &self.pixels)
+    }
+
+    /*
+        FNV-1a hash of the pixels, to compare whole drawings in tests
+     */
+    pub fn checksum(&self) -> u32 {
+        self.pixels
+            .iter()
+            .flat_map(|pixel| [pixel.r, pixel.g, pixel.b])
+            .fold(0x811c_9dc5, |hash, byte| (hash ^ u32::from(byte)).wrapping_mul(0x0100_0193))
+    }
+}
+
+impl Surface for MemorySurface {
+    fn width(&self) -> usize {
+        self.width
+    }
+
+    fn height(&self) -> usize {
+        self.height
+    }
+
+    fn set_pixel(&mut self, x: usize, y: usize, color: Rgb) {
+        if x < self.width && y < self.height {
+            self.pixels[y * self.width + x] = color;
+        }
+    }
+
+    fn pixel(&self, x: usize, y: usize) -> Option<Rgb> {
+        if x < self.width && y < self.height {
+            Some(self.pixels[y * self.width + x])
+        } else {
+            None
+        }
+    }
+}
+
+/*
+    Bar chart for status dashboards (memory usage, task counts, ...),
+    one bar per value, scaled so that `max` reaches the top of the frame
+ */
+pub struct BarChart<'a> {
+    pub values: &'a [usize],
+    pub max: usize,
+    pub color: Rgb,
+}
+
+impl BarChart<'_> {
+    pub fn draw(&self, surface: &mut impl Surface, x: isize, y: isize, width: usize, height: usize) {
+        surface.draw_rect(x, y, width, height, Rgb::WHITE);
+        if self.values.is_empty() || self.max == 0 || width < 2 || height < 2 {
+            return;
+        }
+
+        // Inside of the frame
+        let (inner_width, inner_height) = (width - 2, height - 2);
+        let bar_width = inner_width / self.values.len();
+        for (index, &value) in self.values.iter().enumerate() {
+            let bar_height = value.min(self.max) * inner_height / self.max;
+            let bar_x = x + 1 + (index * bar_width) as isize;
+            let bar_y = y + 1 + (inner_height - bar_height) as isize;
+            // One pixel gap between neighbouring bars
+            surface.fill_rect(bar_x, bar_y, bar_width.saturating_sub(1).max(1), bar_height, self.color);
+        }
+    }
+}
+
+#[test_case]
+fn test_draw_line() {
+    let mut surface = MemorySurface::new(16, 16);
+    surface.draw_line(0, 0, 15, 15, Rgb::WHITE);
+    for i in 0..16 {
+        assert_eq!(surface.pixel(i, i), Some(Rgb::WHITE));
+    }
+
+    let mut surface = MemorySurface::new(16, 16);
+    surface.draw_line(1, 2, 14, 9, Rgb::WHITE);
+    surface.draw_line(3, 15, 6, 0, Rgb::new(0xff, 0, 0));
+    assert_eq!(surface.pixel(1, 2), Some(Rgb::WHITE));
+    assert_eq!(surface.pixel(14, 9), Some(Rgb::WHITE));
+    assert_eq!(surface.checksum(), 0xb14a_a110);
+}
+
+#[test_case]
+fn test_rects() {
+    let mut surface = MemorySurface::new(16, 16);
+    surface.draw_rect(1, 1, 10, 6, Rgb::WHITE);
+    surface.fill_rect(4, 8, 5, 5, Rgb::new(0, 0xff, 0));
+    assert_eq!(surface.pixel(1, 1), Some(Rgb::WHITE));
+    assert_eq!(surface.pixel(10, 6), Some(Rgb::WHITE));
+    assert_eq!(surface.pixel(5, 4), Some(Rgb::BLACK));
+    assert_eq!(surface.checksum(), 0x0352_c288);
+}
+
+#[test_case]
+fn test_circles() {
+    let mut surface = MemorySurface::new(24, 24);
+    surface.draw_circle(7, 7, 6, Rgb::WHITE);
+    surface.fill_circle(17, 17, 5, Rgb::new(0, 0, 0xff));
+    assert_eq!(surface.pixel(13, 7), Some(Rgb::WHITE));
+    assert_eq!(surface.pixel(7, 7), Some(Rgb::BLACK));
+    assert_eq!(surface.pixel(17, 17), Some(Rgb::new(0, 0, 0xff)));
+    assert_eq!(surface.checksum(), 0x26c9_49f0);
+}
+
+#[test_case]
+fn test_clipping() {
+    let mut clipped = MemorySurface::new(8, 8);
+    clipped.fill_rect(-4, -4, 8, 8, Rgb::WHITE);
+    clipped.draw_line(-10, 7, 20, 7, Rgb::WHITE);
+    clipped.draw_circle(0, 0, 20, Rgb::WHITE);
+    clipped.fill_circle(100, 100, 3, Rgb::WHITE);
+
+    let mut expected = MemorySurface::new(8, 8);
+    expected.fill_rect(0, 0, 4, 4, Rgb::WHITE);
+    expected.draw_line(0, 7, 7, 7, Rgb::WHITE);
+    assert_eq!(clipped.checksum(), expected.checksum());
+}
+
+#[test_case]
+fn test_blit() {
+    let mut sprite = MemorySurface::new(4, 4);
+    sprite.fill_rect(0, 0, 4, 4, Rgb::new(0xff, 0, 0));
+    sprite.set_pixel(0, 0, Rgb::WHITE);
+
+    let mut surface = MemorySurface::new(8, 8);
+    surface.blit(2, 2, &sprite.as_bitmap());
+    surface.blit(-1, 6, &sprite.as_bitmap());
+    surface.blit(6, -3, &sprite.as_bitmap());
+    surface.blit(100, 0, &sprite.as_bitmap());
+
+    let mut expected = MemorySurface::new(8, 8);
+    expected.fill_rect(2, 2, 4, 4, Rgb::new(0xff, 0, 0));
+    expected.set_pixel(2, 2, Rgb::WHITE);
+    expected.fill_rect(0, 6, 3, 2, Rgb::new(0xff, 0, 0));
+    expected.fill_rect(6, 0, 2, 1, Rgb::new(0xff, 0, 0));
+    assert_eq!(surface.checksum(), expected.checksum());
+}
+
+#[test_case]
+fn test_bar_chart() {
+    let mut surface = MemorySurface::new(12, 12);
+    let chart = BarChart { values: &[10, 5, 0, 20, 10], max: 10, color: Rgb::new(0, 0xff, 0) };
+    chart.draw(&mut surface, 0, 0, 12, 12);
+    // 5 bars of 2 pixels in a 10x10 inside, the 4th one is clamped to `max`
+    assert_eq!(surface.pixel(1, 1), Some(Rgb::new(0, 0xff, 0)));
+    assert_eq!(surface.pixel(3, 5), Some(Rgb::BLACK));
+    assert_eq!(surface.pixel(3, 6), Some(Rgb::new(0, 0xff, 0)));
+    assert_eq!(surface.pixel(5, 10), Some(Rgb::BLACK));
+    assert_eq!(surface.pixel(7, 1), Some(Rgb::new(0, 0xff, 0)));
+    assert_eq!(surface.pixel(11, 11), Some(Rgb::WHITE));
+}
+
@@ -3,9 +3,10 @@ use x86_64::structures::idt::InterruptStackFrame;
 use x86_64::structures::idt::PageFaultErrorCode;
 use crate::hlt_loop;
 use crate::println;
-use crate::print;
 use crate::gdt;
 use lazy_static::lazy_static;
+use core::sync::atomic::{AtomicU64, Ordering};
+use core::time::Duration;
 
 use pic8259::ChainedPics;
 use spin;
@@ -22,7 +23,10 @@ pub static PICS: spin::Mutex<ChainedPics> =
 #[repr(u8)]
 pub enum InterruptIndex {
     Timer = PIC_1_OFFSET,
-    Keyboard
+    Keyboard,
+    Com2 = PIC_1_OFFSET + 3, // shared with COM4
+    Com1 = PIC_1_OFFSET + 4, // shared with COM3
+    Mouse = PIC_2_OFFSET + 4,
 }
 
 impl InterruptIndex {
@@ -49,6 +53,12 @@ lazy_static! {
             .set_handler_fn(timer_interrupt_handler);
         idt[InterruptIndex::Keyboard.as_usize()]
             .set_handler_fn(keyboard_interrupt_handler);
+        idt[InterruptIndex::Com1.as_usize()]
+            .set_handler_fn(com1_interrupt_handler);
+        idt[InterruptIndex::Com2.as_usize()]
+            .set_handler_fn(com2_interrupt_handler);
+        idt[InterruptIndex::Mouse.as_usize()]
+            .set_handler_fn(mouse_interrupt_handler);
 
         idt.page_fault.set_handler_fn(page_fault_handler);
 
@@ -60,30 +70,96 @@ pub fn init_idt() {
     IDT.load();    
 }
 
+/*
+    Unmask an IRQ line (0..=15) on the PICs, the BIOS leaves the lines it does not use masked
+ */
+pub fn unmask_irq(irq: u8) {
+    use x86_64::instructions::interrupts;
+
+    interrupts::without_interrupts(|| {
+        let mut pics = PICS.lock();
+        let mut masks = unsafe { pics.read_masks() };
+        masks[usize::from(irq / 8)] &= !(1 << (irq % 8));
+        if irq >= 8 {
+            masks[0] &= !(1 << 2); // the secondary PIC is chained to IRQ 2
+        }
+        unsafe { pics.write_masks(masks[0], masks[1]) };
+    });
+}
+
 extern "x86-interrupt" fn keyboard_interrupt_handler(_stack_frame: InterruptStackFrame) {
-    
-    use x86_64::instructions::port::Port;
-    // PS/2 controller I/O port is 0x60
-    let mut port = Port::new(0x60);
+    crate::task::keyboard::handle_interrupt();
 
-    let scan_code: u8 = unsafe {
-        port.read()
-    };
+    unsafe {
+        PICS.lock().notify_end_of_interrupt(InterruptIndex::Keyboard.as_u8());
+    }
+}
 
-    crate::task::keyboard::add_scancode(scan_code);
+extern "x86-interrupt" fn mouse_interrupt_handler(_stack_frame: InterruptStackFrame) {
+    if let Some(byte) = crate::ps2::read_mouse_data() {
+        crate::task::mouse::add_byte(byte);
+    }
 
     unsafe {
-        PICS.lock().notify_end_of_interrupt(InterruptIndex::Keyboard.as_u8());
+        PICS.lock().notify_end_of_interrupt(InterruptIndex::Mouse.as_u8());
     }
 }
 
-extern "x86-interrupt" fn timer_interrupt_handler(_stack_frame: InterruptStackFrame) {
-    print!(".");
+/*
+    The PIT is left at the BIOS setting: the 1.193182 MHz clock divided by 65536, about 18.2 ticks per second
+ */
+const PIT_FREQUENCY: u64 = 1_193_182;
+const PIT_DIVISOR: u64 = 65536;
+
+static TICKS: AtomicU64 = AtomicU64::new(0);
+
+// Timer interrupts since boot
+pub fn ticks() -> u64 {
+    TICKS.load(Ordering::Relaxed)
+}
+
+pub fn uptime() -> Duration {
+    ticks_to_duration(ticks())
+}
+
+pub fn ticks_to_duration(ticks: u64) -> Duration {
+    // Exact integer math, ticks * PIT_DIVISOR only overflows after some 490000 years
+    let seconds = ticks * PIT_DIVISOR / PIT_FREQUENCY;
+    let remainder = ticks * PIT_DIVISOR % PIT_FREQUENCY;
+    Duration::new(seconds, (remainder * 1_000_000_000 / PIT_FREQUENCY) as u32)
+}
+
+// Rounded up, waiting this many ticks takes at least `duration`
+pub fn duration_to_ticks(duration: Duration) -> u64 {
+    let nanos = duration.as_nanos() * u128::from(PIT_FREQUENCY);
+    nanos.div_ceil(u128::from(PIT_DIVISOR) * 1_000_000_000) as u64
+}
+
+extern "x86-interrupt" fn com1_interrupt_handler(_stack_frame: InterruptStackFrame) {
+    crate::serial::handle_interrupt(InterruptIndex::Com1.as_u8() - PIC_1_OFFSET);
 
+    unsafe {
+        PICS.lock().notify_end_of_interrupt(InterruptIndex::Com1.as_u8());
+    }
+}
+
+extern "x86-interrupt" fn com2_interrupt_handler(_stack_frame: InterruptStackFrame) {
+    crate::serial::handle_interrupt(InterruptIndex::Com2.as_u8() - PIC_1_OFFSET);
+
+    unsafe {
+        PICS.lock().notify_end_of_interrupt(InterruptIndex::Com2.as_u8());
+    }
+}
+
+extern "x86-interrupt" fn timer_interrupt_handler(_stack_frame: InterruptStackFrame) {
+    // No output here: a dot every tick would snap the console out of the scrollback view
+    let now = TICKS.fetch_add(1, Ordering::Relaxed) + 1;
     unsafe {
         // Send EOI(end of interrupt) signal to let system preparing for next interrupt
         PICS.lock().notify_end_of_interrupt(InterruptIndex::Timer.as_u8());
     }
+    // After the EOI: this may switch to another thread, which gets the next tick
+    crate::thread::tick(now);
 }
 
 extern "x86-interrupt" fn breakpoint_handler(stack_frame: InterruptStackFrame) {
@@ -92,6 +168,12 @@ extern "x86-interrupt" fn breakpoint_handler(stack_frame: InterruptStackFrame) {
 
 extern "x86-interrupt" fn double_fault_handler(
     stack_frame: InterruptStackFrame, _error_code: u64) -> ! {
+    use x86_64::registers::control::Cr2;
+
+    // The page fault handler could not run on a full stack, that is what ends up here
+    if let Some(slot) = crate::thread::stack::guard_page_slot(Cr2::read()) {
+        panic!("EXECEPTION: DOUBLE FAULT, thread stack {} overflowed\n{:#?}", slot, stack_frame);
+    }
     panic!("EXECEPTION: DOUBLE FAULT\n{:#?}", stack_frame);
 }
 
@@ -6,6 +6,7 @@
 #![feature(abi_x86_interrupt)]
 
 use core::panic::PanicInfo;
+pub mod print_lock;
 pub mod vga_buffer;
 pub mod serial;
 pub mod interrupts;
@@ -13,6 +14,12 @@ pub mod gdt;
 pub mod memory;
 pub mod allocator;
 pub mod task;
+pub mod framebuffer;
+pub mod graphics;
+pub mod power;
+pub mod ps2;
+pub mod shell;
+pub mod thread;
 extern crate alloc;
 
 
@@ -26,6 +33,9 @@ pub enum QemuExitCode {
 pub fn exit_qemu(exit_code: QemuExitCode) {
     use x86_64::instructions::port::Port;
 
+    // Queued serial output would be lost, test results included
+    serial::flush(serial::ComPort::Com1);
+
     unsafe {
         let mut port = Port::new(0xf4); // 0xf4 is iobase of isa-debug-exit device
         port.write(exit_code as u32);                                           // Write to port to tell that we need exit
@@ -76,6 +86,9 @@ pub fn init() {
     gdt::init();
     interrupts::init_idt();
     unsafe { interrupts::PICS.lock().initialize() };
+    if let Err(error) = ps2::init() {
+        println!("WARNING: PS/2 controller setup failed: {:?}", error);
+    }
     x86_64::instructions::interrupts::enable();
 }
 
@@ -96,8 +109,23 @@ entry_point!(test_kernel_main);
 
 // Entry point for `cargo test`
 #[cfg(test)]
-fn test_kernel_main(_boot_info: &'static BootInfo) -> ! {
+fn test_kernel_main(boot_info: &'static BootInfo) -> ! {
+    use x86_64::VirtAddr;
+    use memory::BootInfoFrameAllocator;
+
     init();
+
+    // Unit tests may allocate, so set up the heap like kernel_main does
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe {
+        BootInfoFrameAllocator::init(&boot_info.memory_map)
+    };
+    allocator::init_heap(&mut mapper, &mut frame_allocator)
+        .expect("heap initialization failed");
+    memory::install(mapper, frame_allocator);
+    thread::init().expect("thread initialization failed");
+
     test_main();
     hlt_loop();
 }
\ No newline at end of file
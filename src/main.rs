@@ -5,14 +5,21 @@
 #![reexport_test_harness_main = "test_main"] 
 
 use core::panic::PanicInfo;
-use blog_os::{allocator, memory, println, task::{Task, executor::Executor, keyboard}};
+use blog_os::{allocator, memory, println, serial::{self, ComPort}, shell, task::{Priority, Task, executor::Executor, keyboard}, thread, vga_buffer};
 use bootloader::{BootInfo, entry_point};
 use x86_64::{VirtAddr};
 
 extern crate alloc;
+use alloc::format;
 
 entry_point!(kernel_main);
 
+// Lines kept once they scroll off the screen, 160 bytes of heap each
+const SCROLLBACK_LINES: usize = 200;
+
+// Room for fast typing while the executor is busy, the keyboard is held off when it fills up anyway
+const SCANCODE_QUEUE_SIZE: usize = 256;
+
 // Used as the entry point of the OS
 fn kernel_main(boot_info: &'static BootInfo) -> ! {
 
@@ -29,21 +36,39 @@ fn kernel_main(boot_info: &'static BootInfo) -> ! {
 
     allocator::init_heap(& mut mapper, & mut frame_allocator)
         .expect("heap initialization failed");
+    memory::install(mapper, frame_allocator);
+    thread::init().expect("thread initialization failed");
+    keyboard::init_scancode_queue(SCANCODE_QUEUE_SIZE);
+
+    vga_buffer::init_scrollback(vga_buffer::LOG_CONSOLE, SCROLLBACK_LINES);
+    let serial_console = serial::enable_interrupts(ComPort::Com1).is_ok();
 
     // Test entry point
     #[cfg(test)]
     test_main();
 
-    let mut executor = Executor::new();
-    executor.spawn(Task::new(example_task()));
-    executor.spawn(Task::new(keyboard::print_keypresses()));
-    executor.run();
+    // The executor gets a thread of its own, the boot thread is done
+    thread::spawn("executor", move || {
+        let mut executor = Executor::new();
+        executor.spawn(Task::with_priority(Priority::Background, example_task()).with_name("example"));
+        executor.spawn(Task::with_priority(Priority::BottomHalf, keyboard::print_keypresses()).with_name("keyboard"));
+        // The log console only shows the kernel's println! output, a shell there would mix its prompt into it
+        for console in (0..vga_buffer::CONSOLE_COUNT).filter(|&console| console != vga_buffer::LOG_CONSOLE) {
+            executor.spawn(Task::new(shell::console_shell(console)).with_name(format!("shell tty{}", console)));
+        }
+        if serial_console {
+            executor.spawn(Task::new(shell::serial_shell(ComPort::Com1)).with_name("shell com1"));
+        }
+        executor.run();
+    }).expect("executor thread");
+    thread::exit();
 }
 
 // Will be called on panic
 #[cfg(not(test))]
 #[panic_handler]
 fn panic(info: &PanicInfo) -> ! {
+    vga_buffer::switch_console(vga_buffer::LOG_CONSOLE);
     println!("{}", info);
     blog_os::hlt_loop();
 }
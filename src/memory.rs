@@ -1,7 +1,9 @@
+use core::sync::atomic::{AtomicU64, Ordering};
 use x86_64:: {
-    PhysAddr, VirtAddr, structures::paging::{FrameAllocator, Mapper, OffsetPageTable, Page, PageTable, PhysFrame, Size4KiB}
+    PhysAddr, VirtAddr, structures::paging::{FrameAllocator, Mapper, OffsetPageTable, Page, PageTable, PageTableFlags, PageTableIndex, PhysFrame, Size4KiB, mapper::MapToError}
 };
 
+use alloc::vec::Vec;
 use bootloader::bootinfo::MemoryMap;
 use bootloader::bootinfo::MemoryRegionType;
 
@@ -66,10 +68,62 @@ pub fn create_example_mapping(
 }
 
 
+/*
+    Map `size` bytes of physical memory starting at `phys_start` (e.g. device memory like a framebuffer)
+    to the virtual address `virt_start`, both must be page aligned
+ */
+pub fn map_physical_region(
+    mapper: &mut impl Mapper<Size4KiB>,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+    phys_start: PhysAddr,
+    virt_start: VirtAddr,
+    size: u64,
+) -> Result<(), MapToError<Size4KiB>> {
+    use x86_64::structures::paging::PageTableFlags as Flags;
+
+    let flags = Flags::PRESENT | Flags::WRITABLE;
+    for offset in (0..size).step_by(4096) {
+        let page: Page<Size4KiB> = Page::containing_address(virt_start + offset);
+        let frame = PhysFrame::containing_address(phys_start + offset);
+        // unsafe because the caller must make sure nothing else uses this physical memory as normal RAM
+        unsafe {
+            mapper.map_to(page, frame, flags, frame_allocator)?.flush();
+        }
+    }
+
+    Ok(())
+}
+
+/*
+    The page table and frame allocator, kept after boot for mapping memory later on (thread stacks)
+ */
+static KERNEL_MEMORY: spin::Mutex<Option<(OffsetPageTable<'static>, BootInfoFrameAllocator)>> = spin::Mutex::new(None);
+
+pub fn install(mapper: OffsetPageTable<'static>, frame_allocator: BootInfoFrameAllocator) {
+    use x86_64::instructions::interrupts;
+
+    interrupts::without_interrupts(|| *KERNEL_MEMORY.lock() = Some((mapper, frame_allocator)));
+}
+
+// None before install()
+pub fn with_mapper<R>(f: impl FnOnce(&mut OffsetPageTable<'static>, &mut BootInfoFrameAllocator) -> R) -> Option<R> {
+    use x86_64::instructions::interrupts;
+
+    interrupts::without_interrupts(|| {
+        let mut memory = KERNEL_MEMORY.lock();
+        let (mapper, frame_allocator) = memory.as_mut()?;
+        Some(f(mapper, frame_allocator))
+    })
+}
+
+// Where the bootloader mapped all of physical memory, 0 until init()
+static PHYS_MEM_OFFSET: AtomicU64 = AtomicU64::new(0);
+
 /*
     Initialize a new OffsetPageTable
  */
 pub unsafe fn init(phys_mem_offset: VirtAddr) -> OffsetPageTable<'static> {
+    PHYS_MEM_OFFSET.store(phys_mem_offset.as_u64(), Ordering::Relaxed);
     unsafe {
         let l4_table = active_level_4_table(phys_mem_offset);
         OffsetPageTable::new(l4_table, phys_mem_offset)
@@ -89,3 +143,53 @@ unsafe fn active_level_4_table(physical_mem_offset: VirtAddr) -> &'static mut Pa
 
     unsafe { &mut *page_table_ptr }
 }
+
+/*
+    One step of a page table walk: the entry used at `level` (4 is the top level table)
+ */
+#[derive(Debug, Clone, Copy)]
+pub struct PageTableStep {
+    pub level: u8,
+    pub index: PageTableIndex,
+    pub addr: PhysAddr, // of the next table, or of the (huge) page frame on the last level
+    pub flags: PageTableFlags,
+}
+
+/*
+    The entries the MMU would look at to translate `addr`, from the level 4 table down.
+    The walk stops at an entry which is not present or maps a huge page, the result is the physical address if mapped.
+    None before init().
+ */
+pub fn walk_page_tables(addr: VirtAddr) -> Option<(Vec<PageTableStep>, Option<PhysAddr>)> {
+    use x86_64::registers::control::Cr3;
+
+    let phys_mem_offset = PHYS_MEM_OFFSET.load(Ordering::Relaxed);
+    if phys_mem_offset == 0 {
+        return None;
+    }
+
+    let (level_4_table_frame, _) = Cr3::read();
+    let mut table_addr = level_4_table_frame.start_address();
+    let indices = [addr.p4_index(), addr.p3_index(), addr.p2_index(), addr.p1_index()];
+    let mut steps = Vec::new();
+
+    for (level, index) in (1..=4u8).rev().zip(indices) {
+        let table_ptr: *const PageTable = VirtAddr::new(phys_mem_offset + table_addr.as_u64()).as_ptr();
+        // The bootloader maps all physical memory at the offset, so every table is readable there
+        let table = unsafe { &*table_ptr };
+        let entry = &table[index];
+        let flags = entry.flags();
+        steps.push(PageTableStep { level, index, addr: entry.addr(), flags });
+
+        if !flags.contains(PageTableFlags::PRESENT) {
+            return Some((steps, None));
+        }
+        if level == 1 || flags.contains(PageTableFlags::HUGE_PAGE) {
+            // A level 3 entry maps 1 GiB, level 2 2 MiB, level 1 4 KiB
+            let page_size = 4096u64 << (9 * (u32::from(level) - 1));
+            return Some((steps, Some(entry.addr() + addr.as_u64() % page_size)));
+        }
+        table_addr = entry.addr();
+    }
+    unreachable!("level 1 ends the walk");
+}
@@ -0,0 +1,48 @@
+/*
+    Reboot and power off
+
+    There is no ACPI support yet, so shutdown() uses the ports of the emulators we run on.
+ */
+use x86_64::instructions::port::Port;
+use crate::hlt_loop;
+
+const KEYBOARD_CONTROLLER_COMMAND_PORT: u16 = 0x64;
+const PULSE_RESET_LINE: u8 = 0xFE;
+
+// (port, value) which power off QEMU, Bochs/older QEMU and VirtualBox
+const SHUTDOWN_PORTS: [(u16, u16); 3] = [(0x604, 0x2000), (0xB004, 0x2000), (0x4004, 0x3400)];
+
+/*
+    Reset the CPU through the keyboard controller, with a triple fault as fallback
+ */
+pub fn reboot() -> ! {
+    x86_64::instructions::interrupts::disable();
+
+    let mut command: Port<u8> = Port::new(KEYBOARD_CONTROLLER_COMMAND_PORT);
+    unsafe { command.write(PULSE_RESET_LINE) };
+
+    // An empty IDT turns the breakpoint into a double fault, then a triple fault which resets the machine
+    static EMPTY_IDT: [u64; 2] = [0; 2];
+    let pointer = x86_64::structures::DescriptorTablePointer {
+        limit: 0,
+        base: x86_64::VirtAddr::new(EMPTY_IDT.as_ptr() as u64),
+    };
+    unsafe {
+        x86_64::instructions::tables::lidt(&pointer);
+    }
+    x86_64::instructions::interrupts::int3();
+    hlt_loop();
+}
+
+/*
+    Power off on QEMU, Bochs and VirtualBox, just halts elsewhere
+ */
+pub fn shutdown() -> ! {
+    x86_64::instructions::interrupts::disable();
+
+    for (port, value) in SHUTDOWN_PORTS {
+        let mut port: Port<u16> = Port::new(port);
+        unsafe { port.write(value) };
+    }
+    hlt_loop();
+}
@@ -0,0 +1,151 @@
+use core::ops::{Deref, DerefMut};
+use core::sync::atomic::{AtomicU32, Ordering};
+use spin::{Mutex, MutexGuard};
+use x86_64::instructions::interrupts;
+
+const NO_OWNER: u32 = 0;
+
+// How many failed attempts we accept from a holder running on another CPU before stealing the lock
+const SPIN_LIMIT: usize = 10_000_000;
+
+/*
+    Spin lock used by the print path (VGA writer and serial port).
+
+    The guard keeps interrupts disabled until it is dropped, so no interrupt handler or other thread
+    runs on this CPU while the lock is taken. The only way the same CPU can come back here then is an exception
+    (page fault, double fault...) or a panic raised in the middle of a print. Spinning there means waiting for ourselves forever,
+    so in that case the lock is stolen instead: the interrupted line may come out garbled,
+    but the diagnostic still reaches the screen.
+ */
+pub struct PrintLock<T> {
+    inner: Mutex<T>,
+    owner: AtomicU32, // cpu_id() of the current holder, NO_OWNER when free
+}
+
+impl<T> PrintLock<T> {
+    pub const fn new(value: T) -> Self {
+        PrintLock {
+            inner: Mutex::new(value),
+            owner: AtomicU32::new(NO_OWNER),
+        }
+    }
+
+    /*
+        Acquire the lock, never hangs:
+        - re-entered on the CPU which already holds it: steal it at once
+        - held by another CPU: spin for a while, then steal it
+     */
+    pub fn lock(&self) -> PrintGuard<'_, T> {
+        let interrupts = DisabledInterrupts::new();
+        let cpu = cpu_id();
+        let mut attempts = 0;
+        loop {
+            if let Some(guard) = self.inner.try_lock() {
+                return self.guard(guard, cpu, interrupts);
+            }
+
+            if self.owner.load(Ordering::Acquire) == cpu || attempts >= SPIN_LIMIT {
+                // SyntaxTip: force_unlock is unsafe because the old guard still exists,
+                // we accept the aliasing here since the alternative is a silent hang
+                unsafe { self.inner.force_unlock() };
+            } else {
+                attempts += 1;
+                core::hint::spin_loop();
+            }
+        }
+    }
+
+    pub fn try_lock(&self) -> Option<PrintGuard<'_, T>> {
+        let interrupts = DisabledInterrupts::new();
+        self.inner.try_lock().map(|guard| self.guard(guard, cpu_id(), interrupts))
+    }
+
+    fn guard<'a>(&'a self, guard: MutexGuard<'a, T>, cpu: u32, interrupts: DisabledInterrupts) -> PrintGuard<'a, T> {
+        self.owner.store(cpu, Ordering::Release);
+        PrintGuard { guard, owner: &self.owner, _interrupts: interrupts }
+    }
+}
+
+pub struct PrintGuard<'a, T> {
+    guard: MutexGuard<'a, T>,
+    owner: &'a AtomicU32,
+    _interrupts: DisabledInterrupts, // dropped after the inner guard, the lock is free before interrupts come back
+}
+
+// Disables interrupts and enables them again on drop, if they were enabled before
+struct DisabledInterrupts {
+    were_enabled: bool,
+}
+
+impl DisabledInterrupts {
+    fn new() -> Self {
+        let were_enabled = interrupts::are_enabled();
+        interrupts::disable();
+        DisabledInterrupts { were_enabled }
+    }
+}
+
+impl Drop for DisabledInterrupts {
+    fn drop(&mut self) {
+        if self.were_enabled {
+            interrupts::enable();
+        }
+    }
+}
+
+impl<T> Deref for PrintGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.guard
+    }
+}
+
+impl<T> DerefMut for PrintGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.guard
+    }
+}
+
+impl<T> Drop for PrintGuard<'_, T> {
+    fn drop(&mut self) {
+        // Runs before the inner MutexGuard is dropped, so no one can observe a free lock with a stale owner
+        self.owner.store(NO_OWNER, Ordering::Release);
+    }
+}
+
+/*
+    Identify the running CPU by its initial local APIC id (CPUID leaf 1, EBX[31:24]),
+    shifted by one so that 0 stays free for NO_OWNER.
+    CPUID is slow, a VM exit under QEMU/KVM, so it is read once: the kernel only runs on the boot CPU.
+    Starting other CPUs needs a per CPU copy instead.
+ */
+fn cpu_id() -> u32 {
+    static CPU_ID: AtomicU32 = AtomicU32::new(NO_OWNER);
+
+    match CPU_ID.load(Ordering::Relaxed) {
+        NO_OWNER => {
+            let apic_id = core::arch::x86_64::__cpuid(1).ebx >> 24;
+            CPU_ID.store(apic_id + 1, Ordering::Relaxed);
+            apic_id + 1
+        }
+        cpu => cpu,
+    }
+}
+
+#[test_case]
+fn test_guard_disables_interrupts() {
+    let lock = PrintLock::new(0);
+    let guard = lock.lock();
+    assert!(!interrupts::are_enabled());
+    assert!(lock.try_lock().is_none());
+    drop(guard);
+    assert!(interrupts::are_enabled());
+
+    // Nested in code which disabled them itself, they stay disabled
+    interrupts::without_interrupts(|| {
+        drop(lock.lock());
+        assert!(!interrupts::are_enabled());
+    });
+    assert_eq!(cpu_id(), cpu_id());
+}
@@ -0,0 +1,585 @@
+/*
+    Driver for the 8042 PS/2 controller, the keyboard on its first port and the mouse on its second
+
+    init() resets the controller and the devices instead of relying on what the firmware left behind.
+    The keyboard runs scancode set 2 with the controller translating it to set 1, which task::keyboard decodes.
+    The mouse reports on IRQ 12, with a wheel if it answers the IntelliMouse knock, see task::mouse.
+
+    Commands wait for their answer with interrupts disabled, the IRQ which fires afterwards
+    finds the output buffer empty and is ignored, see read_data().
+    Bytes the other device sends while a command waits are passed on to its task module.
+    The exception is set_leds(), called on every lock key: it only sends the command,
+    the keyboard IRQ takes the ACKs and sends the rest, see LedCommand.
+ */
+use spin::Mutex;
+use x86_64::instructions::port::Port;
+
+const DATA_PORT: u16 = 0x60;
+const STATUS_PORT: u16 = 0x64; // command port when written
+
+// Status register
+const OUTPUT_FULL: u8 = 0x01;
+const INPUT_FULL: u8 = 0x02;
+const SECOND_PORT_DATA: u8 = 0x20; // the byte in the output buffer came from the second port
+
+// Controller commands
+const READ_CONFIG: u8 = 0x20;
+const WRITE_CONFIG: u8 = 0x60;
+const DISABLE_SECOND_PORT: u8 = 0xA7;
+const ENABLE_SECOND_PORT: u8 = 0xA8;
+const TEST_SECOND_PORT: u8 = 0xA9;
+const SELF_TEST: u8 = 0xAA;
+const TEST_FIRST_PORT: u8 = 0xAB;
+const DISABLE_FIRST_PORT: u8 = 0xAD;
+const ENABLE_FIRST_PORT: u8 = 0xAE;
+const WRITE_SECOND_PORT: u8 = 0xD4; // the next data byte goes to the mouse
+
+const SELF_TEST_PASSED: u8 = 0x55;
+const PORT_TEST_PASSED: u8 = 0x00;
+
+// Configuration byte
+const FIRST_PORT_IRQ: u8 = 0x01;
+const SECOND_PORT_IRQ: u8 = 0x02;
+const FIRST_PORT_CLOCK_DISABLED: u8 = 0x10;
+const SECOND_PORT_CLOCK_DISABLED: u8 = 0x20;
+const TRANSLATION: u8 = 0x40;
+
+// Device commands, the mouse calls scanning data reporting
+const SET_LEDS: u8 = 0xED;
+const SET_SCANCODE_SET: u8 = 0xF0;
+const GET_ID: u8 = 0xF2;
+const SET_TYPEMATIC: u8 = 0xF3; // sample rate for the mouse
+const ENABLE_SCANNING: u8 = 0xF4;
+const DISABLE_SCANNING: u8 = 0xF5;
+const RESET: u8 = 0xFF;
+
+const INTELLIMOUSE_ID: u8 = 0x03;
+pub const KEYBOARD_IRQ: u8 = 1;
+pub const MOUSE_IRQ: u8 = 12;
+
+const ACK: u8 = 0xFA;
+const RESEND: u8 = 0xFE;
+const RESET_PASSED: u8 = 0xAA;
+
+const RESEND_LIMIT: usize = 3;
+
+// Status polls before giving up, a device answer takes a few milliseconds on real hardware
+const TIMEOUT: usize = 100_000;
+const RESET_TIMEOUT: usize = 1_000_000;
+
+// The controller takes a byte within microseconds, the LED command does not wait longer from the IRQ handler
+const LED_WRITE_TIMEOUT: usize = 1_000;
+// Timer ticks after which an unanswered LED command is given up, about 110 ms
+const LED_ACK_TICKS: u64 = 2;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Ps2Error {
+    Timeout,
+    SelfTestFailed,
+    PortTestFailed,
+    NoAck, // the device kept asking for a resend
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Device {
+    Keyboard,
+    Mouse,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MouseKind {
+    Standard,    // 3 byte packets
+    IntelliMouse, // 4 byte packets, the last one is the wheel
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Leds {
+    pub scroll_lock: bool,
+    pub num_lock: bool,
+    pub caps_lock: bool,
+}
+
+impl Leds {
+    fn bits(self) -> u8 {
+        self.scroll_lock as u8 | (self.num_lock as u8) << 1 | (self.caps_lock as u8) << 2
+    }
+}
+
+/*
+    Key repeat: the delay before the first repeat and the repeats per second after it.
+    The keyboard knows delays of 250..1000 ms in steps of 250 and rates from 2 to 30 per second,
+    the nearest supported values are used.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Typematic {
+    pub delay_ms: u16,
+    pub rate: u8,
+}
+
+// What the keyboard does after a reset
+impl Default for Typematic {
+    fn default() -> Self {
+        Typematic { delay_ms: 500, rate: 11 }
+    }
+}
+
+impl Typematic {
+    fn byte(self) -> u8 {
+        let delay = (self.delay_ms.clamp(250, 1000) + 125) / 250 - 1;
+
+        // The repeat period is (8 + A) * 2^B * 4.17 ms for the rate bits BBAAA, in 10 µs here
+        let period = |code: u8| (8 + u32::from(code & 7)) * (1 << ((code >> 3) & 3)) * 417;
+        let wanted = 100_000 / u32::from(self.rate.clamp(2, 30));
+        let rate = (0..32).min_by_key(|&code| period(code).abs_diff(wanted)).unwrap_or(0);
+
+        (delay as u8) << 5 | rate
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum LedState {
+    Idle,
+    SetLeds, // sent SET_LEDS, waiting for its ACK
+    Bits,    // sent the LED bits, waiting for their ACK
+}
+
+// What the keyboard IRQ does with a byte while an LED command is under way
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum LedReply {
+    NotOurs, // a scancode
+    Done,
+    Send(u8),
+}
+
+/*
+    The LED command as a state machine driven by the keyboard's replies,
+    so neither the keyboard task nor the IRQ handler waits for the keyboard.
+    LEDs changed while a command is under way are sent once it is done.
+ */
+struct LedCommand {
+    state: LedState,
+    wanted: u8, // the LED bits to show
+    sent: u8,   // the LED bits of the command under way
+    resends: usize,
+    started: u64, // timer tick
+}
+
+static LED_COMMAND: Mutex<LedCommand> = Mutex::new(LedCommand::new());
+
+impl LedCommand {
+    const fn new() -> Self {
+        LedCommand { state: LedState::Idle, wanted: 0, sent: 0, resends: 0, started: 0 }
+    }
+
+    // The byte to send to start over
+    fn start(&mut self, now: u64) -> u8 {
+        self.state = LedState::SetLeds;
+        self.resends = 0;
+        self.started = now;
+        SET_LEDS
+    }
+
+    // A command without an answer for a while is given up, the keyboard might not be there
+    fn is_busy(&self, now: u64) -> bool {
+        self.state != LedState::Idle && now.saturating_sub(self.started) < LED_ACK_TICKS
+    }
+
+    fn reply(&mut self, byte: u8, now: u64) -> LedReply {
+        match (self.state, byte) {
+            (LedState::Idle, _) => LedReply::NotOurs,
+            (LedState::SetLeds, ACK) => {
+                self.state = LedState::Bits;
+                self.resends = 0;
+                self.sent = self.wanted;
+                LedReply::Send(self.sent)
+            }
+            (LedState::Bits, ACK) if self.sent != self.wanted => LedReply::Send(self.start(now)),
+            (LedState::Bits, ACK) => {
+                self.state = LedState::Idle;
+                LedReply::Done
+            }
+            (state, RESEND) if self.resends < RESEND_LIMIT => {
+                self.resends += 1;
+                LedReply::Send(if state == LedState::SetLeds { SET_LEDS } else { self.sent })
+            }
+            (_, RESEND) => {
+                self.state = LedState::Idle;
+                LedReply::Done
+            }
+            _ => LedReply::NotOurs,
+        }
+    }
+}
+
+// For the LED command, which does not hold the controller lock
+fn write_keyboard_byte(value: u8) -> Result<(), Ps2Error> {
+    let mut status: Port<u8> = Port::new(STATUS_PORT);
+    let mut data: Port<u8> = Port::new(DATA_PORT);
+    for _ in 0..LED_WRITE_TIMEOUT {
+        if unsafe { status.read() } & INPUT_FULL == 0 {
+            unsafe { data.write(value) };
+            return Ok(());
+        }
+        core::hint::spin_loop();
+    }
+    Err(Ps2Error::Timeout)
+}
+
+struct Controller {
+    data: Port<u8>,
+    status: Port<u8>,
+    has_second_port: bool,
+    mouse: Option<MouseKind>,
+}
+
+static CONTROLLER: Mutex<Controller> = Mutex::new(Controller::new());
+
+impl Controller {
+    const fn new() -> Self {
+        Controller {
+            data: Port::new(DATA_PORT),
+            status: Port::new(STATUS_PORT),
+            has_second_port: false,
+            mouse: None,
+        }
+    }
+
+    fn status(&mut self) -> u8 {
+        unsafe { self.status.read() }
+    }
+
+    fn write_command(&mut self, command: u8) -> Result<(), Ps2Error> {
+        self.wait_input_empty()?;
+        unsafe { self.status.write(command) };
+        Ok(())
+    }
+
+    fn write_data(&mut self, value: u8) -> Result<(), Ps2Error> {
+        self.wait_input_empty()?;
+        unsafe { self.data.write(value) };
+        Ok(())
+    }
+
+    fn wait_input_empty(&mut self) -> Result<(), Ps2Error> {
+        for _ in 0..TIMEOUT {
+            if self.status() & INPUT_FULL == 0 {
+                return Ok(());
+            }
+            core::hint::spin_loop();
+        }
+        Err(Ps2Error::Timeout)
+    }
+
+    fn read(&mut self, timeout: usize) -> Result<u8, Ps2Error> {
+        for _ in 0..timeout {
+            if self.status() & OUTPUT_FULL != 0 {
+                return Ok(unsafe { self.data.read() });
+            }
+            core::hint::spin_loop();
+        }
+        Err(Ps2Error::Timeout)
+    }
+
+    // The next byte from `device`, what the other device sends meanwhile goes to its task module
+    fn read_from(&mut self, device: Device, timeout: usize) -> Result<u8, Ps2Error> {
+        for _ in 0..timeout {
+            let status = self.status();
+            if status & OUTPUT_FULL == 0 {
+                core::hint::spin_loop();
+                continue;
+            }
+            let byte = unsafe { self.data.read() };
+            match (status & SECOND_PORT_DATA != 0, device) {
+                (false, Device::Keyboard) | (true, Device::Mouse) => return Ok(byte),
+                (false, Device::Mouse) => crate::task::keyboard::add_scancode(byte),
+                (true, Device::Keyboard) => crate::task::mouse::add_byte(byte),
+            }
+        }
+        Err(Ps2Error::Timeout)
+    }
+
+    // Drop whatever the devices sent before we took over
+    fn flush(&mut self) {
+        for _ in 0..16 {
+            if self.status() & OUTPUT_FULL == 0 {
+                break;
+            }
+            unsafe { self.data.read() };
+        }
+    }
+
+    fn command_with_reply(&mut self, command: u8) -> Result<u8, Ps2Error> {
+        self.write_command(command)?;
+        self.read(TIMEOUT)
+    }
+
+    fn config(&mut self) -> Result<u8, Ps2Error> {
+        self.command_with_reply(READ_CONFIG)
+    }
+
+    fn set_config(&mut self, config: u8) -> Result<(), Ps2Error> {
+        self.write_command(WRITE_CONFIG)?;
+        self.write_data(config)
+    }
+
+    /*
+        Send a byte to a device and wait for its ACK, resending when asked to
+     */
+    fn device_command(&mut self, device: Device, value: u8) -> Result<(), Ps2Error> {
+        for _ in 0..RESEND_LIMIT {
+            if device == Device::Mouse {
+                self.write_command(WRITE_SECOND_PORT)?;
+            }
+            self.write_data(value)?;
+            loop {
+                match self.read_from(device, TIMEOUT)? {
+                    ACK => return Ok(()),
+                    RESEND => break,
+                    // Typed or moved while we were waiting, it is still input
+                    byte if device == Device::Keyboard => crate::task::keyboard::add_scancode(byte),
+                    byte => crate::task::mouse::add_byte(byte),
+                }
+            }
+        }
+        Err(Ps2Error::NoAck)
+    }
+
+    fn keyboard_command(&mut self, value: u8) -> Result<(), Ps2Error> {
+        self.device_command(Device::Keyboard, value)
+    }
+
+    fn mouse_command(&mut self, value: u8) -> Result<(), Ps2Error> {
+        self.device_command(Device::Mouse, value)
+    }
+
+    fn reset_device(&mut self, device: Device) -> Result<(), Ps2Error> {
+        self.device_command(device, RESET)?;
+        match self.read_from(device, RESET_TIMEOUT)? {
+            RESET_PASSED => Ok(()),
+            _ => Err(Ps2Error::SelfTestFailed),
+        }
+    }
+
+    fn init_mouse(&mut self) -> Result<MouseKind, Ps2Error> {
+        self.write_command(ENABLE_SECOND_PORT)?;
+        self.reset_device(Device::Mouse)?;
+        self.read_from(Device::Mouse, TIMEOUT)?; // the device ID, a mouse sends 0 after a reset
+
+        // The IntelliMouse knock: sample rates 200, 100 and 80 switch on the wheel, the ID tells whether it worked
+        for rate in [200, 100, 80] {
+            self.mouse_command(SET_TYPEMATIC)?;
+            self.mouse_command(rate)?;
+        }
+        self.mouse_command(GET_ID)?;
+        let kind = match self.read_from(Device::Mouse, TIMEOUT)? {
+            INTELLIMOUSE_ID => MouseKind::IntelliMouse,
+            _ => MouseKind::Standard,
+        };
+        self.mouse_command(SET_TYPEMATIC)?;
+        self.mouse_command(100)?;
+        self.mouse_command(ENABLE_SCANNING)?;
+        Ok(kind)
+    }
+
+    fn init(&mut self) -> Result<(), Ps2Error> {
+        self.write_command(DISABLE_FIRST_PORT)?;
+        self.write_command(DISABLE_SECOND_PORT)?;
+        self.flush();
+
+        let config = self.config()? & !(FIRST_PORT_IRQ | SECOND_PORT_IRQ | TRANSLATION);
+        self.set_config(config)?;
+
+        if self.command_with_reply(SELF_TEST)? != SELF_TEST_PASSED {
+            return Err(Ps2Error::SelfTestFailed);
+        }
+        // Some controllers reset themselves during the self test
+        self.set_config(config)?;
+
+        // The second port exists if enabling it starts its clock
+        self.has_second_port = false;
+        if config & SECOND_PORT_CLOCK_DISABLED != 0 {
+            self.write_command(ENABLE_SECOND_PORT)?;
+            self.has_second_port = self.config()? & SECOND_PORT_CLOCK_DISABLED == 0;
+            self.write_command(DISABLE_SECOND_PORT)?;
+        }
+        if self.has_second_port && self.command_with_reply(TEST_SECOND_PORT)? != PORT_TEST_PASSED {
+            self.has_second_port = false;
+        }
+        if self.command_with_reply(TEST_FIRST_PORT)? != PORT_TEST_PASSED {
+            return Err(Ps2Error::PortTestFailed);
+        }
+
+        self.write_command(ENABLE_FIRST_PORT)?;
+        self.reset_device(Device::Keyboard)?;
+        self.keyboard_command(DISABLE_SCANNING)?;
+        self.keyboard_command(SET_SCANCODE_SET)?;
+        self.keyboard_command(2)?;
+        self.keyboard_command(SET_TYPEMATIC)?;
+        self.keyboard_command(Typematic::default().byte())?;
+        self.keyboard_command(SET_LEDS)?;
+        self.keyboard_command(Leds { num_lock: true, ..Leds::default() }.bits())?;
+        self.keyboard_command(ENABLE_SCANNING)?;
+
+        // Without a mouse the keyboard is still worth having
+        self.mouse = None;
+        if self.has_second_port {
+            self.mouse = self.init_mouse().ok();
+        }
+        self.flush();
+
+        // The clock bits were set by the disable commands above
+        let mut config = (config | FIRST_PORT_IRQ | TRANSLATION) & !FIRST_PORT_CLOCK_DISABLED;
+        if self.mouse.is_some() {
+            config = (config | SECOND_PORT_IRQ) & !SECOND_PORT_CLOCK_DISABLED;
+        } else if self.has_second_port {
+            self.write_command(DISABLE_SECOND_PORT)?;
+        }
+        self.set_config(config)
+    }
+
+    /*
+        Best effort after a failed init: the keyboard keeps working if the firmware had set it up
+     */
+    fn restore(&mut self) {
+        let _ = self.write_command(ENABLE_FIRST_PORT);
+        if let Ok(config) = self.config() {
+            let _ = self.set_config(config | FIRST_PORT_IRQ | TRANSLATION);
+        }
+    }
+}
+
+/*
+    Reset and set up the controller and the keyboard, before interrupts are enabled
+ */
+pub fn init() -> Result<(), Ps2Error> {
+    use x86_64::instructions::interrupts;
+
+    interrupts::without_interrupts(|| {
+        let mut controller = CONTROLLER.lock();
+        let result = controller.init();
+        if result.is_err() {
+            controller.restore();
+        }
+        result
+    })?;
+    if mouse().is_some() {
+        crate::interrupts::unmask_irq(MOUSE_IRQ);
+    }
+    Ok(())
+}
+
+pub fn has_second_port() -> bool {
+    CONTROLLER.lock().has_second_port
+}
+
+// The mouse found by init(), if any
+pub fn mouse() -> Option<MouseKind> {
+    CONTROLLER.lock().mouse
+}
+
+/*
+    Returns once the command is sent, the keyboard IRQ handles the rest.
+    The LEDs show the latest call's state once the keyboard answered.
+ */
+pub fn set_leds(leds: Leds) -> Result<(), Ps2Error> {
+    use x86_64::instructions::interrupts;
+
+    interrupts::without_interrupts(|| {
+        let now = crate::interrupts::ticks();
+        let mut command = LED_COMMAND.lock();
+        command.wanted = leds.bits();
+        if command.is_busy(now) {
+            return Ok(());
+        }
+        let byte = command.start(now);
+        let result = write_keyboard_byte(byte);
+        if result.is_err() {
+            command.state = LedState::Idle;
+        }
+        result
+    })
+}
+
+pub fn set_typematic(typematic: Typematic) -> Result<(), Ps2Error> {
+    use x86_64::instructions::interrupts;
+
+    interrupts::without_interrupts(|| {
+        let mut controller = CONTROLLER.lock();
+        controller.keyboard_command(SET_TYPEMATIC)?;
+        controller.keyboard_command(typematic.byte())
+    })
+}
+
+/*
+    Called by the keyboard IRQ handler: the byte from the first port,
+    None if a command already took it or it belongs to the second port
+ */
+pub(crate) fn read_data() -> Option<u8> {
+    let byte = read_port_data(false)?;
+    let reply = LED_COMMAND.lock().reply(byte, crate::interrupts::ticks());
+    match reply {
+        LedReply::NotOurs => Some(byte),
+        LedReply::Done => None,
+        LedReply::Send(next) => {
+            if write_keyboard_byte(next).is_err() {
+                LED_COMMAND.lock().state = LedState::Idle;
+            }
+            None
+        }
+    }
+}
+
+// The same for the mouse IRQ handler and the second port
+pub(crate) fn read_mouse_data() -> Option<u8> {
+    read_port_data(true)
+}
+
+fn read_port_data(second_port: bool) -> Option<u8> {
+    let mut status: Port<u8> = Port::new(STATUS_PORT);
+    let mut data: Port<u8> = Port::new(DATA_PORT);
+    let status = unsafe { status.read() };
+    if status & OUTPUT_FULL == 0 || (status & SECOND_PORT_DATA != 0) != second_port {
+        return None;
+    }
+    Some(unsafe { data.read() })
+}
+
+#[test_case]
+fn test_typematic() {
+    assert_eq!(Typematic { delay_ms: 250, rate: 30 }.byte(), 0x00);
+    assert_eq!(Typematic { delay_ms: 1000, rate: 2 }.byte(), 0x7F);
+    assert_eq!(Typematic { delay_ms: 500, rate: 10 }.byte(), 0x2C);
+    assert_eq!(Typematic::default().byte(), 0x2B);
+    // Out of range values are clamped
+    assert_eq!(Typematic { delay_ms: 0, rate: 100 }.byte(), 0x00);
+}
+
+#[test_case]
+fn test_led_command() {
+    let mut command = LedCommand::new();
+    assert_eq!(command.reply(ACK, 0), LedReply::NotOurs);
+
+    command.wanted = 0x04;
+    assert_eq!(command.start(10), SET_LEDS);
+    assert!(command.is_busy(10));
+    assert_eq!(command.reply(0x1e, 10), LedReply::NotOurs); // a key pressed meanwhile
+    assert_eq!(command.reply(RESEND, 10), LedReply::Send(SET_LEDS));
+    assert_eq!(command.reply(ACK, 10), LedReply::Send(0x04));
+
+    // Changed while under way, sent once the keyboard took the old bits
+    command.wanted = 0x05;
+    assert_eq!(command.reply(ACK, 11), LedReply::Send(SET_LEDS));
+    assert_eq!(command.reply(ACK, 11), LedReply::Send(0x05));
+    assert_eq!(command.reply(ACK, 11), LedReply::Done);
+    assert!(!command.is_busy(11));
+
+    // No answer, given up
+    command.start(20);
+    assert!(!command.is_busy(20 + LED_ACK_TICKS));
+}
+
+#[test_case]
+fn test_leds() {
+    assert_eq!(Leds::default().bits(), 0);
+    assert_eq!(Leds { caps_lock: true, scroll_lock: true, ..Leds::default() }.bits(), 0x05);
+}
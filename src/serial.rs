@@ -1,25 +1,138 @@
-use uart_16550::SerialPort;
-use spin::Mutex;
+/*
+    Serial ports COM1..COM4
+
+    COM1 is set up polled at boot so that serial_print! works right away, in tests and in panics too.
+    enable_interrupts() makes a port IRQ driven: received bytes are queued for SerialStream (task::serial)
+    and output goes through a transmit ring instead of waiting for the chip.
+ */
+use crate::print_lock::PrintLock;
 use lazy_static::lazy_static;
 
+pub mod uart;
+
+pub use uart::{Config, FlowControl, Parity, StopBits, Uart};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ComPort {
+    Com1,
+    Com2,
+    Com3,
+    Com4,
+}
+
+pub const COM_PORT_COUNT: usize = 4;
+
+impl ComPort {
+    pub const ALL: [ComPort; COM_PORT_COUNT] = [ComPort::Com1, ComPort::Com2, ComPort::Com3, ComPort::Com4];
+
+    pub fn base(self) -> u16 {
+        match self {
+            ComPort::Com1 => 0x3F8,
+            ComPort::Com2 => 0x2F8,
+            ComPort::Com3 => 0x3E8,
+            ComPort::Com4 => 0x2E8,
+        }
+    }
+
+    // COM1 and COM3 share IRQ 4, COM2 and COM4 share IRQ 3
+    pub fn irq(self) -> u8 {
+        match self {
+            ComPort::Com1 | ComPort::Com3 => 4,
+            ComPort::Com2 | ComPort::Com4 => 3,
+        }
+    }
+
+    pub fn index(self) -> usize {
+        self as usize
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SerialError {
+    NoDevice,
+    InvalidConfig, // baud rate which is no divisor of 115200, or data bits outside of 5..=8
+}
+
 lazy_static! {
-    pub static ref SERIAL1 : Mutex<SerialPort> = {
-        let mut  serial_port = unsafe {
-            SerialPort::new(0x3F8)
-        };
-        serial_port.init();
-        Mutex::new(serial_port)
+    static ref PORTS: [PrintLock<Uart>; COM_PORT_COUNT] = {
+        let ports = ComPort::ALL.map(|port| PrintLock::new(Uart::new(port.base())));
+        // Nothing to report to if COM1 is missing, serial output is dropped then
+        let _ = ports[ComPort::Com1.index()].lock().init(Config::default());
+        ports
     };
 }
 
+pub fn port(port: ComPort) -> &'static PrintLock<Uart> {
+    &PORTS[port.index()]
+}
+
+/*
+    (Re)configure a port
+ */
+pub fn open(port: ComPort, config: Config) -> Result<(), SerialError> {
+    use x86_64::instructions::interrupts;
+
+    interrupts::without_interrupts(|| self::port(port).lock().init(config))
+}
+
+/*
+    Switch an opened port to interrupt driven operation, needs the heap
+ */
+pub fn enable_interrupts(port: ComPort) -> Result<(), SerialError> {
+    use x86_64::instructions::interrupts;
+
+    interrupts::without_interrupts(|| self::port(port).lock().enable_interrupts())?;
+    crate::interrupts::unmask_irq(port.irq());
+    Ok(())
+}
+
+pub fn write(port: ComPort, bytes: &[u8]) {
+    use x86_64::instructions::interrupts;
+
+    interrupts::without_interrupts(|| {
+        let mut uart = self::port(port).lock();
+        for &byte in bytes {
+            uart.send(byte);
+        }
+    });
+}
+
+// Wait until the queued output of the port went out, e.g. before QEMU exits
+pub fn flush(port: ComPort) {
+    use x86_64::instructions::interrupts;
+
+    interrupts::without_interrupts(|| self::port(port).lock().flush());
+}
+
+/*
+    Called by the IRQ 3 and IRQ 4 handlers, serves every port on the line
+ */
+pub(crate) fn handle_interrupt(irq: u8) {
+    for port in ComPort::ALL.into_iter().filter(|port| port.irq() == irq) {
+        let mut uart = self::port(port).lock();
+        let mut ready = true;
+        uart.handle_interrupt(|byte| ready &= crate::task::serial::add_received(port, byte));
+        if !ready {
+            uart.set_receive_ready(false);
+        }
+    }
+}
+
+// Called by SerialStream once its queue has room again
+pub(crate) fn resume_receive(port: ComPort) {
+    use x86_64::instructions::interrupts;
+
+    interrupts::without_interrupts(|| self::port(port).lock().set_receive_ready(true));
+}
+
 #[doc(hidden)]
 pub fn _print(args: ::core::fmt::Arguments) {
     use core::fmt::Write;
     use x86_64::instructions::interrupts;
     interrupts::without_interrupts(|| {
-        SERIAL1.lock().write_fmt(args).expect("Printing to serial failed");
+        port(ComPort::Com1).lock().write_fmt(args).expect("Printing to serial failed");
     });
-    
+
 }
 
 // Print to host through the sertial interface
@@ -36,4 +149,12 @@ macro_rules! serial_println {
     () => ($crate::serial_print!("\n"));
     ($fmt:expr) => ($crate::serial_print!(concat!($fmt, "\n")));
     ($fmt:expr, $($arg:tt)*) => ($crate::serial_print!(concat!($fmt, "\n"), $($arg)*));
-}
\ No newline at end of file
+}
+
+#[test_case]
+fn test_config_validation() {
+    let config = Config { baud_rate: 1000, ..Config::default() };
+    assert_eq!(open(ComPort::Com2, config), Err(SerialError::InvalidConfig));
+    let config = Config { data_bits: 9, ..Config::default() };
+    assert_eq!(open(ComPort::Com2, config), Err(SerialError::InvalidConfig));
+}
@@ -0,0 +1,318 @@
+/*
+    Driver for the 16550 UART, the chip behind the PC's COM ports
+
+    Polled until enable_interrupts(), afterwards output is queued in a transmit ring
+    which handle_interrupt() feeds into the chip's 16 byte FIFO whenever it runs empty.
+ */
+use core::fmt;
+use crossbeam_queue::ArrayQueue;
+use x86_64::instructions::port::Port;
+use super::SerialError;
+
+// Register offsets from the base port
+const DATA: u16 = 0;             // divisor low byte while DLAB is set
+const INTERRUPT_ENABLE: u16 = 1; // divisor high byte while DLAB is set
+const INTERRUPT_ID: u16 = 2;     // FIFO control when written
+const LINE_CONTROL: u16 = 3;
+const MODEM_CONTROL: u16 = 4;
+const LINE_STATUS: u16 = 5;
+const MODEM_STATUS: u16 = 6;
+
+// The divisor latch divides this clock
+const BASE_BAUD_RATE: u32 = 115_200;
+
+const ENABLE_RECEIVED: u8 = 0x01;
+const ENABLE_TRANSMIT_EMPTY: u8 = 0x02;
+const ENABLE_MODEM_STATUS: u8 = 0x08;
+
+const NO_INTERRUPT_PENDING: u8 = 0x01;
+
+const FIFO_ENABLE_AND_CLEAR: u8 = 0xC7; // interrupt at 14 received bytes
+const FIFO_SIZE: usize = 16;
+
+const DIVISOR_LATCH: u8 = 0x80;
+const TWO_STOP_BITS: u8 = 0x04;
+
+const DTR: u8 = 0x01;
+const RTS: u8 = 0x02;
+const OUT2: u8 = 0x08; // gates the interrupt line on PCs
+const LOOPBACK: u8 = 0x10;
+
+const DATA_READY: u8 = 0x01;
+const TRANSMIT_EMPTY: u8 = 0x20;
+
+const CLEAR_TO_SEND: u8 = 0x10;
+
+const TRANSMIT_RING_SIZE: usize = 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Parity {
+    None,
+    Odd,
+    Even,
+    Mark,  // always 1
+    Space, // always 0
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StopBits {
+    One,
+    Two,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FlowControl {
+    None,
+    RtsCts, // hardware handshake, we only send while CTS is set and drop RTS when our buffer fills up
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Config {
+    pub baud_rate: u32,
+    pub data_bits: u8, // 5..=8
+    pub parity: Parity,
+    pub stop_bits: StopBits,
+    pub flow_control: FlowControl,
+}
+
+// 115200 8N1, what QEMU's -serial expects
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            baud_rate: BASE_BAUD_RATE,
+            data_bits: 8,
+            parity: Parity::None,
+            stop_bits: StopBits::One,
+            flow_control: FlowControl::None,
+        }
+    }
+}
+
+impl Config {
+    fn divisor(&self) -> Option<u16> {
+        if self.baud_rate == 0 || !BASE_BAUD_RATE.is_multiple_of(self.baud_rate) {
+            return None;
+        }
+        u16::try_from(BASE_BAUD_RATE / self.baud_rate).ok()
+    }
+
+    fn line_control(&self) -> Option<u8> {
+        if !(5..=8).contains(&self.data_bits) {
+            return None;
+        }
+        let stop_bits = match self.stop_bits {
+            StopBits::One => 0,
+            StopBits::Two => TWO_STOP_BITS,
+        };
+        let parity = match self.parity {
+            Parity::None => 0x00,
+            Parity::Odd => 0x08,
+            Parity::Even => 0x18,
+            Parity::Mark => 0x28,
+            Parity::Space => 0x38,
+        };
+        Some((self.data_bits - 5) | stop_bits | parity)
+    }
+}
+
+pub struct Uart {
+    base: u16,
+    config: Option<Config>, // None until init() found the chip
+    transmit: Option<ArrayQueue<u8>>, // Some once interrupt driven
+    receive_paused: bool,
+}
+
+impl Uart {
+    pub const fn new(base: u16) -> Self {
+        Uart {
+            base,
+            config: None,
+            transmit: None,
+            receive_paused: false,
+        }
+    }
+
+    fn read(&self, register: u16) -> u8 {
+        let mut port: Port<u8> = Port::new(self.base + register);
+        unsafe { port.read() }
+    }
+
+    fn write(&self, register: u16, value: u8) {
+        let mut port: Port<u8> = Port::new(self.base + register);
+        unsafe { port.write(value) };
+    }
+
+    /*
+        Program the line settings and check that the chip is there with a loopback test.
+        A port switched to interrupts stays interrupt driven.
+     */
+    pub fn init(&mut self, config: Config) -> Result<(), SerialError> {
+        let divisor = config.divisor().ok_or(SerialError::InvalidConfig)?;
+        let line_control = config.line_control().ok_or(SerialError::InvalidConfig)?;
+
+        self.write(INTERRUPT_ENABLE, 0);
+        self.write(LINE_CONTROL, DIVISOR_LATCH);
+        self.write(DATA, divisor as u8);
+        self.write(INTERRUPT_ENABLE, (divisor >> 8) as u8);
+        self.write(LINE_CONTROL, line_control);
+        self.write(INTERRUPT_ID, FIFO_ENABLE_AND_CLEAR);
+
+        // Whatever is sent in loopback mode comes right back, a missing port reads 0xff
+        self.write(MODEM_CONTROL, LOOPBACK | RTS | OUT2);
+        self.write(DATA, 0xAE);
+        if self.read(DATA) != 0xAE {
+            self.config = None;
+            return Err(SerialError::NoDevice);
+        }
+
+        self.write(MODEM_CONTROL, DTR | RTS | OUT2);
+        self.config = Some(config);
+        self.receive_paused = false;
+        if self.transmit.is_some() {
+            self.write(INTERRUPT_ENABLE, self.interrupt_mask());
+        }
+        Ok(())
+    }
+
+    pub fn config(&self) -> Option<Config> {
+        self.config
+    }
+
+    pub fn is_interrupt_driven(&self) -> bool {
+        self.transmit.is_some()
+    }
+
+    /*
+        Needs the heap for the transmit ring. The IRQ line has to be unmasked by the caller.
+     */
+    pub fn enable_interrupts(&mut self) -> Result<(), SerialError> {
+        if self.config.is_none() {
+            return Err(SerialError::NoDevice);
+        }
+        if self.transmit.is_none() {
+            self.transmit = Some(ArrayQueue::new(TRANSMIT_RING_SIZE));
+        }
+        self.write(INTERRUPT_ENABLE, self.interrupt_mask());
+        Ok(())
+    }
+
+    fn interrupt_mask(&self) -> u8 {
+        match self.config.map(|config| config.flow_control) {
+            Some(FlowControl::RtsCts) => ENABLE_RECEIVED | ENABLE_TRANSMIT_EMPTY | ENABLE_MODEM_STATUS,
+            _ => ENABLE_RECEIVED | ENABLE_TRANSMIT_EMPTY,
+        }
+    }
+
+    fn clear_to_send(&self) -> bool {
+        match self.config.map(|config| config.flow_control) {
+            Some(FlowControl::RtsCts) => self.read(MODEM_STATUS) & CLEAR_TO_SEND != 0,
+            _ => true,
+        }
+    }
+
+    fn transmit_empty(&self) -> bool {
+        self.read(LINE_STATUS) & TRANSMIT_EMPTY != 0
+    }
+
+    /*
+        Queued when interrupt driven, a full ring is drained by polling so no output gets lost.
+        Output to a port which was not found is dropped.
+     */
+    pub fn send(&mut self, byte: u8) {
+        if self.config.is_none() {
+            return;
+        }
+        match &self.transmit {
+            Some(transmit) => {
+                if let Err(byte) = transmit.push(byte) {
+                    self.flush();
+                    self.send_polled(byte);
+                }
+                self.fill_fifo();
+            }
+            None => self.send_polled(byte),
+        }
+    }
+
+    fn send_polled(&self, byte: u8) {
+        while !self.transmit_empty() || !self.clear_to_send() {
+            core::hint::spin_loop();
+        }
+        self.write(DATA, byte);
+    }
+
+    // Wait until everything in the transmit ring went out
+    pub fn flush(&mut self) {
+        if let Some(transmit) = &self.transmit {
+            while let Some(byte) = transmit.pop() {
+                self.send_polled(byte);
+            }
+        }
+    }
+
+    fn fill_fifo(&mut self) {
+        let Some(transmit) = &self.transmit else {
+            return;
+        };
+        if !self.transmit_empty() || !self.clear_to_send() {
+            return; // the transmit empty or modem status interrupt calls us again
+        }
+        for _ in 0..FIFO_SIZE {
+            match transmit.pop() {
+                Some(byte) => self.write(DATA, byte),
+                None => break,
+            }
+        }
+    }
+
+    // Polled receive, for ports which are not interrupt driven
+    pub fn try_receive(&mut self) -> Option<u8> {
+        if self.config.is_some() && self.read(LINE_STATUS) & DATA_READY != 0 {
+            Some(self.read(DATA))
+        } else {
+            None
+        }
+    }
+
+    /*
+        With RTS/CTS flow control, tell the other side to stop sending (false) or to continue (true)
+     */
+    pub fn set_receive_ready(&mut self, ready: bool) {
+        if self.config.map(|config| config.flow_control) != Some(FlowControl::RtsCts) || self.receive_paused != ready {
+            return;
+        }
+        self.receive_paused = !ready;
+        let modem_control = if ready { DTR | RTS | OUT2 } else { DTR | OUT2 };
+        self.write(MODEM_CONTROL, modem_control);
+    }
+
+    /*
+        Service the chip after its IRQ fired, every received byte is handed to `receive`
+     */
+    pub fn handle_interrupt(&mut self, mut receive: impl FnMut(u8)) {
+        if !self.is_interrupt_driven() {
+            return;
+        }
+        // Bounded, a confused chip must not hang the interrupt handler
+        for _ in 0..FIFO_SIZE {
+            if self.read(INTERRUPT_ID) & NO_INTERRUPT_PENDING != 0 {
+                break;
+            }
+            // Reading the modem status acknowledges a CTS change, the line status is read below anyway
+            self.read(MODEM_STATUS);
+            while self.read(LINE_STATUS) & DATA_READY != 0 {
+                receive(self.read(DATA));
+            }
+            self.fill_fifo();
+        }
+    }
+}
+
+impl fmt::Write for Uart {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for byte in s.bytes() {
+            self.send(byte);
+        }
+        Ok(())
+    }
+}
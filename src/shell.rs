@@ -0,0 +1,636 @@
+/*
+    Interactive kernel shell
+
+    One shell runs per virtual console (keys typed while it is active) and one on the serial console.
+    Line editing works with ANSI sequences, which the VGA consoles and serial terminals both understand.
+    Tab completes command names and arguments, Ctrl-R searches the history which all shells share.
+ */
+use alloc::{collections::VecDeque, string::String, sync::Arc, vec::Vec};
+use core::fmt::{self, Write};
+use futures_util::StreamExt;
+use lazy_static::lazy_static;
+use pc_keyboard::{DecodedKey, KeyCode};
+use spin::Mutex;
+
+use crate::serial::{self, ComPort};
+use crate::task::{keyboard::ConsoleInput, serial::SerialStream};
+use crate::vga_buffer::{self, ansi::{Action, Parser}};
+
+pub mod commands;
+
+const PROMPT: &str = "blog_os> ";
+const HISTORY_SIZE: usize = 32;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Key {
+    Char(char),
+    Enter,
+    Backspace,
+    Delete,
+    Left,
+    Right,
+    Up,
+    Down,
+    Home,
+    End,
+    Tab,
+    ReverseSearch, // Ctrl-R
+    Cancel,        // Ctrl-C, Ctrl-G or Escape
+}
+
+const CTRL_C: u8 = 0x03;
+const CTRL_G: u8 = 0x07;
+const CTRL_R: u8 = 0x12;
+
+impl Key {
+    pub fn from_decoded(key: DecodedKey) -> Option<Key> {
+        match key {
+            DecodedKey::Unicode('\n') => Some(Key::Enter),
+            DecodedKey::Unicode('\x08') => Some(Key::Backspace),
+            DecodedKey::Unicode('\x7f') => Some(Key::Delete),
+            DecodedKey::Unicode('\t') => Some(Key::Tab),
+            // Ctrl+letters arrive as control characters, see HandleControl::MapLettersToUnicode
+            DecodedKey::Unicode('\x12') => Some(Key::ReverseSearch),
+            DecodedKey::Unicode('\x03' | '\x07' | '\x1b') => Some(Key::Cancel),
+            DecodedKey::Unicode(c) if !c.is_control() => Some(Key::Char(c)),
+            DecodedKey::RawKey(KeyCode::ArrowLeft) => Some(Key::Left),
+            DecodedKey::RawKey(KeyCode::ArrowRight) => Some(Key::Right),
+            DecodedKey::RawKey(KeyCode::ArrowUp) => Some(Key::Up),
+            DecodedKey::RawKey(KeyCode::ArrowDown) => Some(Key::Down),
+            DecodedKey::RawKey(KeyCode::Home) => Some(Key::Home),
+            DecodedKey::RawKey(KeyCode::End) => Some(Key::End),
+            _ => None,
+        }
+    }
+
+    /*
+        What a serial terminal sends: printable characters, control characters and VT100 key sequences
+     */
+    pub fn from_ansi(action: Action) -> Option<Key> {
+        match action {
+            Action::Print(c) => Some(Key::Char(c)),
+            Action::Execute(b'\r' | b'\n') => Some(Key::Enter),
+            Action::Execute(0x08 | 0x7f) => Some(Key::Backspace), // terminals send DEL for the backspace key
+            Action::Execute(b'\t') => Some(Key::Tab),
+            Action::Execute(CTRL_R) => Some(Key::ReverseSearch),
+            Action::Execute(CTRL_C | CTRL_G) => Some(Key::Cancel),
+            Action::Csi(csi) => match (csi.final_byte, csi.param_or(0, 0)) {
+                (b'A', _) => Some(Key::Up),
+                (b'B', _) => Some(Key::Down),
+                (b'C', _) => Some(Key::Right),
+                (b'D', _) => Some(Key::Left),
+                (b'H', _) | (b'~', 1 | 7) => Some(Key::Home),
+                (b'F', _) | (b'~', 4 | 8) => Some(Key::End),
+                (b'~', 3) => Some(Key::Delete),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+}
+
+/*
+    Where a shell writes to
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Terminal {
+    Console(usize),
+    Serial(ComPort),
+}
+
+impl Terminal {
+    pub fn clear(&mut self) {
+        let _ = self.write_str("\x1b[2J\x1b[H");
+    }
+}
+
+impl fmt::Write for Terminal {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        match *self {
+            Terminal::Console(index) => vga_buffer::_print_to(index, format_args!("{}", s)),
+            // Serial terminals need a carriage return to get back to the first column
+            Terminal::Serial(port) => {
+                for (index, line) in s.split('\n').enumerate() {
+                    if index > 0 {
+                        serial::write(port, b"\r\n");
+                    }
+                    serial::write(port, line.as_bytes());
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/*
+    Entered lines, oldest first. The console and serial shells share one, a shell made with_history() has its own.
+ */
+pub struct History {
+    entries: Mutex<VecDeque<String>>,
+}
+
+impl History {
+    pub fn new() -> Self {
+        History { entries: Mutex::new(VecDeque::new()) }
+    }
+
+    pub fn add(&self, line: &str) {
+        let mut entries = self.entries.lock();
+        if entries.back().map(String::as_str) == Some(line) {
+            return;
+        }
+        if entries.len() == HISTORY_SIZE {
+            entries.pop_front();
+        }
+        entries.push_back(String::from(line));
+    }
+
+    pub fn entries(&self) -> Vec<String> {
+        self.entries.lock().iter().cloned().collect()
+    }
+
+    fn get(&self, index: usize) -> Option<String> {
+        self.entries.lock().get(index).cloned()
+    }
+
+    fn len(&self) -> usize {
+        self.entries.lock().len()
+    }
+
+    // Newest entry containing `query`, older than `before` if given
+    fn find(&self, query: &str, before: Option<usize>) -> Option<usize> {
+        let entries = self.entries.lock();
+        let end = before.unwrap_or(entries.len());
+        (0..end).rev().find(|&index| entries[index].contains(query))
+    }
+}
+
+impl Default for History {
+    fn default() -> Self {
+        History::new()
+    }
+}
+
+lazy_static! {
+    static ref HISTORY: Arc<History> = Arc::new(History::new());
+}
+
+// The shared history, oldest entry first
+pub fn history() -> Vec<String> {
+    HISTORY.entries()
+}
+
+/*
+    State of a Ctrl-R reverse search
+ */
+struct Search {
+    query: String,
+    found: Option<usize>, // history index of the shown match
+    saved_line: Vec<char>, // restored when the search is cancelled
+}
+
+pub struct Shell {
+    terminal: Terminal,
+    line: Vec<char>,
+    cursor: usize,
+    history_index: Option<usize>, // entry shown while browsing the history with Up/Down
+    draft: String,                // the line typed before browsing the history
+    search: Option<Search>,
+    history: Arc<History>,
+}
+
+impl Shell {
+    // A shell on the shared history
+    pub fn new(terminal: Terminal) -> Self {
+        Shell::with_history(terminal, HISTORY.clone())
+    }
+
+    pub fn with_history(terminal: Terminal, history: Arc<History>) -> Self {
+        Shell {
+            terminal,
+            line: Vec::new(),
+            cursor: 0,
+            history_index: None,
+            draft: String::new(),
+            search: None,
+            history,
+        }
+    }
+
+    pub fn line(&self) -> String {
+        self.line.iter().collect()
+    }
+
+    pub fn cursor(&self) -> usize {
+        self.cursor
+    }
+
+    pub fn prompt(&mut self) {
+        let _ = self.terminal.write_str(PROMPT);
+    }
+
+    pub fn handle_key(&mut self, key: Key) {
+        if self.search.is_some() {
+            self.handle_search_key(key);
+            return;
+        }
+
+        match key {
+            Key::Char(c) => {
+                self.line.insert(self.cursor, c);
+                self.cursor += 1;
+                let _ = write!(self.terminal, "{}", c);
+                self.redraw_tail(0);
+            }
+            Key::Backspace if self.cursor > 0 => {
+                self.cursor -= 1;
+                self.line.remove(self.cursor);
+                self.move_left(1);
+                self.redraw_tail(1);
+            }
+            Key::Delete if self.cursor < self.line.len() => {
+                self.line.remove(self.cursor);
+                self.redraw_tail(1);
+            }
+            Key::Left if self.cursor > 0 => {
+                self.cursor -= 1;
+                self.move_left(1);
+            }
+            Key::Right if self.cursor < self.line.len() => {
+                self.cursor += 1;
+                let _ = self.terminal.write_str("\x1b[C");
+            }
+            Key::Home => {
+                self.move_left(self.cursor);
+                self.cursor = 0;
+            }
+            Key::End => {
+                let tail: String = self.line[self.cursor..].iter().collect();
+                let _ = self.terminal.write_str(&tail);
+                self.cursor = self.line.len();
+            }
+            Key::Up => self.browse_history(true),
+            Key::Down => self.browse_history(false),
+            Key::Tab => self.complete(),
+            Key::ReverseSearch => {
+                self.search = Some(Search { query: String::new(), found: None, saved_line: self.line.clone() });
+                self.draw_search();
+            }
+            Key::Cancel => {
+                let _ = self.terminal.write_str("^C\n");
+                self.line.clear();
+                self.cursor = 0;
+                self.history_index = None;
+                self.prompt();
+            }
+            Key::Enter => self.enter(),
+            _ => {}
+        }
+    }
+
+    fn move_left(&mut self, count: usize) {
+        if count > 0 {
+            let _ = write!(self.terminal, "\x1b[{}D", count);
+        }
+    }
+
+    /*
+        Rewrite the line from the cursor to its end after an edit, `erased` characters at the end have to be blanked
+     */
+    fn redraw_tail(&mut self, erased: usize) {
+        let tail: String = self.line[self.cursor..].iter().collect();
+        let _ = write!(self.terminal, "{}{:erased$}", tail, "");
+        self.move_left(tail.chars().count() + erased);
+    }
+
+    // Prompt and line again from the start of the screen line, e.g. after listing completions
+    fn redraw_line(&mut self) {
+        let line = self.line();
+        let _ = write!(self.terminal, "\r\x1b[K{}{}", PROMPT, line);
+        self.move_left(self.line.len() - self.cursor);
+    }
+
+    // Replace the whole line, e.g. with a history entry
+    fn set_line(&mut self, line: &str) {
+        self.move_left(self.cursor);
+        let old_len = self.line.len();
+        self.line = line.chars().collect();
+        self.cursor = self.line.len();
+        let blank = old_len.saturating_sub(self.line.len());
+        let _ = write!(self.terminal, "{}{:blank$}", line, "");
+        self.move_left(blank);
+    }
+
+    fn browse_history(&mut self, older: bool) {
+        let len = self.history.len();
+        let index = match (self.history_index, older) {
+            (None, true) if len > 0 => {
+                self.draft = self.line();
+                len - 1
+            }
+            (Some(index), true) if index > 0 => index - 1,
+            (Some(index), false) if index + 1 < len => index + 1,
+            (Some(_), false) => {
+                self.history_index = None;
+                let draft = core::mem::take(&mut self.draft);
+                self.set_line(&draft);
+                return;
+            }
+            _ => return,
+        };
+        self.history_index = Some(index);
+        // Other shells may have trimmed the history meanwhile
+        if let Some(entry) = self.history.get(index) {
+            self.set_line(&entry);
+        }
+    }
+
+    /*
+        Complete the word before the cursor: a unique candidate is inserted with a space after it,
+        otherwise the common prefix of the candidates is inserted, or the candidates are listed
+     */
+    fn complete(&mut self) {
+        let before: String = self.line[..self.cursor].iter().collect();
+        let word_start = before.rfind(' ').map_or(0, |index| index + 1);
+        let word = &before[word_start..];
+        let candidates = commands::complete(&before);
+
+        let Some(first) = candidates.first() else {
+            return;
+        };
+        let common = candidates.iter().fold(first.as_str(), |common, candidate| {
+            let len = common.chars().zip(candidate.chars()).take_while(|(a, b)| a == b).map(|(a, _)| a.len_utf8()).sum();
+            &common[..len]
+        });
+
+        if candidates.len() == 1 {
+            let insert = String::from(&common[word.len()..]) + " ";
+            insert.chars().for_each(|c| self.handle_key(Key::Char(c)));
+        } else if common.len() > word.len() {
+            let insert = String::from(&common[word.len()..]);
+            insert.chars().for_each(|c| self.handle_key(Key::Char(c)));
+        } else {
+            let _ = writeln!(self.terminal);
+            let _ = writeln!(self.terminal, "{}", candidates.join("  "));
+            self.redraw_line();
+        }
+    }
+
+    fn handle_search_key(&mut self, key: Key) {
+        let Some(search) = self.search.as_mut() else {
+            return;
+        };
+        match key {
+            Key::Char(c) => {
+                search.query.push(c);
+                search.found = self.history.find(&search.query, None);
+            }
+            Key::Backspace => {
+                search.query.pop();
+                search.found = self.history.find(&search.query, None);
+            }
+            // Next older match, the current one stays when there is none
+            Key::ReverseSearch => {
+                if let Some(older) = search.found.and_then(|index| self.history.find(&search.query, Some(index))) {
+                    search.found = Some(older);
+                }
+            }
+            Key::Cancel => {
+                let line = core::mem::take(&mut search.saved_line);
+                self.end_search(line);
+                return;
+            }
+            key => {
+                // Accept the match and handle the key on it, Enter runs it right away
+                let line = match search.found.and_then(|index| self.history.get(index)) {
+                    Some(entry) => entry.chars().collect(),
+                    None => core::mem::take(&mut search.saved_line),
+                };
+                self.end_search(line);
+                self.handle_key(key);
+                return;
+            }
+        }
+        self.draw_search();
+    }
+
+    fn draw_search(&mut self) {
+        let Some(search) = &self.search else {
+            return;
+        };
+        let found = search.found.and_then(|index| self.history.get(index)).unwrap_or_default();
+        let _ = write!(self.terminal, "\r\x1b[K(reverse-i-search)`{}': {}", search.query, found);
+    }
+
+    fn end_search(&mut self, line: Vec<char>) {
+        self.search = None;
+        self.cursor = line.len();
+        self.line = line;
+        self.redraw_line();
+    }
+
+    fn enter(&mut self) {
+        let line = self.line();
+        self.line.clear();
+        self.cursor = 0;
+        self.history_index = None;
+        let _ = self.terminal.write_str("\n");
+
+        if !line.trim().is_empty() {
+            self.history.add(&line);
+            commands::execute(&mut self.terminal, &line);
+        }
+        self.prompt();
+    }
+}
+
+/*
+    Shell on a virtual console, reading the keys pressed while it is the active one
+ */
+pub async fn console_shell(console: usize) {
+    let mut events = ConsoleInput::new(console);
+    let mut shell = Shell::new(Terminal::Console(console));
+    shell.prompt();
+
+    while let Some(event) = events.next().await {
+        if let Some(key) = event.key.and_then(Key::from_decoded) {
+            shell.handle_key(key);
+        }
+    }
+}
+
+/*
+    Shell on an interrupt driven serial port, e.g. QEMU's -serial stdio
+ */
+pub async fn serial_shell(port: ComPort) {
+    let mut bytes = SerialStream::new(port);
+    let mut decoder = Utf8Decoder::new();
+    let mut parser = Parser::new();
+    let mut shell = Shell::new(Terminal::Serial(port));
+    shell.prompt();
+
+    while let Some(byte) = bytes.next().await {
+        if let Some(key) = decoder.push(byte).and_then(|c| parser.advance(c)).and_then(Key::from_ansi) {
+            shell.handle_key(key);
+        }
+    }
+}
+
+/*
+    Turns the bytes from a serial terminal back into characters, a character may arrive split over several reads.
+    Invalid sequences become U+FFFD, a sequence cut short by a new one is dropped.
+ */
+struct Utf8Decoder {
+    bytes: [u8; 4],
+    len: usize,
+    needed: usize, // length of the sequence being collected
+}
+
+impl Utf8Decoder {
+    const fn new() -> Self {
+        Utf8Decoder { bytes: [0; 4], len: 0, needed: 0 }
+    }
+
+    // The character `byte` completes, if any
+    fn push(&mut self, byte: u8) -> Option<char> {
+        if self.len > 0 && byte & 0xc0 != 0x80 {
+            self.len = 0;
+        }
+        if self.len == 0 {
+            self.needed = match byte {
+                0x00..=0x7f => return Some(char::from(byte)),
+                0xc2..=0xdf => 2,
+                0xe0..=0xef => 3,
+                0xf0..=0xf4 => 4,
+                _ => return Some(char::REPLACEMENT_CHARACTER),
+            };
+        }
+        self.bytes[self.len] = byte;
+        self.len += 1;
+        if self.len < self.needed {
+            return None;
+        }
+        self.len = 0;
+        // Still rejects overlong forms and surrogates
+        let decoded = core::str::from_utf8(&self.bytes[..self.needed]).ok().and_then(|s| s.chars().next());
+        Some(decoded.unwrap_or(char::REPLACEMENT_CHARACTER))
+    }
+}
+
+// On the last console, with a history of its own
+#[cfg(test)]
+fn test_shell() -> Shell {
+    Shell::with_history(Terminal::Console(vga_buffer::CONSOLE_COUNT - 1), Arc::new(History::new()))
+}
+
+#[cfg(test)]
+fn type_keys(shell: &mut Shell, keys: &[Key]) {
+    for &key in keys {
+        shell.handle_key(key);
+    }
+}
+
+#[cfg(test)]
+fn type_str(shell: &mut Shell, s: &str) {
+    for c in s.chars() {
+        shell.handle_key(Key::Char(c));
+    }
+}
+
+#[test_case]
+fn test_line_editing() {
+    let mut shell = test_shell();
+    type_str(&mut shell, "cho");
+    shell.handle_key(Key::Home);
+    type_str(&mut shell, "e");
+    assert_eq!(shell.line(), "echo");
+    assert_eq!(shell.cursor(), 1);
+
+    shell.handle_key(Key::End);
+    type_str(&mut shell, " hi");
+    type_keys(&mut shell, &[Key::Left, Key::Left, Key::Left, Key::Backspace]);
+    assert_eq!(shell.line(), "ech hi");
+    assert_eq!(shell.cursor(), 3);
+    shell.handle_key(Key::Delete);
+    assert_eq!(shell.line(), "echhi");
+    assert_eq!(shell.cursor(), 3);
+}
+
+#[test_case]
+fn test_history() {
+    let mut shell = test_shell();
+    type_str(&mut shell, "echo one");
+    shell.handle_key(Key::Enter);
+    type_str(&mut shell, "echo two");
+    shell.handle_key(Key::Enter);
+    type_str(&mut shell, "draft");
+
+    shell.handle_key(Key::Up);
+    assert_eq!(shell.line(), "echo two");
+    type_keys(&mut shell, &[Key::Up, Key::Up]);
+    assert_eq!(shell.line(), "echo one");
+    shell.handle_key(Key::Down);
+    assert_eq!(shell.line(), "echo two");
+    shell.handle_key(Key::Down);
+    assert_eq!(shell.line(), "draft");
+    assert_eq!(shell.cursor(), 5);
+}
+
+#[test_case]
+fn test_reverse_search() {
+    let mut shell = test_shell();
+    type_str(&mut shell, "echo alpha");
+    shell.handle_key(Key::Enter);
+    type_str(&mut shell, "echo beta");
+    shell.handle_key(Key::Enter);
+
+    type_str(&mut shell, "draft");
+    shell.handle_key(Key::ReverseSearch);
+    type_str(&mut shell, "zzz");
+    shell.handle_key(Key::Cancel);
+    assert_eq!(shell.line(), "draft");
+
+    shell.handle_key(Key::ReverseSearch);
+    type_str(&mut shell, "echo");
+    shell.handle_key(Key::ReverseSearch);
+    shell.handle_key(Key::End);
+    assert_eq!(shell.line(), "echo alpha");
+    assert_eq!(shell.cursor(), 10);
+}
+
+#[test_case]
+fn test_completion() {
+    let mut shell = test_shell();
+    type_str(&mut shell, "upt");
+    shell.handle_key(Key::Tab);
+    assert_eq!(shell.line(), "uptime ");
+
+    shell.handle_key(Key::Cancel);
+    type_str(&mut shell, "help sh");
+    shell.handle_key(Key::Tab);
+    assert_eq!(shell.line(), "help shutdown ");
+}
+
+#[test_case]
+fn test_utf8_decoder() {
+    let mut decoder = Utf8Decoder::new();
+    let decode = |decoder: &mut Utf8Decoder, bytes: &[u8]| -> String {
+        bytes.iter().filter_map(|&byte| decoder.push(byte)).collect()
+    };
+    assert_eq!(decode(&mut decoder, "aé─".as_bytes()), "aé─");
+    // Split over two reads
+    assert_eq!(decode(&mut decoder, &[0xe2, 0x94]), "");
+    assert_eq!(decode(&mut decoder, &[0x80, b'b']), "─b");
+    // Cut short, then a stray continuation byte and an overlong form
+    assert_eq!(decode(&mut decoder, &[0xc3, b'c', 0x80, 0xe0, 0x80, 0x80]), "c\u{fffd}\u{fffd}");
+}
+
+#[test_case]
+fn test_serial_keys() {
+    let mut parser = Parser::new();
+    let keys: Vec<Key> = "a\x1b[D\x1b[3~\x7f\r".chars()
+        .filter_map(|c| parser.advance(c))
+        .filter_map(Key::from_ansi)
+        .collect();
+    assert_eq!(keys, [Key::Char('a'), Key::Left, Key::Delete, Key::Backspace, Key::Enter]);
+}
@@ -0,0 +1,333 @@
+/*
+    Shell command registry and the built-in commands
+
+    Other parts of the kernel can add their own commands with register(),
+    a command may bring a completer for its arguments (task ids, paths, ...).
+ */
+use alloc::{format, string::{String, ToString}, vec::Vec};
+use core::fmt::Write;
+use lazy_static::lazy_static;
+use spin::Mutex;
+use x86_64::VirtAddr;
+
+use super::Terminal;
+use crate::{allocator, interrupts, memory, power, task::{self, keyboard::{self, Layout}}, thread};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CommandError {
+    Usage,                // wrong arguments, the shell prints the usage line
+    Failed(&'static str),
+}
+
+pub type CommandFn = fn(&mut Terminal, &[&str]) -> Result<(), CommandError>;
+
+// Candidates for the argument `word` which follows `args`, filtering by prefix is done by the caller
+pub type CompleteFn = fn(args: &[&str], word: &str) -> Vec<String>;
+
+#[derive(Clone, Copy)]
+pub struct Command {
+    pub name: &'static str,
+    pub usage: &'static str,
+    pub help: &'static str,
+    pub run: CommandFn,
+    pub complete: Option<CompleteFn>,
+}
+
+const BUILTINS: [Command; 12] = [
+    Command { name: "help", usage: "help [command]", help: "list the commands, or explain one", run: help, complete: Some(complete_command) },
+    Command { name: "history", usage: "history", help: "list the entered lines", run: history, complete: None },
+    Command { name: "mem", usage: "mem", help: "show heap usage", run: mem, complete: None },
+    Command { name: "tasks", usage: "tasks [id]", help: "list the async tasks and their poll statistics", run: tasks, complete: Some(complete_task) },
+    Command { name: "threads", usage: "threads", help: "list the kernel threads", run: threads, complete: None },
+    Command { name: "uptime", usage: "uptime", help: "show the time since boot", run: uptime, complete: None },
+    Command { name: "clear", usage: "clear", help: "clear the screen", run: clear, complete: None },
+    Command { name: "echo", usage: "echo [text...]", help: "print the arguments", run: echo, complete: None },
+    Command { name: "reboot", usage: "reboot", help: "restart the machine", run: reboot, complete: None },
+    Command { name: "shutdown", usage: "shutdown", help: "power off the machine", run: shutdown, complete: None },
+    Command { name: "pagetable", usage: "pagetable <addr>", help: "show how an address is translated", run: pagetable, complete: None },
+    Command { name: "layout", usage: "layout [name]", help: "show or switch the keyboard layout", run: layout, complete: Some(complete_layout) },
+];
+
+lazy_static! {
+    static ref COMMANDS: Mutex<Vec<Command>> = Mutex::new(BUILTINS.to_vec());
+}
+
+/*
+    Add a command, replacing a command of the same name
+ */
+pub fn register(command: Command) {
+    let mut commands = COMMANDS.lock();
+    commands.retain(|existing| existing.name != command.name);
+    commands.push(command);
+}
+
+pub fn find(name: &str) -> Option<Command> {
+    COMMANDS.lock().iter().find(|command| command.name == name).copied()
+}
+
+// Commands sorted by name
+pub fn commands() -> Vec<Command> {
+    let mut commands = COMMANDS.lock().clone();
+    commands.sort_by_key(|command| command.name);
+    commands
+}
+
+/*
+    Completions for the last word of `line`, which ends at the cursor:
+    command names for the first word, otherwise whatever the command's completer offers
+ */
+pub fn complete(line: &str) -> Vec<String> {
+    let mut words: Vec<&str> = line.split(' ').collect();
+    let word = words.pop().unwrap_or("");
+    words.retain(|word| !word.is_empty());
+
+    let mut candidates = match words.split_first() {
+        None => complete_command(&[], word),
+        Some((name, args)) => match find(name).and_then(|command| command.complete) {
+            Some(complete) => complete(args, word),
+            None => Vec::new(),
+        },
+    };
+    candidates.retain(|candidate| candidate.starts_with(word));
+    candidates.sort();
+    candidates.dedup();
+    candidates
+}
+
+fn complete_command(args: &[&str], _word: &str) -> Vec<String> {
+    if !args.is_empty() {
+        return Vec::new();
+    }
+    commands().iter().map(|command| String::from(command.name)).collect()
+}
+
+/*
+    Run a command line: the first word names the command, the others are its arguments
+ */
+pub fn execute(terminal: &mut Terminal, line: &str) {
+    let words: Vec<&str> = line.split_whitespace().collect();
+    let Some((&name, args)) = words.split_first() else {
+        return;
+    };
+
+    // The registry is not locked while the command runs, so commands may use it themselves
+    let Some(command) = find(name) else {
+        let _ = writeln!(terminal, "{}: command not found, try help", name);
+        return;
+    };
+    match (command.run)(terminal, args) {
+        Ok(()) => {}
+        Err(CommandError::Usage) => {
+            let _ = writeln!(terminal, "usage: {}", command.usage);
+        }
+        Err(CommandError::Failed(message)) => {
+            let _ = writeln!(terminal, "{}: {}", name, message);
+        }
+    }
+}
+
+fn no_args(args: &[&str]) -> Result<(), CommandError> {
+    if args.is_empty() { Ok(()) } else { Err(CommandError::Usage) }
+}
+
+fn help(terminal: &mut Terminal, args: &[&str]) -> Result<(), CommandError> {
+    match args {
+        [] => {
+            for command in commands() {
+                let _ = writeln!(terminal, "{:<20} {}", command.usage, command.help);
+            }
+        }
+        [name] => {
+            let command = find(name).ok_or(CommandError::Failed("no such command"))?;
+            let _ = writeln!(terminal, "usage: {}\n{}", command.usage, command.help);
+        }
+        _ => return Err(CommandError::Usage),
+    }
+    Ok(())
+}
+
+fn history(terminal: &mut Terminal, args: &[&str]) -> Result<(), CommandError> {
+    no_args(args)?;
+    for (number, line) in super::history().iter().enumerate() {
+        let _ = writeln!(terminal, "{:4}  {}", number + 1, line);
+    }
+    Ok(())
+}
+
+fn mem(terminal: &mut Terminal, args: &[&str]) -> Result<(), CommandError> {
+    no_args(args)?;
+    let used = allocator::heap_used();
+    let size = allocator::HEAP_SIZE;
+    let _ = writeln!(terminal, "heap: {} of {} bytes used ({}%), {} free", used, size, used * 100 / size, size - used);
+    Ok(())
+}
+
+fn tasks(terminal: &mut Terminal, args: &[&str]) -> Result<(), CommandError> {
+    let mut tasks = task::tasks();
+    match args {
+        [] => {}
+        [id] => {
+            tasks.retain(|task| task.id.to_string() == *id);
+            if tasks.is_empty() {
+                return Err(CommandError::Failed("no such task"));
+            }
+        }
+        _ => return Err(CommandError::Usage),
+    }
+    let now = interrupts::ticks();
+    let _ = writeln!(terminal, "{:>4} {:<16} {:<11} {:<7} {:>8} {:>10} {:>8} {:>7}",
+        "ID", "NAME", "PRIORITY", "STATE", "POLLS", "KCYCLES", "WAKEUPS", "WOKEN");
+    for task in &tasks {
+        let woken = match task.last_woken {
+            Some(tick) => format!("{}s", interrupts::ticks_to_duration(now.saturating_sub(tick)).as_secs()),
+            None => String::from("-"),
+        };
+        let _ = writeln!(terminal, "{:>4} {:<16} {:<11} {:<7} {:>8} {:>10} {:>8} {:>7}",
+            task.id, task.name.as_deref().unwrap_or("-"), format!("{:?}", task.priority), format!("{:?}", task.state),
+            task.polls, task.poll_cycles / 1000, task.wakeups, woken);
+    }
+    if args.is_empty() {
+        let _ = writeln!(terminal, "{} tasks", task::task_count());
+    }
+    Ok(())
+}
+
+fn complete_task(args: &[&str], _word: &str) -> Vec<String> {
+    if !args.is_empty() {
+        return Vec::new();
+    }
+    task::tasks().iter().map(|task| task.id.to_string()).collect()
+}
+
+fn threads(terminal: &mut Terminal, args: &[&str]) -> Result<(), CommandError> {
+    no_args(args)?;
+    let threads = thread::threads();
+    let _ = writeln!(terminal, "{:>4} {:<16} {:<8}", "ID", "NAME", "STATE");
+    for thread in &threads {
+        let _ = writeln!(terminal, "{:>4} {:<16} {:<8}", thread.id, thread.name, format!("{:?}", thread.state));
+    }
+    let _ = writeln!(terminal, "{} threads", threads.len());
+    Ok(())
+}
+
+fn uptime(terminal: &mut Terminal, args: &[&str]) -> Result<(), CommandError> {
+    no_args(args)?;
+    let seconds = interrupts::uptime().as_secs();
+    let _ = writeln!(terminal, "up {}:{:02}:{:02}, {} timer ticks",
+        seconds / 3600, seconds / 60 % 60, seconds % 60, interrupts::ticks());
+    Ok(())
+}
+
+fn clear(terminal: &mut Terminal, args: &[&str]) -> Result<(), CommandError> {
+    no_args(args)?;
+    terminal.clear();
+    Ok(())
+}
+
+fn echo(terminal: &mut Terminal, args: &[&str]) -> Result<(), CommandError> {
+    let _ = writeln!(terminal, "{}", args.join(" "));
+    Ok(())
+}
+
+fn reboot(_terminal: &mut Terminal, args: &[&str]) -> Result<(), CommandError> {
+    no_args(args)?;
+    power::reboot();
+}
+
+fn shutdown(_terminal: &mut Terminal, args: &[&str]) -> Result<(), CommandError> {
+    no_args(args)?;
+    power::shutdown();
+}
+
+// Hexadecimal with 0x prefix, or decimal
+fn parse_number(text: &str) -> Option<u64> {
+    match text.strip_prefix("0x").or_else(|| text.strip_prefix("0X")) {
+        Some(hex) => u64::from_str_radix(&hex.replace('_', ""), 16).ok(),
+        None => text.parse().ok(),
+    }
+}
+
+fn pagetable(terminal: &mut Terminal, args: &[&str]) -> Result<(), CommandError> {
+    let [addr] = args else {
+        return Err(CommandError::Usage);
+    };
+    let addr = parse_number(addr).ok_or(CommandError::Usage)?;
+    let addr = VirtAddr::try_new(addr).map_err(|_| CommandError::Failed("not a canonical address"))?;
+    let (steps, phys) = memory::walk_page_tables(addr).ok_or(CommandError::Failed("paging not initialized"))?;
+
+    for step in steps {
+        let _ = writeln!(terminal, "L{}[{:3}] {:#014x} {:?}",
+            step.level, u16::from(step.index), step.addr.as_u64(), step.flags);
+    }
+    let _ = match phys {
+        Some(phys) => writeln!(terminal, "{:#x} -> {:#x}", addr.as_u64(), phys.as_u64()),
+        None => writeln!(terminal, "{:#x} is not mapped", addr.as_u64()),
+    };
+    Ok(())
+}
+
+fn layout(terminal: &mut Terminal, args: &[&str]) -> Result<(), CommandError> {
+    match args {
+        [] => {
+            let _ = write!(terminal, "layout: {}, available:", keyboard::layout().name());
+            for layout in Layout::ALL {
+                let _ = write!(terminal, " {}", layout.name());
+            }
+            let _ = writeln!(terminal);
+        }
+        [name] => keyboard::set_layout(Layout::from_name(name).ok_or(CommandError::Failed("unknown layout"))?),
+        _ => return Err(CommandError::Usage),
+    }
+    Ok(())
+}
+
+fn complete_layout(args: &[&str], _word: &str) -> Vec<String> {
+    if !args.is_empty() {
+        return Vec::new();
+    }
+    Layout::ALL.iter().map(|layout| String::from(layout.name())).collect()
+}
+
+#[test_case]
+fn test_parse_number() {
+    assert_eq!(parse_number("0xb8000"), Some(0xb8000));
+    assert_eq!(parse_number("0x_4444_4444_0000"), Some(0x4444_4444_0000));
+    assert_eq!(parse_number("4096"), Some(4096));
+    assert_eq!(parse_number("0xg"), None);
+}
+
+#[test_case]
+fn test_complete() {
+    assert_eq!(complete("he"), ["help"]);
+    assert_eq!(complete("help p"), ["pagetable"]);
+    assert_eq!(complete("help pagetable "), [] as [String; 0]);
+    assert_eq!(complete("echo a"), [] as [String; 0]);
+    assert_eq!(complete("layout d"), ["de", "dvorak"]);
+    assert!(complete("").len() >= BUILTINS.len());
+}
+
+#[test_case]
+fn test_complete_task() {
+    use crate::task::{Task, executor::Executor};
+
+    let mut executor = Executor::new();
+    let task = Task::new(core::future::pending());
+    let id = task.id().to_string();
+    executor.spawn(task);
+    assert!(complete("tasks ").contains(&id));
+    assert_eq!(complete(&format!("tasks {} ", id)), [] as [String; 0]);
+}
+
+#[test_case]
+fn test_register() {
+    fn hello(terminal: &mut Terminal, _args: &[&str]) -> Result<(), CommandError> {
+        let _ = writeln!(terminal, "hello");
+        Ok(())
+    }
+
+    assert!(find("hello").is_none());
+    register(Command { name: "hello", usage: "hello", help: "say hello", run: hello, complete: None });
+    let command = find("hello").expect("registered command not found");
+    assert_eq!(command.help, "say hello");
+    assert!(commands().windows(2).all(|pair| pair[0].name <= pair[1].name));
+}
@@ -0,0 +1,18 @@
+/*
+    Channels for passing values between tasks
+
+    oneshot: a single value, like a reply to a request
+    mpsc: a bounded queue from many senders to one receiver, send() waits while it is full
+    broadcast: every receiver gets every value, a receiver falling behind misses the oldest ones
+    watch: a single value the receivers wait to change, like a setting or a device state
+
+    The sends which never wait (oneshot and broadcast send, mpsc try_send, watch send) may be called
+    from interrupt handlers: the shared state is only locked with interrupts disabled
+    and the memory for the values is allocated when the channel is created.
+    These sends wake the waiting receivers without removing them from the waiter list, a receiver removes
+    itself once it got a value. So a watch receiver is woken once for changes it has not seen yet, not once per send.
+ */
+pub mod broadcast;
+pub mod mpsc;
+pub mod oneshot;
+pub mod watch;
@@ -0,0 +1,219 @@
+use alloc::{collections::VecDeque, sync::Arc};
+use core::task::{Context, Poll};
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+use crate::task::sync::Waiters;
+
+/*
+    Every receiver gets a clone of every value sent after it subscribed.
+    Sending never waits: the channel keeps the latest `capacity` values,
+    a receiver which falls further behind misses the oldest ones and learns how many from RecvError::Lagged.
+ */
+pub fn channel<T: Clone>(capacity: usize) -> (Sender<T>, Receiver<T>) {
+    assert!(capacity > 0, "channel capacity must not be zero");
+    let shared = Arc::new(Shared {
+        capacity,
+        state: Mutex::new(State { buffer: VecDeque::with_capacity(capacity), head: 0, senders: 1, receivers: 1, waiters: Waiters::new() }),
+    });
+    (Sender { shared: shared.clone() }, Receiver { shared, next: 0, ticket: None })
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SendError<T>(pub T); // there are no receivers
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RecvError {
+    Lagged(u64), // this many values were missed, the next recv() returns the oldest one still kept
+    Closed,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TryRecvError {
+    Empty,
+    Lagged(u64),
+    Closed,
+}
+
+struct Shared<T> {
+    capacity: usize,
+    state: Mutex<State<T>>,
+}
+
+struct State<T> {
+    buffer: VecDeque<T>,
+    head: u64, // number of values sent before buffer[0]
+    senders: usize,
+    receivers: usize,
+    waiters: Waiters,
+}
+
+impl<T> Shared<T> {
+    fn lock<R>(&self, f: impl FnOnce(&mut State<T>) -> R) -> R {
+        interrupts::without_interrupts(|| f(&mut self.state.lock()))
+    }
+}
+
+impl<T> State<T> {
+    // Number of values sent so far
+    fn tail(&self) -> u64 {
+        self.head + self.buffer.len() as u64
+    }
+}
+
+impl<T: Clone> State<T> {
+    fn take(&self, next: &mut u64) -> Result<T, TryRecvError> {
+        if *next < self.head {
+            let missed = self.head - *next;
+            *next = self.head;
+            return Err(TryRecvError::Lagged(missed));
+        }
+        match self.buffer.get((*next - self.head) as usize) {
+            Some(value) => {
+                *next += 1;
+                Ok(value.clone())
+            }
+            None if self.senders == 0 => Err(TryRecvError::Closed),
+            None => Err(TryRecvError::Empty),
+        }
+    }
+}
+
+pub struct Sender<T> {
+    shared: Arc<Shared<T>>,
+}
+
+impl<T> Sender<T> {
+    // Returns the number of receivers
+    pub fn send(&self, value: T) -> Result<usize, SendError<T>> {
+        self.shared.lock(|state| {
+            if state.receivers == 0 {
+                return Err(SendError(value));
+            }
+            if state.buffer.len() == self.shared.capacity {
+                state.buffer.pop_front();
+                state.head += 1;
+            }
+            state.buffer.push_back(value);
+            state.waiters.wake_all_by_ref();
+            Ok(state.receivers)
+        })
+    }
+
+    // The new receiver gets the values sent from now on
+    pub fn subscribe(&self) -> Receiver<T> {
+        let next = self.shared.lock(|state| {
+            state.receivers += 1;
+            state.tail()
+        });
+        Receiver { shared: self.shared.clone(), next, ticket: None }
+    }
+
+    pub fn receiver_count(&self) -> usize {
+        self.shared.lock(|state| state.receivers)
+    }
+}
+
+impl<T> Clone for Sender<T> {
+    fn clone(&self) -> Self {
+        self.shared.lock(|state| state.senders += 1);
+        Sender { shared: self.shared.clone() }
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        self.shared.lock(|state| {
+            state.senders -= 1;
+            if state.senders == 0 {
+                state.waiters.wake_all();
+            }
+        });
+    }
+}
+
+pub struct Receiver<T> {
+    shared: Arc<Shared<T>>,
+    next: u64, // number of the next value to receive
+    ticket: Option<u64>,
+}
+
+impl<T: Clone> Receiver<T> {
+    pub async fn recv(&mut self) -> Result<T, RecvError> {
+        core::future::poll_fn(|cx| self.poll_recv(cx)).await
+    }
+
+    pub fn poll_recv(&mut self, cx: &mut Context) -> Poll<Result<T, RecvError>> {
+        if crate::task::consume_budget(cx).is_pending() {
+            return Poll::Pending;
+        }
+        let shared = &self.shared;
+        shared.lock(|state| {
+            let result = match state.take(&mut self.next) {
+                Ok(value) => Ok(value),
+                Err(TryRecvError::Lagged(missed)) => Err(RecvError::Lagged(missed)),
+                Err(TryRecvError::Closed) => Err(RecvError::Closed),
+                Err(TryRecvError::Empty) => {
+                    state.waiters.register(&mut self.ticket, cx.waker());
+                    return Poll::Pending;
+                }
+            };
+            if let Some(ticket) = self.ticket.take() {
+                state.waiters.remove(ticket);
+            }
+            Poll::Ready(result)
+        })
+    }
+
+    pub fn try_recv(&mut self) -> Result<T, TryRecvError> {
+        let shared = &self.shared;
+        shared.lock(|state| state.take(&mut self.next))
+    }
+}
+
+// The clone continues where this receiver is
+impl<T> Clone for Receiver<T> {
+    fn clone(&self) -> Self {
+        self.shared.lock(|state| state.receivers += 1);
+        Receiver { shared: self.shared.clone(), next: self.next, ticket: None }
+    }
+}
+
+impl<T> Drop for Receiver<T> {
+    fn drop(&mut self) {
+        self.shared.lock(|state| {
+            state.receivers -= 1;
+            if let Some(ticket) = self.ticket {
+                state.waiters.remove(ticket);
+            }
+        });
+    }
+}
+
+#[test_case]
+fn test_broadcast() {
+    use crate::task::sync::test_util::{TestWaker, poll_fn};
+
+    let waker = TestWaker::new();
+    let (sender, mut first) = channel(2);
+    assert!(poll_fn(&waker, |cx| first.poll_recv(cx)).is_pending());
+    assert_eq!(sender.send(1), Ok(1));
+    assert_eq!(waker.wakeups(), 1);
+
+    let mut second = sender.subscribe();
+    assert_eq!(sender.send(2), Ok(2));
+    assert_eq!(poll_fn(&waker, |cx| first.poll_recv(cx)), Poll::Ready(Ok(1)));
+    assert_eq!(second.try_recv(), Ok(2));
+    assert_eq!(first.try_recv(), Ok(2));
+
+    // Only the latest two are kept
+    for value in 3..6 {
+        sender.send(value).unwrap();
+    }
+    assert_eq!(first.try_recv(), Err(TryRecvError::Lagged(1)));
+    assert_eq!(first.try_recv(), Ok(4));
+    drop(sender);
+    assert_eq!(first.try_recv(), Ok(5));
+    assert_eq!(first.try_recv(), Err(TryRecvError::Closed));
+    assert_eq!(second.try_recv(), Err(TryRecvError::Lagged(1)));
+}
@@ -0,0 +1,303 @@
+use alloc::{collections::VecDeque, sync::Arc};
+use core::{pin::Pin, task::{Context, Poll, Waker}};
+use futures_util::Stream;
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+use crate::task::sync::Waiters;
+
+/*
+    A queue of at most `capacity` values from any number of senders to one receiver.
+    send() waits while the queue is full, the waiting senders take turns in arrival order.
+    try_send() never waits, it is the one for interrupt handlers.
+ */
+pub fn channel<T>(capacity: usize) -> (Sender<T>, Receiver<T>) {
+    assert!(capacity > 0, "channel capacity must not be zero");
+    let shared = Arc::new(Shared {
+        capacity,
+        state: Mutex::new(State {
+            queue: VecDeque::with_capacity(capacity),
+            senders: 1,
+            receiver_alive: true,
+            receiver: None,
+            waiting_senders: Waiters::new(),
+        }),
+    });
+    (Sender { shared: shared.clone() }, Receiver { shared })
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SendError<T>(pub T); // the receiver is gone, here is the value back
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TrySendError<T> {
+    Full(T),
+    Closed(T),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TryRecvError {
+    Empty,
+    Disconnected, // empty and all senders are gone
+}
+
+struct Shared<T> {
+    capacity: usize,
+    state: Mutex<State<T>>,
+}
+
+struct State<T> {
+    queue: VecDeque<T>,
+    senders: usize,
+    receiver_alive: bool,
+    receiver: Option<Waker>,
+    waiting_senders: Waiters,
+}
+
+impl<T> Shared<T> {
+    fn lock<R>(&self, f: impl FnOnce(&mut State<T>) -> R) -> R {
+        interrupts::without_interrupts(|| f(&mut self.state.lock()))
+    }
+}
+
+impl<T> State<T> {
+    fn push(&mut self, value: T) {
+        self.queue.push_back(value);
+        if let Some(waker) = self.receiver.take() {
+            waker.wake();
+        }
+    }
+
+    fn pop(&mut self) -> Option<T> {
+        let value = self.queue.pop_front()?;
+        self.waiting_senders.wake_first();
+        Some(value)
+    }
+}
+
+pub struct Sender<T> {
+    shared: Arc<Shared<T>>,
+}
+
+impl<T> Sender<T> {
+    pub async fn send(&self, value: T) -> Result<(), SendError<T>> {
+        Sending { sender: self, value: Some(value), ticket: None }.await
+    }
+
+    pub fn try_send(&self, value: T) -> Result<(), TrySendError<T>> {
+        // Does not queue up behind waiting senders, an interrupt handler cannot wait for its turn
+        self.shared.lock(|state| {
+            if !state.receiver_alive {
+                Err(TrySendError::Closed(value))
+            } else if state.queue.len() == self.shared.capacity {
+                Err(TrySendError::Full(value))
+            } else {
+                state.push(value);
+                Ok(())
+            }
+        })
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.shared.lock(|state| !state.receiver_alive)
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.shared.capacity
+    }
+
+    // Values waiting for the receiver
+    pub fn len(&self) -> usize {
+        self.shared.lock(|state| state.queue.len())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl<T> Clone for Sender<T> {
+    fn clone(&self) -> Self {
+        self.shared.lock(|state| state.senders += 1);
+        Sender { shared: self.shared.clone() }
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        self.shared.lock(|state| {
+            state.senders -= 1;
+            if state.senders == 0 && let Some(waker) = state.receiver.take() {
+                waker.wake();
+            }
+        });
+    }
+}
+
+struct Sending<'a, T> {
+    sender: &'a Sender<T>,
+    value: Option<T>,
+    ticket: Option<u64>, // our place among the waiting senders
+}
+
+// The value is moved, never pinned
+impl<T> Unpin for Sending<'_, T> {}
+
+impl<T> Future for Sending<'_, T> {
+    type Output = Result<(), SendError<T>>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<(), SendError<T>>> {
+        let this = self.get_mut();
+        let shared = &this.sender.shared;
+        shared.lock(|state| {
+            if !state.receiver_alive {
+                let value = this.value.take().expect("polled after completion");
+                return Poll::Ready(Err(SendError(value)));
+            }
+            let first = match this.ticket {
+                Some(ticket) => state.waiting_senders.first() == Some(ticket),
+                None => state.waiting_senders.is_empty(),
+            };
+            if !first || state.queue.len() == shared.capacity {
+                state.waiting_senders.register(&mut this.ticket, cx.waker());
+                return Poll::Pending;
+            }
+
+            if let Some(ticket) = this.ticket.take() {
+                state.waiting_senders.remove(ticket);
+            }
+            state.push(this.value.take().expect("polled after completion"));
+            if state.queue.len() < shared.capacity {
+                state.waiting_senders.wake_first();
+            }
+            Poll::Ready(Ok(()))
+        })
+    }
+}
+
+impl<T> Drop for Sending<'_, T> {
+    fn drop(&mut self) {
+        let Some(ticket) = self.ticket else {
+            return;
+        };
+        let shared = &self.sender.shared;
+        shared.lock(|state| {
+            let first = state.waiting_senders.first() == Some(ticket);
+            state.waiting_senders.remove(ticket);
+            // We might have been woken for a slot we no longer take
+            if first && state.queue.len() < shared.capacity {
+                state.waiting_senders.wake_first();
+            }
+        });
+    }
+}
+
+pub struct Receiver<T> {
+    shared: Arc<Shared<T>>,
+}
+
+impl<T> Receiver<T> {
+    // None once the queue is empty and all senders are gone
+    pub async fn recv(&mut self) -> Option<T> {
+        core::future::poll_fn(|cx| self.poll_recv(cx)).await
+    }
+
+    pub fn poll_recv(&mut self, cx: &mut Context) -> Poll<Option<T>> {
+        if crate::task::consume_budget(cx).is_pending() {
+            return Poll::Pending;
+        }
+        self.shared.lock(|state| {
+            if let Some(value) = state.pop() {
+                return Poll::Ready(Some(value));
+            }
+            if state.senders == 0 {
+                return Poll::Ready(None);
+            }
+            match &mut state.receiver {
+                Some(waker) => waker.clone_from(cx.waker()),
+                None => state.receiver = Some(cx.waker().clone()),
+            }
+            Poll::Pending
+        })
+    }
+
+    pub fn try_recv(&mut self) -> Result<T, TryRecvError> {
+        self.shared.lock(|state| match state.pop() {
+            Some(value) => Ok(value),
+            None if state.senders == 0 => Err(TryRecvError::Disconnected),
+            None => Err(TryRecvError::Empty),
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        self.shared.lock(|state| state.queue.len())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl<T> Stream for Receiver<T> {
+    type Item = T;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<T>> {
+        self.get_mut().poll_recv(cx)
+    }
+}
+
+impl<T> Drop for Receiver<T> {
+    fn drop(&mut self) {
+        let queue = self.shared.lock(|state| {
+            state.receiver_alive = false;
+            state.waiting_senders.wake_all();
+            core::mem::take(&mut state.queue)
+        });
+        // Values nobody will receive are dropped here rather than with the lock held
+        drop(queue);
+    }
+}
+
+#[test_case]
+fn test_mpsc_backpressure() {
+    use core::pin::pin;
+    use crate::task::sync::test_util::{TestWaker, poll};
+
+    let waker = TestWaker::new();
+    let (sender, mut receiver) = channel(2);
+    assert_eq!(sender.try_send(1), Ok(()));
+    assert_eq!(sender.clone().try_send(2), Ok(()));
+    assert_eq!(sender.try_send(3), Err(TrySendError::Full(3)));
+
+    {
+        let mut sending = pin!(sender.send(3));
+        assert!(poll(sending.as_mut(), &waker).is_pending());
+        assert_eq!(receiver.try_recv(), Ok(1));
+        assert_eq!(waker.wakeups(), 1);
+        assert_eq!(poll(sending.as_mut(), &waker), Poll::Ready(Ok(())));
+    }
+
+    assert_eq!(receiver.try_recv(), Ok(2));
+    assert_eq!(receiver.try_recv(), Ok(3));
+    assert_eq!(receiver.try_recv(), Err(TryRecvError::Empty));
+    drop(sender);
+    assert_eq!(receiver.try_recv(), Err(TryRecvError::Disconnected));
+}
+
+#[test_case]
+fn test_mpsc_closed() {
+    use core::pin::pin;
+    use crate::task::sync::test_util::{TestWaker, poll};
+
+    let waker = TestWaker::new();
+    let (sender, receiver) = channel(1);
+    assert_eq!(sender.try_send(1), Ok(()));
+    let mut sending = pin!(sender.send(2));
+    assert!(poll(sending.as_mut(), &waker).is_pending());
+
+    drop(receiver);
+    assert_eq!(waker.wakeups(), 1);
+    assert_eq!(poll(sending.as_mut(), &waker), Poll::Ready(Err(SendError(2))));
+    assert!(sender.is_closed());
+    assert_eq!(sender.try_send(3), Err(TrySendError::Closed(3)));
+}
@@ -0,0 +1,134 @@
+use alloc::sync::Arc;
+use core::{pin::Pin, task::{Context, Poll, Waker}};
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+/*
+    Passes a single value, the receiver is a future which resolves to it
+ */
+pub fn channel<T>() -> (Sender<T>, Receiver<T>) {
+    let shared = Arc::new(Mutex::new(State { value: None, sender_alive: true, receiver_alive: true, waker: None }));
+    (Sender { shared: shared.clone() }, Receiver { shared })
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RecvError; // the sender was dropped without sending
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TryRecvError {
+    Empty,
+    Closed,
+}
+
+struct State<T> {
+    value: Option<T>,
+    sender_alive: bool,
+    receiver_alive: bool,
+    waker: Option<Waker>,
+}
+
+pub struct Sender<T> {
+    shared: Arc<Mutex<State<T>>>,
+}
+
+impl<T> Sender<T> {
+    // Gives the value back when the receiver is gone
+    pub fn send(self, value: T) -> Result<(), T> {
+        interrupts::without_interrupts(|| {
+            let mut state = self.shared.lock();
+            if !state.receiver_alive {
+                return Err(value);
+            }
+            state.value = Some(value);
+            if let Some(waker) = state.waker.take() {
+                waker.wake();
+            }
+            Ok(())
+        })
+    }
+
+    pub fn is_closed(&self) -> bool {
+        interrupts::without_interrupts(|| !self.shared.lock().receiver_alive)
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        interrupts::without_interrupts(|| {
+            let mut state = self.shared.lock();
+            state.sender_alive = false;
+            if let Some(waker) = state.waker.take() {
+                waker.wake();
+            }
+        });
+    }
+}
+
+pub struct Receiver<T> {
+    shared: Arc<Mutex<State<T>>>,
+}
+
+impl<T> Receiver<T> {
+    pub fn try_recv(&mut self) -> Result<T, TryRecvError> {
+        interrupts::without_interrupts(|| {
+            let mut state = self.shared.lock();
+            match state.value.take() {
+                Some(value) => Ok(value),
+                None if state.sender_alive => Err(TryRecvError::Empty),
+                None => Err(TryRecvError::Closed),
+            }
+        })
+    }
+}
+
+impl<T> Future for Receiver<T> {
+    type Output = Result<T, RecvError>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<T, RecvError>> {
+        interrupts::without_interrupts(|| {
+            let mut state = self.shared.lock();
+            if let Some(value) = state.value.take() {
+                return Poll::Ready(Ok(value));
+            }
+            if !state.sender_alive {
+                return Poll::Ready(Err(RecvError));
+            }
+            state.waker = Some(cx.waker().clone());
+            Poll::Pending
+        })
+    }
+}
+
+impl<T> Drop for Receiver<T> {
+    fn drop(&mut self) {
+        let value = interrupts::without_interrupts(|| {
+            let mut state = self.shared.lock();
+            state.receiver_alive = false;
+            state.value.take()
+        });
+        // A value nobody will receive is dropped here rather than with the lock held
+        drop(value);
+    }
+}
+
+#[test_case]
+fn test_oneshot() {
+    use crate::task::sync::test_util::{TestWaker, poll};
+
+    let waker = TestWaker::new();
+    let (sender, mut receiver) = channel();
+    assert!(poll(Pin::new(&mut receiver), &waker).is_pending());
+    assert_eq!(sender.send(7), Ok(()));
+    assert_eq!(waker.wakeups(), 1);
+    assert_eq!(poll(Pin::new(&mut receiver), &waker), Poll::Ready(Ok(7)));
+
+    let (sender, mut receiver) = channel::<u8>();
+    drop(sender);
+    assert_eq!(receiver.try_recv(), Err(TryRecvError::Closed));
+    assert_eq!(poll(Pin::new(&mut receiver), &waker), Poll::Ready(Err(RecvError)));
+
+    let (sender, receiver) = channel();
+    drop(receiver);
+    assert!(sender.is_closed());
+    assert_eq!(sender.send(1), Err(1));
+}
@@ -0,0 +1,204 @@
+use alloc::sync::Arc;
+use core::task::{Context, Poll};
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+use crate::task::sync::Waiters;
+
+/*
+    Holds a single value, receivers wait for it to change.
+    Only the latest value counts: a receiver which was busy while it changed twice sees one change.
+ */
+pub fn channel<T>(initial: T) -> (Sender<T>, Receiver<T>) {
+    let shared = Arc::new(Shared {
+        state: Mutex::new(State { value: initial, version: 0, sender_alive: true, receivers: 1, waiters: Waiters::new(), woken: 0 }),
+    });
+    (Sender { shared: shared.clone() }, Receiver { shared, seen: 0, ticket: None })
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RecvError; // the sender is gone, the value will not change anymore
+
+struct Shared<T> {
+    state: Mutex<State<T>>,
+}
+
+struct State<T> {
+    value: T,
+    version: u64, // number of changes
+    sender_alive: bool,
+    receivers: usize,
+    waiters: Waiters,
+    woken: u64, // waiters up to this ticket were woken for a change they have not seen yet
+}
+
+impl<T> Shared<T> {
+    fn lock<R>(&self, f: impl FnOnce(&mut State<T>) -> R) -> R {
+        interrupts::without_interrupts(|| f(&mut self.state.lock()))
+    }
+}
+
+impl<T> State<T> {
+    /*
+        Wake the receivers which saw the value before this change. A receiver woken by an earlier change
+        keeps its ticket until it polls and sees the change, it is not woken again meanwhile.
+     */
+    fn changed(&mut self) {
+        self.version += 1;
+        self.waiters.wake_newer_by_ref(self.woken);
+        self.woken = self.waiters.last_ticket();
+    }
+}
+
+pub struct Sender<T> {
+    shared: Arc<Shared<T>>,
+}
+
+impl<T> Sender<T> {
+    // Stores the value even without receivers, later subscribers see it
+    pub fn send(&self, value: T) {
+        let previous = self.shared.lock(|state| {
+            let previous = core::mem::replace(&mut state.value, value);
+            state.changed();
+            previous
+        });
+        // Dropped without the lock held
+        drop(previous);
+    }
+
+    // Changes the value in place
+    pub fn send_modify(&self, modify: impl FnOnce(&mut T)) {
+        self.shared.lock(|state| {
+            modify(&mut state.value);
+            state.changed();
+        });
+    }
+
+    pub fn subscribe(&self) -> Receiver<T> {
+        let seen = self.shared.lock(|state| {
+            state.receivers += 1;
+            state.version
+        });
+        Receiver { shared: self.shared.clone(), seen, ticket: None }
+    }
+
+    pub fn receiver_count(&self) -> usize {
+        self.shared.lock(|state| state.receivers)
+    }
+}
+
+impl<T: Clone> Sender<T> {
+    pub fn get(&self) -> T {
+        self.shared.lock(|state| state.value.clone())
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        self.shared.lock(|state| {
+            state.sender_alive = false;
+            state.waiters.wake_all();
+        });
+    }
+}
+
+pub struct Receiver<T> {
+    shared: Arc<Shared<T>>,
+    seen: u64, // version of the value this receiver saw last
+    ticket: Option<u64>,
+}
+
+impl<T> Receiver<T> {
+    // Waits until the value changed since this receiver saw it last, and marks it seen
+    pub async fn changed(&mut self) -> Result<(), RecvError> {
+        core::future::poll_fn(|cx| self.poll_changed(cx)).await
+    }
+
+    pub fn poll_changed(&mut self, cx: &mut Context) -> Poll<Result<(), RecvError>> {
+        let shared = &self.shared;
+        shared.lock(|state| {
+            let result = if state.version != self.seen {
+                self.seen = state.version;
+                Ok(())
+            } else if !state.sender_alive {
+                Err(RecvError)
+            } else {
+                state.waiters.register(&mut self.ticket, cx.waker());
+                return Poll::Pending;
+            };
+            if let Some(ticket) = self.ticket.take() {
+                state.waiters.remove(ticket);
+            }
+            Poll::Ready(result)
+        })
+    }
+
+    pub fn has_changed(&self) -> Result<bool, RecvError> {
+        self.shared.lock(|state| match state.version != self.seen {
+            false if !state.sender_alive => Err(RecvError),
+            changed => Ok(changed),
+        })
+    }
+}
+
+impl<T: Clone> Receiver<T> {
+    // The current value, seen or not
+    pub fn get(&self) -> T {
+        self.shared.lock(|state| state.value.clone())
+    }
+
+    // The current value, marked as seen
+    pub fn get_and_update(&mut self) -> T {
+        let shared = &self.shared;
+        shared.lock(|state| {
+            self.seen = state.version;
+            state.value.clone()
+        })
+    }
+}
+
+// The clone has seen what this receiver has seen
+impl<T> Clone for Receiver<T> {
+    fn clone(&self) -> Self {
+        self.shared.lock(|state| state.receivers += 1);
+        Receiver { shared: self.shared.clone(), seen: self.seen, ticket: None }
+    }
+}
+
+impl<T> Drop for Receiver<T> {
+    fn drop(&mut self) {
+        self.shared.lock(|state| {
+            state.receivers -= 1;
+            if let Some(ticket) = self.ticket {
+                state.waiters.remove(ticket);
+            }
+        });
+    }
+}
+
+#[test_case]
+fn test_watch() {
+    use crate::task::sync::test_util::{TestWaker, poll_fn};
+
+    let waker = TestWaker::new();
+    let (sender, mut receiver) = channel(0);
+    assert_eq!(receiver.has_changed(), Ok(false));
+    assert!(poll_fn(&waker, |cx| receiver.poll_changed(cx)).is_pending());
+
+    // Two changes while the receiver is busy are seen as one
+    sender.send(1);
+    sender.send_modify(|value| *value += 1);
+    assert_eq!(waker.wakeups(), 1);
+    let mut other = sender.subscribe();
+    assert_eq!(poll_fn(&waker, |cx| receiver.poll_changed(cx)), Poll::Ready(Ok(())));
+    assert_eq!(receiver.get(), 2);
+    assert!(poll_fn(&waker, |cx| receiver.poll_changed(cx)).is_pending());
+    assert_eq!(other.has_changed(), Ok(false));
+
+    sender.send(3);
+    assert_eq!(other.get_and_update(), 3);
+    drop(sender);
+    assert_eq!(other.has_changed(), Err(RecvError));
+    assert_eq!(poll_fn(&waker, |cx| receiver.poll_changed(cx)), Poll::Ready(Ok(())));
+    assert_eq!(poll_fn(&waker, |cx| receiver.poll_changed(cx)), Poll::Ready(Err(RecvError)));
+}
@@ -1,54 +1,193 @@
-use alloc::{collections::BTreeMap, sync::Arc, task::Wake};
+use alloc::{collections::{BTreeMap, VecDeque}, sync::Arc, task::Wake};
 use crossbeam_queue::ArrayQueue;
-use super::{Task, TaskId};
-use core::task::{Context, Poll, Waker};
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+use super::{PRIORITY_COUNT, Priority, SPAWNER, SpawnError, Task, TaskId, info::{self, TaskInfo}, join::{self, JoinHandle}};
+use core::{sync::atomic::{AtomicBool, Ordering}, task::{Context, Poll, Waker}};
+
+// Polls a class with ready tasks may be passed over by higher classes before it gets a turn
+const PRIORITY_BUDGET: u32 = 16;
+
+// Tasks handed over by Spawners which the executor did not pick up yet
+const SPAWN_QUEUE_SIZE: usize = 100;
 
 pub struct Executor {
     tasks: BTreeMap<TaskId, Task>,
-    task_queue: Arc<ArrayQueue<TaskId>>,
+    task_queue: Arc<ReadyQueue>,
     waker_cache: BTreeMap<TaskId, Waker>,
+    spawn_queue: Arc<ArrayQueue<Task>>,
+    passed_over: [u32; PRIORITY_COUNT],
+}
+
+/*
+    Ids of the tasks to poll, one queue per priority class. The scheduled flag keeps a task from being queued twice,
+    so a queue never holds more ids than its class has tasks, and add_task() makes room for that many up front.
+    So waking never fails and never allocates, interrupt handlers can do it.
+ */
+struct ReadyQueue {
+    queues: [Mutex<ClassQueue>; PRIORITY_COUNT],
+}
+
+struct ClassQueue {
+    ids: VecDeque<TaskId>,
+    tasks: usize, // of this class, the queue has room for all of them besides the ids it holds
+}
+
+impl ReadyQueue {
+    fn new() -> Self {
+        ReadyQueue {
+            queues: core::array::from_fn(|_| Mutex::new(ClassQueue { ids: VecDeque::new(), tasks: 0 })),
+        }
+    }
+
+    fn lock<R>(&self, priority: usize, f: impl FnOnce(&mut ClassQueue) -> R) -> R {
+        interrupts::without_interrupts(|| f(&mut self.queues[priority].lock()))
+    }
+
+    // Not from interrupt handlers, this is where the queue grows
+    fn add_task(&self, priority: Priority) {
+        self.lock(priority.index(), |queue| {
+            queue.tasks += 1;
+            // Ids of finished tasks may still be queued, they do not take the room of a live task
+            queue.ids.reserve(queue.tasks);
+        });
+    }
+
+    fn remove_task(&self, priority: Priority, scheduled: &AtomicBool) {
+        // Later wakeups of the finished task must not queue it again, the room for it is gone
+        scheduled.store(true, Ordering::Release);
+        self.lock(priority.index(), |queue| queue.tasks -= 1);
+    }
+
+    fn schedule(&self, task_id: TaskId, priority: Priority, scheduled: &AtomicBool) {
+        if scheduled.swap(true, Ordering::AcqRel) {
+            return;
+        }
+        self.lock(priority.index(), |queue| {
+            debug_assert!(queue.ids.len() < queue.ids.capacity(), "ready queue full");
+            queue.ids.push_back(task_id);
+        });
+    }
+
+    fn is_waiting(&self, class: usize) -> bool {
+        self.lock(class, |queue| !queue.ids.is_empty())
+    }
+
+    /*
+        The next task of the highest class, unless a lower class was passed over PRIORITY_BUDGET times:
+        busy interactive tasks slow background tasks down but do not starve them
+     */
+    fn pop(&self, passed_over: &mut [u32; PRIORITY_COUNT]) -> Option<TaskId> {
+        let waiting = |class: &usize| self.is_waiting(*class);
+        let class = (0..PRIORITY_COUNT).filter(waiting).find(|&class| passed_over[class] >= PRIORITY_BUDGET)
+            .or_else(|| (0..PRIORITY_COUNT).find(waiting))?;
+
+        for lower in (class + 1..PRIORITY_COUNT).filter(waiting) {
+            passed_over[lower] += 1;
+        }
+        passed_over[class] = 0;
+        self.lock(class, |queue| queue.ids.pop_front())
+    }
+
+    fn is_empty(&self) -> bool {
+        (0..PRIORITY_COUNT).all(|class| !self.is_waiting(class))
+    }
+}
+
+/*
+    Cloneable handle which adds tasks to an executor while it runs.
+    The queue is lock free, so spawning works from tasks and from interrupt handlers alike.
+ */
+#[derive(Clone)]
+pub struct Spawner {
+    spawn_queue: Arc<ArrayQueue<Task>>,
+}
+
+impl Spawner {
+    pub fn spawn(&self, task: Task) -> Result<(), SpawnError> {
+        self.spawn_queue.push(task).map_err(|_| SpawnError::QueueFull)
+    }
+
+    pub fn spawn_joinable<F>(&self, future: F) -> Result<JoinHandle<F::Output>, SpawnError>
+    where
+        F: Future + Send + 'static,
+        F::Output: Send + 'static,
+    {
+        let (task, handle) = join::joinable(future);
+        self.spawn(task)?;
+        Ok(handle)
+    }
 }
 
 impl Executor {
     pub fn new() -> Self {
         Executor { 
             tasks: BTreeMap::new(),
-            task_queue: Arc::new(ArrayQueue::new(100)), 
+            task_queue: Arc::new(ReadyQueue::new()),
             waker_cache: BTreeMap::new(),
+            spawn_queue: Arc::new(ArrayQueue::new(SPAWN_QUEUE_SIZE)),
+            passed_over: [0; PRIORITY_COUNT],
         }
     }
 
     pub fn spawn(&mut self, task: Task) {
-        let task_id = task.id;
-        if self.tasks.insert(task.id, task).is_some() {
-            panic!("task with same ID already exists");
-        }
-        self.task_queue.push(task_id).expect("queue full");
+        add_task(&mut self.tasks, &self.task_queue, task);
+    }
+
+    pub fn spawn_joinable<F>(&mut self, future: F) -> JoinHandle<F::Output>
+    where
+        F: Future + Send + 'static,
+        F::Output: Send + 'static,
+    {
+        let (task, handle) = join::joinable(future);
+        self.spawn(task);
+        handle
+    }
+
+    pub fn spawner(&self) -> Spawner {
+        Spawner { spawn_queue: self.spawn_queue.clone() }
     }
 
     fn run_ready_tasks(&mut self) {
-        // SyntaxTips: destructuring to split self into its three fields to avoid some borrow checker errors
+        // SyntaxTips: destructuring to split self into its fields to avoid some borrow checker errors
         let Self {
             tasks,
             task_queue,
             waker_cache,
+            spawn_queue,
+            passed_over,
         } = self;
 
-        while let Some(task_id) = task_queue.pop() {
+        loop {
+            // Tasks spawned by the tasks polled last round, or by interrupt handlers
+            while let Some(task) = spawn_queue.pop() {
+                add_task(tasks, task_queue, task);
+            }
+            let Some(task_id) = task_queue.pop(passed_over) else {
+                break;
+            };
+
             let task = match tasks.get_mut(&task_id) {
                 Some(task) => task,
                 None => continue,
             };
+            // Cleared before the poll, so the task can wake itself during it
+            task.info.scheduled.store(false, Ordering::Release);
 
             // simply ignore waker check for now
             //todo: create waker from task_id
             let waker = waker_cache
                 .entry(task_id)
-                .or_insert_with(|| TaskWaker::new(task_id, task_queue.clone()));
+                .or_insert_with(|| TaskWaker::new(task.info.clone(), task_queue.clone()));
 
             let mut context = Context::from_waker(waker);
-            match task.poll(&mut context) {
+            super::reset_budget();
+            let start = task.info.start_poll();
+            let poll = task.poll(&mut context);
+            task.info.end_poll(start);
+            match poll {
                 Poll::Ready(()) => {
+                    task_queue.remove_task(task.priority, &task.info.scheduled);
                     tasks.remove(&task_id);
                     waker_cache.remove(&task_id);
                 }
@@ -57,7 +196,11 @@ impl Executor {
         }
     }
 
+    /*
+        Poll tasks forever, the first executor to run also serves task::spawn()
+     */
     pub fn run(&mut self) -> ! {
+        let _ = SPAWNER.try_init_once(|| self.spawner());
         loop {
             self.run_ready_tasks();
             self.sleep_if_idle();
@@ -65,12 +208,11 @@ impl Executor {
     }
 
     fn sleep_if_idle(&self) {
-        use x86_64::instructions::interrupts::{self, enable_and_hlt};
-
         interrupts::disable();
 
-        if self.task_queue.is_empty() {
-            enable_and_hlt();
+        if self.task_queue.is_empty() && self.spawn_queue.is_empty() {
+            // Lets other threads run, or halts
+            crate::thread::idle();
         } else {
             interrupts::enable();
         }
@@ -78,18 +220,29 @@ impl Executor {
 
 }
 
+fn add_task(tasks: &mut BTreeMap<TaskId, Task>, task_queue: &ReadyQueue, task: Task) {
+    let task_info = task.info.clone();
+    if tasks.insert(task.id, task).is_some() {
+        panic!("task with same ID already exists");
+    }
+    info::register(&task_info);
+    task_queue.add_task(task_info.priority);
+    task_queue.schedule(task_info.id, task_info.priority, &task_info.scheduled);
+}
+
 struct TaskWaker {
-    task_id: TaskId,
-    task_queue: Arc<ArrayQueue<TaskId>>,
+    info: Arc<TaskInfo>,
+    task_queue: Arc<ReadyQueue>,
 }
 
 impl TaskWaker {
     fn wake_task(&self) {
-        self.task_queue.push(self.task_id).expect("task_queue full");
+        self.info.woken();
+        self.task_queue.schedule(self.info.id, self.info.priority, &self.info.scheduled);
     }
 
-    fn new(task_id: TaskId, task_queue: Arc<ArrayQueue<TaskId>>) -> Waker {
-        Waker::from(Arc::new(TaskWaker { task_id, task_queue }))
+    fn new(info: Arc<TaskInfo>, task_queue: Arc<ReadyQueue>) -> Waker {
+        Waker::from(Arc::new(TaskWaker { info, task_queue }))
     }
 }
 
@@ -103,3 +256,152 @@ impl Wake for TaskWaker {
     }
 }
 
+
+#[test_case]
+fn test_spawner() {
+    use core::sync::atomic::{AtomicBool, Ordering};
+
+    static SPAWNED_RAN: AtomicBool = AtomicBool::new(false);
+
+    let mut executor = Executor::new();
+    let spawner = executor.spawner();
+    executor.spawn(Task::new(async move {
+        spawner.spawn(Task::new(async {
+            SPAWNED_RAN.store(true, Ordering::Relaxed);
+        })).expect("spawn failed");
+    }));
+    executor.run_ready_tasks();
+
+    assert!(SPAWNED_RAN.load(Ordering::Relaxed));
+    assert!(executor.tasks.is_empty());
+}
+
+#[test_case]
+fn test_join_handle() {
+    use core::sync::atomic::{AtomicUsize, Ordering};
+
+    static RESULT: AtomicUsize = AtomicUsize::new(0);
+
+    let mut executor = Executor::new();
+    let handle = executor.spawn_joinable(async { 6 * 7 });
+    executor.spawn(Task::new(async move {
+        RESULT.store(handle.await.expect("task cancelled"), Ordering::Relaxed);
+    }));
+    executor.run_ready_tasks();
+
+    assert_eq!(RESULT.load(Ordering::Relaxed), 42);
+    assert!(executor.tasks.is_empty());
+}
+
+#[test_case]
+fn test_abort() {
+    use core::sync::atomic::{AtomicBool, Ordering};
+
+    static CANCELLED: AtomicBool = AtomicBool::new(false);
+
+    let mut executor = Executor::new();
+    let tasks_before = super::task_count();
+    let handle = executor.spawn_joinable(core::future::pending::<()>());
+    executor.run_ready_tasks();
+    assert!(!handle.is_finished());
+
+    handle.abort();
+    executor.spawn(Task::new(async move {
+        CANCELLED.store(handle.await == Err(join::JoinError::Cancelled), Ordering::Relaxed);
+    }));
+    executor.run_ready_tasks();
+
+    assert!(CANCELLED.load(Ordering::Relaxed));
+    assert_eq!(super::task_count(), tasks_before);
+}
+
+#[test_case]
+fn test_many_yielding_tasks() {
+    use core::sync::atomic::AtomicUsize;
+
+    // More tasks than the ready queues used to have room for, each going through its queue again and again
+    const COUNT: usize = 150;
+    const YIELDS: usize = 5;
+    static POLLED: AtomicUsize = AtomicUsize::new(0);
+    static FINISHED: AtomicUsize = AtomicUsize::new(0);
+
+    let mut executor = Executor::new();
+    for _ in 0..COUNT {
+        executor.spawn(Task::new(async {
+            for _ in 0..YIELDS {
+                POLLED.fetch_add(1, Ordering::Relaxed);
+                super::yield_now().await;
+            }
+            FINISHED.fetch_add(1, Ordering::Relaxed);
+        }));
+    }
+    executor.run_ready_tasks();
+
+    assert_eq!(POLLED.load(Ordering::Relaxed), COUNT * YIELDS);
+    assert_eq!(FINISHED.load(Ordering::Relaxed), COUNT);
+    assert!(executor.tasks.is_empty());
+    assert!(executor.task_queue.is_empty());
+}
+
+#[test_case]
+fn test_priorities() {
+    use alloc::vec::Vec;
+    use spin::Mutex;
+
+    let order = Arc::new(Mutex::new(Vec::new()));
+    let mut executor = Executor::new();
+    for priority in [Priority::Background, Priority::Interactive, Priority::BottomHalf] {
+        let order = order.clone();
+        executor.spawn(Task::with_priority(priority, async move {
+            order.lock().push(priority);
+        }));
+    }
+    executor.run_ready_tasks();
+
+    assert_eq!(*order.lock(), [Priority::BottomHalf, Priority::Interactive, Priority::Background]);
+}
+
+#[test_case]
+fn test_no_starvation() {
+    use core::sync::atomic::AtomicU32;
+
+    const ROUNDS: u32 = PRIORITY_BUDGET * 4;
+    static BUSY_ROUNDS: AtomicU32 = AtomicU32::new(0);
+    static SEEN_BY_BACKGROUND: AtomicU32 = AtomicU32::new(u32::MAX);
+
+    let mut executor = Executor::new();
+    executor.spawn(Task::with_priority(Priority::BottomHalf, async {
+        for _ in 0..ROUNDS {
+            BUSY_ROUNDS.fetch_add(1, Ordering::Relaxed);
+            super::yield_now().await;
+        }
+    }));
+    executor.spawn(Task::with_priority(Priority::Background, async {
+        SEEN_BY_BACKGROUND.store(BUSY_ROUNDS.load(Ordering::Relaxed), Ordering::Relaxed);
+    }));
+    executor.run_ready_tasks();
+
+    // The background task ran while the busy one was still going
+    assert!(SEEN_BY_BACKGROUND.load(Ordering::Relaxed) <= PRIORITY_BUDGET + 1);
+}
+
+#[test_case]
+fn test_task_info() {
+    let mut executor = Executor::new();
+    let task = Task::new(async {
+        super::yield_now().await;
+        core::future::pending::<()>().await;
+    }).with_name("sleeper");
+    let id = task.id();
+    executor.spawn(task);
+    executor.run_ready_tasks();
+
+    let snapshot = super::tasks().into_iter().find(|task| task.id == id).expect("task not listed");
+    assert_eq!(snapshot.name.as_deref(), Some("sleeper"));
+    assert_eq!(snapshot.state, super::TaskState::Waiting);
+    assert_eq!((snapshot.polls, snapshot.wakeups), (2, 1));
+    assert!(snapshot.last_woken.is_some());
+
+    drop(executor);
+    assert!(super::tasks().iter().all(|task| task.id != id));
+}
@@ -0,0 +1,134 @@
+/*
+    What the executors know about their tasks, for the shell's tasks command and the like
+
+    Every spawned task is listed until it is dropped. The counters are updated by the executor
+    around each poll and by the task's waker, tasks() takes a snapshot of all of them.
+ */
+use alloc::{collections::BTreeMap, string::String, sync::Arc, vec::Vec};
+use core::{fmt, sync::atomic::{AtomicBool, AtomicU64, Ordering}};
+use lazy_static::lazy_static;
+use spin::Mutex;
+
+use super::{Priority, TaskId};
+
+impl fmt::Display for TaskId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+// Shared by a task, its wakers and the task list
+pub(super) struct TaskInfo {
+    pub(super) id: TaskId,
+    pub(super) name: Option<String>,
+    pub(super) priority: Priority,
+    pub(super) scheduled: AtomicBool, // set while the task waits in the executor's ready queue
+    running: AtomicBool,
+    polls: AtomicU64,
+    poll_cycles: AtomicU64, // time spent in poll, in TSC cycles
+    wakeups: AtomicU64,
+    last_woken: AtomicU64, // timer tick of the latest wakeup
+}
+
+impl TaskInfo {
+    pub(super) fn new(id: TaskId, priority: Priority) -> Self {
+        TaskInfo {
+            id,
+            name: None,
+            priority,
+            scheduled: AtomicBool::new(false),
+            running: AtomicBool::new(false),
+            polls: AtomicU64::new(0),
+            poll_cycles: AtomicU64::new(0),
+            wakeups: AtomicU64::new(0),
+            last_woken: AtomicU64::new(0),
+        }
+    }
+
+    // Called by the executor around a poll
+    pub(super) fn start_poll(&self) -> u64 {
+        self.running.store(true, Ordering::Relaxed);
+        tsc()
+    }
+
+    pub(super) fn end_poll(&self, start: u64) {
+        self.running.store(false, Ordering::Relaxed);
+        self.polls.fetch_add(1, Ordering::Relaxed);
+        self.poll_cycles.fetch_add(tsc().wrapping_sub(start), Ordering::Relaxed);
+    }
+
+    // Called by the waker, also from interrupt handlers
+    pub(super) fn woken(&self) {
+        self.wakeups.fetch_add(1, Ordering::Relaxed);
+        self.last_woken.store(crate::interrupts::ticks(), Ordering::Relaxed);
+    }
+
+    fn snapshot(&self) -> TaskSnapshot {
+        let state = if self.running.load(Ordering::Relaxed) {
+            TaskState::Running
+        } else if self.scheduled.load(Ordering::Relaxed) {
+            TaskState::Ready
+        } else {
+            TaskState::Waiting
+        };
+        let wakeups = self.wakeups.load(Ordering::Relaxed);
+        TaskSnapshot {
+            id: self.id,
+            name: self.name.clone(),
+            priority: self.priority,
+            state,
+            polls: self.polls.load(Ordering::Relaxed),
+            poll_cycles: self.poll_cycles.load(Ordering::Relaxed),
+            wakeups,
+            last_woken: (wakeups > 0).then(|| self.last_woken.load(Ordering::Relaxed)),
+        }
+    }
+}
+
+fn tsc() -> u64 {
+    unsafe { core::arch::x86_64::_rdtsc() }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TaskState {
+    Running, // the task asking is usually the one running
+    Ready,
+    Waiting,
+}
+
+#[derive(Debug, Clone)]
+pub struct TaskSnapshot {
+    pub id: TaskId,
+    pub name: Option<String>,
+    pub priority: Priority,
+    pub state: TaskState,
+    pub polls: u64,
+    pub poll_cycles: u64,
+    pub wakeups: u64,
+    pub last_woken: Option<u64>, // timer tick, see interrupts::ticks()
+}
+
+lazy_static! {
+    static ref TASKS: Mutex<BTreeMap<TaskId, Arc<TaskInfo>>> = Mutex::new(BTreeMap::new());
+}
+
+pub(super) fn register(info: &Arc<TaskInfo>) {
+    use x86_64::instructions::interrupts;
+
+    interrupts::without_interrupts(|| TASKS.lock().insert(info.id, info.clone()));
+}
+
+pub(super) fn unregister(id: TaskId) {
+    use x86_64::instructions::interrupts;
+
+    interrupts::without_interrupts(|| TASKS.lock().remove(&id));
+}
+
+/*
+    The spawned tasks which did not finish yet, ordered by id
+ */
+pub fn tasks() -> Vec<TaskSnapshot> {
+    use x86_64::instructions::interrupts;
+
+    interrupts::without_interrupts(|| TASKS.lock().values().map(|info| info.snapshot()).collect())
+}
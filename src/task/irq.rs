@@ -0,0 +1,216 @@
+/*
+    From an interrupt handler to a task
+
+    A driver declares a static IrqQueue for its IRQ line and payload type. The interrupt handler pushes
+    what it read from the device, the driver's task reads it through the queue's IrqStream.
+    The queue does not allocate or lock in the handler, and it counts what it had to drop:
+    payloads arriving before the queue exists and payloads not fitting in it anymore. stats() lists the counts of all queues.
+
+    Masking and unmasking the line is left to the driver, it knows when its device is ready, see interrupts::unmask_irq.
+ */
+use alloc::vec::Vec;
+use core::{pin::Pin, sync::atomic::{AtomicBool, AtomicU64, Ordering}, task::{Context, Poll}};
+use conquer_once::spin::OnceCell;
+use crossbeam_queue::ArrayQueue;
+use futures_util::{Stream, task::AtomicWaker};
+use lazy_static::lazy_static;
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+pub struct IrqQueue<T> {
+    name: &'static str,
+    irq: u8,
+    queue: OnceCell<ArrayQueue<T>>, // OnceCell ensures the allocation does not happen in the interrupt handler
+    waker: AtomicWaker,
+    received: AtomicU64,
+    dropped_full: AtomicU64,
+    dropped_early: AtomicU64,
+    stream_created: AtomicBool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IrqStats {
+    pub name: &'static str,
+    pub irq: u8,
+    pub capacity: usize, // 0 before the queue exists
+    pub queued: usize,
+    pub received: u64,      // pushed into the queue
+    pub dropped_full: u64,  // arrived while the queue was full
+    pub dropped_early: u64, // arrived before the queue existed
+}
+
+impl<T> IrqQueue<T> {
+    pub const fn new(name: &'static str, irq: u8) -> Self {
+        IrqQueue {
+            name,
+            irq,
+            queue: OnceCell::uninit(),
+            waker: AtomicWaker::new(),
+            received: AtomicU64::new(0),
+            dropped_full: AtomicU64::new(0),
+            dropped_early: AtomicU64::new(0),
+            stream_created: AtomicBool::new(false),
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    pub fn irq(&self) -> u8 {
+        self.irq
+    }
+
+    // Called by the interrupt handler, gives the payload back when it was dropped
+    pub fn push(&self, value: T) -> Result<(), T> {
+        let Ok(queue) = self.queue.try_get() else {
+            self.dropped_early.fetch_add(1, Ordering::Relaxed);
+            return Err(value);
+        };
+        match queue.push(value) {
+            Ok(()) => {
+                self.received.fetch_add(1, Ordering::Relaxed);
+                self.waker.wake();
+                Ok(())
+            }
+            Err(value) => {
+                self.dropped_full.fetch_add(1, Ordering::Relaxed);
+                Err(value)
+            }
+        }
+    }
+
+    // A driver able to hold its device off checks this before reading from it
+    pub fn is_full(&self) -> bool {
+        self.queue.try_get().is_ok_and(|queue| queue.is_full())
+    }
+
+    pub fn len(&self) -> usize {
+        self.queue.try_get().map_or(0, |queue| queue.len())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn stats(&self) -> IrqStats {
+        let (capacity, queued) = match self.queue.try_get() {
+            Ok(queue) => (queue.capacity(), queue.len()),
+            Err(_) => (0, 0),
+        };
+        IrqStats {
+            name: self.name,
+            irq: self.irq,
+            capacity,
+            queued,
+            received: self.received.load(Ordering::Relaxed),
+            dropped_full: self.dropped_full.load(Ordering::Relaxed),
+            dropped_early: self.dropped_early.load(Ordering::Relaxed),
+        }
+    }
+}
+
+impl<T: Send> IrqQueue<T> {
+    /*
+        Create the queue, payloads pushed before are dropped. Not from the interrupt handler.
+        Returns false if it already exists.
+     */
+    pub fn init(&'static self, capacity: usize) -> bool {
+        if self.queue.try_init_once(|| ArrayQueue::new(capacity)).is_err() {
+            return false;
+        }
+        interrupts::without_interrupts(|| QUEUES.lock().push(self));
+        true
+    }
+
+    // The queue has a single reader, this panics when called a second time or before init()
+    pub fn stream(&'static self) -> IrqStream<T> {
+        assert!(self.queue.is_initialized(), "IrqQueue {} is not initialized", self.name);
+        assert!(!self.stream_created.swap(true, Ordering::Relaxed), "IrqQueue {} already has a stream", self.name);
+        IrqStream { queue: self }
+    }
+}
+
+pub struct IrqStream<T: 'static> {
+    queue: &'static IrqQueue<T>,
+}
+
+impl<T> IrqStream<T> {
+    pub fn queue(&self) -> &'static IrqQueue<T> {
+        self.queue
+    }
+
+    fn pop(&self) -> Option<T> {
+        self.queue.queue.try_get().expect("not initialized").pop()
+    }
+}
+
+// Never ends, the device might always send more
+impl<T> Stream for IrqStream<T> {
+    type Item = T;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<T>> {
+        if super::consume_budget(cx).is_pending() {
+            return Poll::Pending;
+        }
+        if let Some(value) = self.pop() {
+            return Poll::Ready(Some(value));
+        }
+
+        self.queue.waker.register(cx.waker());
+        match self.pop() {
+            Some(value) => {
+                self.queue.waker.take();
+                Poll::Ready(Some(value))
+            }
+            None => Poll::Pending,
+        }
+    }
+}
+
+trait StatsSource: Sync {
+    fn stats(&self) -> IrqStats;
+}
+
+impl<T: Send> StatsSource for IrqQueue<T> {
+    fn stats(&self) -> IrqStats {
+        IrqQueue::stats(self)
+    }
+}
+
+lazy_static! {
+    static ref QUEUES: Mutex<Vec<&'static dyn StatsSource>> = Mutex::new(Vec::new());
+}
+
+/*
+    The counts of every queue created so far, in creation order
+ */
+pub fn stats() -> Vec<IrqStats> {
+    interrupts::without_interrupts(|| QUEUES.lock().iter().map(|queue| queue.stats()).collect())
+}
+
+#[test_case]
+fn test_irq_queue() {
+    use crate::task::sync::test_util::{TestWaker, poll_fn};
+
+    static QUEUE: IrqQueue<u32> = IrqQueue::new("test", 0);
+
+    assert_eq!(QUEUE.push(1), Err(1));
+    assert!(QUEUE.init(2));
+    assert!(!QUEUE.init(4));
+    let mut stream = QUEUE.stream();
+    let waker = TestWaker::new();
+    assert!(poll_fn(&waker, |cx| Pin::new(&mut stream).poll_next(cx)).is_pending());
+
+    assert_eq!(QUEUE.push(2), Ok(()));
+    assert_eq!(waker.wakeups(), 1);
+    assert_eq!(QUEUE.push(3), Ok(()));
+    assert!(QUEUE.is_full());
+    assert_eq!(QUEUE.push(4), Err(4));
+    assert_eq!(poll_fn(&waker, |cx| Pin::new(&mut stream).poll_next(cx)), Poll::Ready(Some(2)));
+
+    let stats = QUEUE.stats();
+    assert_eq!((stats.capacity, stats.queued), (2, 1));
+    assert_eq!((stats.received, stats.dropped_full, stats.dropped_early), (2, 1, 1));
+    assert!(self::stats().contains(&stats));
+}
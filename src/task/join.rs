@@ -0,0 +1,140 @@
+/*
+    Results of spawned tasks
+
+    joinable() wraps a future into a Task and hands out a JoinHandle, which resolves to the future's output.
+    Dropping the handle detaches the task, abort() drops the future at its next poll.
+ */
+use alloc::{boxed::Box, sync::Arc};
+use core::{pin::Pin, task::{Context, Poll, Waker}};
+use spin::Mutex;
+
+use super::Task;
+
+/*
+    Why a task has no result.
+    A panicking task takes the kernel down with it, there is no unwinding which could catch it here.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum JoinError {
+    Cancelled, // aborted, or dropped by its executor before it finished
+}
+
+struct State<T> {
+    result: Option<Result<T, JoinError>>,
+    finished: bool, // stays set once the handle took the result
+    aborted: bool,
+    task_waker: Option<Waker>,
+    join_waker: Option<Waker>,
+}
+
+impl<T> State<T> {
+    // The waker of the handle, to be called once the lock is released
+    fn finish(&mut self, result: Result<T, JoinError>) -> Option<Waker> {
+        if self.finished {
+            return None;
+        }
+        self.finished = true;
+        self.result = Some(result);
+        self.join_waker.take()
+    }
+}
+
+// The future of a joinable Task
+struct Joinable<F: Future> {
+    future: Pin<Box<F>>,
+    state: Arc<Mutex<State<F::Output>>>,
+}
+
+impl<F: Future> Future for Joinable<F> {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        {
+            let mut state = self.state.lock();
+            if state.aborted {
+                return Poll::Ready(()); // the executor drops us, see Drop
+            }
+            state.task_waker = Some(cx.waker().clone());
+        }
+
+        let output = match self.future.as_mut().poll(cx) {
+            Poll::Ready(output) => output,
+            Poll::Pending => return Poll::Pending,
+        };
+        let waker = self.state.lock().finish(Ok(output));
+        if let Some(waker) = waker {
+            waker.wake();
+        }
+        Poll::Ready(())
+    }
+}
+
+impl<F: Future> Drop for Joinable<F> {
+    fn drop(&mut self) {
+        let waker = self.state.lock().finish(Err(JoinError::Cancelled));
+        if let Some(waker) = waker {
+            waker.wake();
+        }
+    }
+}
+
+pub struct JoinHandle<T> {
+    state: Arc<Mutex<State<T>>>,
+}
+
+impl<T> JoinHandle<T> {
+    /*
+        Stop the task: its future is dropped instead of polled again and the handle resolves to Cancelled.
+        Does nothing once the task finished.
+     */
+    pub fn abort(&self) {
+        let waker = {
+            let mut state = self.state.lock();
+            if state.finished {
+                return;
+            }
+            state.aborted = true;
+            state.task_waker.take()
+        };
+        if let Some(waker) = waker {
+            waker.wake();
+        }
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.state.lock().finished
+    }
+}
+
+impl<T> Future for JoinHandle<T> {
+    type Output = Result<T, JoinError>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<T, JoinError>> {
+        let mut state = self.state.lock();
+        if let Some(result) = state.result.take() {
+            return Poll::Ready(result);
+        }
+        assert!(!state.finished, "JoinHandle polled after it returned the result");
+        state.join_waker = Some(cx.waker().clone());
+        Poll::Pending
+    }
+}
+
+/*
+    A Task running `future` and the handle which waits for its output
+ */
+pub fn joinable<F>(future: F) -> (Task, JoinHandle<F::Output>)
+where
+    F: Future + Send + 'static,
+    F::Output: Send + 'static,
+{
+    let state = Arc::new(Mutex::new(State {
+        result: None,
+        finished: false,
+        aborted: false,
+        task_waker: None,
+        join_waker: None,
+    }));
+    let task = Task::new(Joinable { future: Box::pin(future), state: state.clone() });
+    (task, JoinHandle { state })
+}
@@ -1,38 +1,81 @@
-use core::{pin::Pin, task::{Context, Poll}};
+use core::{pin::Pin, sync::atomic::{AtomicBool, AtomicU8, AtomicU64, Ordering}, task::{Context, Poll}};
 
 use conquer_once::spin::OnceCell;
-use crossbeam_queue::ArrayQueue;
-use futures_util::{Stream, StreamExt, task::AtomicWaker};
-use pc_keyboard::{DecodedKey, HandleControl, Keyboard, ScancodeSet1, layouts};
+use futures_util::{Stream, StreamExt, ready};
+use pc_keyboard::{DecodedKey, EventDecoder, HandleControl, KeyCode, KeyState, ScancodeSet, ScancodeSet1, layouts::{self, AnyLayout}};
 
-use crate::{print, println};
+use super::{channel::mpsc::{self, TrySendError}, irq::{IrqQueue, IrqStream}};
+use crate::{console_print, ps2::{self, Leds}, vga_buffer::{self, CONSOLE_COUNT}};
 
-// OnceCell ensures the initialization does not happend in the interrupt handler
-static SCANCODE_QUEUE: OnceCell<ArrayQueue<u8>> = OnceCell::uninit();
+pub const DEFAULT_SCANCODE_QUEUE_SIZE: usize = 100;
 
-static WAKER: AtomicWaker = AtomicWaker::new();
+static SCANCODES: IrqQueue<u8> = IrqQueue::new("keyboard", ps2::KEYBOARD_IRQ);
 
-pub (crate) fn add_scancode(scancode: u8) {
-    if let Ok(queue) = SCANCODE_QUEUE.try_get() {
-        if let Err(_) = queue.push(scancode) {
-            println!("WARNING: scancode queue full, dropping keyboard input");
-        } else {
-            WAKER.wake();
+// No output from the interrupt handler, printing would take the console locks there
+static STALLS: AtomicU64 = AtomicU64::new(0);
+
+// A scancode waits in the controller because the queue was full
+static STALLED: AtomicBool = AtomicBool::new(false);
+
+/*
+    Create the scancode queue, right after the heap so keys typed during boot wait there for ScancodeStream.
+    Returns false if it already exists.
+ */
+pub fn init_scancode_queue(capacity: usize) -> bool {
+    SCANCODES.init(capacity)
+}
+
+/*
+    Called by the keyboard IRQ handler.
+    While the queue is full the scancode stays in the controller, which holds the keyboard off
+    until ScancodeStream made room and fetches it.
+ */
+pub(crate) fn handle_interrupt() {
+    if SCANCODES.is_full() {
+        if !STALLED.swap(true, Ordering::Relaxed) {
+            STALLS.fetch_add(1, Ordering::Relaxed);
         }
-    } else {
-        println!("WARNING: scancode queue uninitialized");
+        return;
+    }
+    if let Some(scancode) = ps2::read_data() {
+        add_scancode(scancode);
+    }
+}
+
+pub (crate) fn add_scancode(scancode: u8) {
+    // Only full while a PS/2 command was waiting, or before the queue exists; counted by the queue
+    let _ = SCANCODES.push(scancode);
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ScancodeStats {
+    pub capacity: usize,     // 0 before the queue exists
+    pub queued: usize,
+    pub dropped_full: u64,  // arrived while a PS/2 command was waiting and the queue was full
+    pub dropped_early: u64, // arrived before the queue existed
+    pub stalls: u64,        // times the keyboard was held off by a full queue
+}
+
+pub fn scancode_stats() -> ScancodeStats {
+    let stats = SCANCODES.stats();
+    ScancodeStats {
+        capacity: stats.capacity,
+        queued: stats.queued,
+        dropped_full: stats.dropped_full,
+        dropped_early: stats.dropped_early,
+        stalls: STALLS.load(Ordering::Relaxed),
     }
 }
 
 pub struct ScancodeStream {
-    _private: (), // Syntaxtip: prevent construct of the struct outside of this module
+    scancodes: IrqStream<u8>,
 }
 
 impl ScancodeStream {
+    // Uses the queue from init_scancode_queue(), or creates one of the default size
     pub fn new() -> Self {
-        SCANCODE_QUEUE.try_init_once(|| ArrayQueue::new(100))
-            .expect("ScancodeStream::new should only be called once");
-        ScancodeStream { _private: () }
+        init_scancode_queue(DEFAULT_SCANCODE_QUEUE_SIZE);
+        ScancodeStream { scancodes: SCANCODES.stream() }
     }
 }
 
@@ -40,42 +83,334 @@ impl Stream for ScancodeStream {
     type Item = u8;
 
     fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<u8>> {
-        let queue = SCANCODE_QUEUE.try_get().expect("not initialized");
+        let scancode = ready!(Pin::new(&mut self.get_mut().scancodes).poll_next(cx));
+        if STALLED.swap(false, Ordering::Relaxed) {
+            // The IRQ of the held back scancode has passed, fetch it ourselves now that there is room
+            use x86_64::instructions::interrupts;
 
-        if let Some(scancode) = queue.pop()  {
-            return Poll::Ready(Some(scancode));
+            if let Some(held) = interrupts::without_interrupts(ps2::read_data) {
+                add_scancode(held);
+            }
         }
+        Poll::Ready(scancode)
+    }
+}
 
-        WAKER.register(&cx.waker());
-        match queue.pop() {
-            Some(scancode) => {
-                WAKER.take();
-                Poll::Ready(Some(scancode))
-            }
-            None => Poll::Pending,
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Layout {
+    Us,
+    Uk,
+    De,
+    Fr, // AZERTY
+    Dvorak,
+    Colemak,
+}
+
+impl Layout {
+    pub const ALL: [Layout; 6] = [Layout::Us, Layout::Uk, Layout::De, Layout::Fr, Layout::Dvorak, Layout::Colemak];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Layout::Us => "us",
+            Layout::Uk => "uk",
+            Layout::De => "de",
+            Layout::Fr => "fr",
+            Layout::Dvorak => "dvorak",
+            Layout::Colemak => "colemak",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Layout> {
+        Layout::ALL.into_iter().find(|layout| layout.name().eq_ignore_ascii_case(name))
+    }
+
+    fn keyboard_layout(self) -> AnyLayout {
+        match self {
+            Layout::Us => AnyLayout::Us104Key(layouts::Us104Key),
+            Layout::Uk => AnyLayout::Uk105Key(layouts::Uk105Key),
+            Layout::De => AnyLayout::De105Key(layouts::De105Key),
+            Layout::Fr => AnyLayout::Azerty(layouts::Azerty),
+            Layout::Dvorak => AnyLayout::Dvorak104Key(layouts::Dvorak104Key),
+            Layout::Colemak => AnyLayout::Colemak(layouts::Colemak),
+        }
+    }
+}
+
+// Index into Layout::ALL, picked up by the decoder with the next key
+static LAYOUT: AtomicU8 = AtomicU8::new(0);
+
+pub fn layout() -> Layout {
+    Layout::ALL[LAYOUT.load(Ordering::Relaxed) as usize]
+}
+
+pub fn set_layout(layout: Layout) {
+    let index = Layout::ALL.iter().position(|&other| other == layout).unwrap_or(0);
+    LAYOUT.store(index as u8, Ordering::Relaxed);
+}
+
+/*
+    Held modifiers and lock states.
+    pc_keyboard keeps its own copy private, so the decoder tracks them again for the KeyEvents.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Modifiers {
+    pub lshift: bool,
+    pub rshift: bool,
+    pub lctrl: bool,
+    pub rctrl: bool,
+    pub alt: bool,
+    pub alt_gr: bool,
+    pub caps_lock: bool,
+    pub num_lock: bool,
+    pub scroll_lock: bool,
+    pause: bool, // the hidden right control which makes the following NumLock a Pause key
+}
+
+impl Modifiers {
+    // Num Lock starts switched on, like in pc_keyboard
+    pub const fn new() -> Self {
+        Modifiers {
+            lshift: false,
+            rshift: false,
+            lctrl: false,
+            rctrl: false,
+            alt: false,
+            alt_gr: false,
+            caps_lock: false,
+            num_lock: true,
+            scroll_lock: false,
+            pause: false,
+        }
+    }
+
+    pub fn shift(&self) -> bool {
+        self.lshift || self.rshift
+    }
+
+    pub fn ctrl(&self) -> bool {
+        self.lctrl || self.rctrl
+    }
+
+    pub fn leds(&self) -> Leds {
+        Leds { scroll_lock: self.scroll_lock, num_lock: self.num_lock, caps_lock: self.caps_lock }
+    }
+
+    fn update(&mut self, code: KeyCode, state: KeyState) {
+        let down = state != KeyState::Up;
+        match code {
+            KeyCode::LShift => self.lshift = down,
+            KeyCode::RShift => self.rshift = down,
+            KeyCode::LControl => self.lctrl = down,
+            KeyCode::RControl => self.rctrl = down,
+            KeyCode::RControl2 => self.pause = down,
+            KeyCode::LAlt => self.alt = down,
+            KeyCode::RAltGr => self.alt_gr = down,
+            // Locks toggle with every press, like in pc_keyboard
+            KeyCode::CapsLock if down => self.caps_lock = !self.caps_lock,
+            KeyCode::ScrollLock if down => self.scroll_lock = !self.scroll_lock,
+            KeyCode::NumpadLock if down && !self.pause => self.num_lock = !self.num_lock,
+            _ => {}
         }
+    }
+}
+
+impl Default for Modifiers {
+    fn default() -> Self {
+        Modifiers::new()
+    }
+}
 
+/*
+    A key going down or up, with the modifiers as they are after it.
+    `key` is the decoded character or raw key of a press, releases have none.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KeyEvent {
+    pub code: KeyCode,
+    pub state: KeyState,
+    pub modifiers: Modifiers,
+    pub key: Option<DecodedKey>,
+}
+
+impl KeyEvent {
+    pub fn is_pressed(&self) -> bool {
+        self.state != KeyState::Up
     }
 }
 
+/*
+    Every virtual console has its own input channel, key events while it is active go there.
+    The channel exists once somebody reads from it, see ConsoleInput.
+ */
+const CONSOLE_INPUT_SIZE: usize = 64;
+
+static CONSOLE_INPUT: [OnceCell<mpsc::Sender<KeyEvent>>; CONSOLE_COUNT] = [const { OnceCell::uninit() }; CONSOLE_COUNT];
+
+// Returns false when nobody reads the console's input
+fn add_console_input(console: usize, event: KeyEvent) -> bool {
+    let Ok(sender) = CONSOLE_INPUT[console].try_get() else {
+        return false;
+    };
+    match sender.try_send(event) {
+        // A full queue means the reader is too slow, dropping the event is all we can do
+        Ok(()) | Err(TrySendError::Full(_)) => true,
+        Err(TrySendError::Closed(_)) => false,
+    }
+}
+
+/*
+    Key presses and releases while a given virtual console is active
+ */
+pub struct ConsoleInput {
+    events: mpsc::Receiver<KeyEvent>,
+}
+
+impl ConsoleInput {
+    pub fn new(console: usize) -> Self {
+        let (sender, events) = mpsc::channel(CONSOLE_INPUT_SIZE);
+        CONSOLE_INPUT[console].try_init_once(|| sender)
+            .expect("ConsoleInput::new should only be called once per console");
+        ConsoleInput { events }
+    }
+}
+
+impl Stream for ConsoleInput {
+    type Item = KeyEvent;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<KeyEvent>> {
+        self.get_mut().events.poll_recv(cx)
+    }
+}
+
+const CONSOLE_KEYS: [KeyCode; CONSOLE_COUNT] = [
+    KeyCode::F1, KeyCode::F2, KeyCode::F3, KeyCode::F4, KeyCode::F5, KeyCode::F6,
+];
+
+/*
+    Decode the keyboard in the current layout, keep the lock LEDs in sync and dispatch the key events:
+    Alt+F1..F6 switch the virtual console, Shift+PageUp/PageDown scroll it,
+    other events go to the input queue of the active console, or typed characters are echoed when nobody reads it
+ */
 pub async fn print_keypresses() {
     let mut scancodes = ScancodeStream::new();
-    let mut keyboard = Keyboard::new(
-        ScancodeSet1::new(), 
-        layouts::Us104Key,
-        HandleControl::Ignore);
-    
+    let mut scancode_set = ScancodeSet1::new();
+    let mut current = layout();
+    // Only the event decoder can change its layout, so it is used without pc_keyboard's Keyboard wrapper
+    let mut decoder = EventDecoder::new(current.keyboard_layout(), HandleControl::MapLettersToUnicode);
+    let mut modifiers = Modifiers::new();
+
     while let Some(scancode) = scancodes.next().await {
-        if let Ok(Some(key_event)) = keyboard.add_byte(scancode) {
-            if let Some(key) = keyboard.process_keyevent(key_event) {
-                match key {
-                    DecodedKey::Unicode(character) => print!("{}", character),
-                    DecodedKey::RawKey(key) => print!("{:?}", key),
+        let Ok(Some(raw_event)) = scancode_set.advance_state(scancode) else {
+            continue;
+        };
+        if layout() != current {
+            current = layout();
+            decoder.change_layout(current.keyboard_layout());
+        }
+        let leds = modifiers.leds();
+        modifiers.update(raw_event.code, raw_event.state);
+        if modifiers.leds() != leds {
+            // Without a working controller the locks still work, they just do not light up
+            let _ = ps2::set_leds(modifiers.leds());
+        }
+
+        if raw_event.state == KeyState::Down {
+            if modifiers.alt && let Some(console) = CONSOLE_KEYS.iter().position(|&key| key == raw_event.code) {
+                vga_buffer::switch_console(console);
+                continue;
+            }
+            match raw_event.code {
+                KeyCode::PageUp if modifiers.shift() => {
+                    vga_buffer::scroll_back(vga_buffer::SCROLL_PAGE);
+                    continue;
+                }
+                KeyCode::PageDown if modifiers.shift() => {
+                    vga_buffer::scroll_forward(vga_buffer::SCROLL_PAGE);
+                    continue;
                 }
+                _ => {}
             }
         }
+
+        let event = KeyEvent {
+            code: raw_event.code,
+            state: raw_event.state,
+            modifiers,
+            key: decoder.process_keyevent(raw_event),
+        };
+        let console = vga_buffer::active_console();
+        if add_console_input(console, event) {
+            continue;
+        }
+        // Keys without a character (arrows, modifiers, ...) mean nothing without a reader
+        if let Some(DecodedKey::Unicode(character)) = event.key {
+            console_print!(console, "{}", character);
+        }
+    }
+
+}
+
+#[test_case]
+fn test_modifiers() {
+    let mut modifiers = Modifiers::new();
+    modifiers.update(KeyCode::LShift, KeyState::Down);
+    modifiers.update(KeyCode::RControl, KeyState::Down);
+    assert!(modifiers.shift() && modifiers.ctrl());
+    modifiers.update(KeyCode::LShift, KeyState::Up);
+    assert!(!modifiers.shift() && modifiers.ctrl());
+
+    modifiers.update(KeyCode::CapsLock, KeyState::Down);
+    modifiers.update(KeyCode::CapsLock, KeyState::Up);
+    assert!(modifiers.caps_lock);
+    modifiers.update(KeyCode::CapsLock, KeyState::Down);
+    assert!(!modifiers.caps_lock);
+
+    // Pause arrives as RControl2 + NumLock and must not toggle Num Lock
+    modifiers.update(KeyCode::RControl2, KeyState::Down);
+    modifiers.update(KeyCode::NumpadLock, KeyState::Down);
+    assert!(modifiers.num_lock);
+    modifiers.update(KeyCode::RControl2, KeyState::Up);
+    modifiers.update(KeyCode::NumpadLock, KeyState::Down);
+    assert!(!modifiers.num_lock);
+}
+
+#[test_case]
+fn test_layouts() {
+    assert_eq!(Layout::from_name("DE"), Some(Layout::De));
+    assert_eq!(Layout::from_name("qwertz"), None);
+    for layout in Layout::ALL {
+        assert_eq!(Layout::from_name(layout.name()), Some(layout));
     }
 
+    // The key right of T is a Y on US keyboards and a Z on German ones
+    let event = ScancodeSet1::new().advance_state(0x15).unwrap().unwrap();
+    let mut decoder = EventDecoder::new(Layout::Us.keyboard_layout(), HandleControl::Ignore);
+    assert_eq!(decoder.process_keyevent(event.clone()), Some(DecodedKey::Unicode('y')));
+    decoder.change_layout(Layout::De.keyboard_layout());
+    assert_eq!(decoder.process_keyevent(event), Some(DecodedKey::Unicode('z')));
+
+    let previous = layout();
+    set_layout(Layout::Colemak);
+    assert_eq!(layout(), Layout::Colemak);
+    set_layout(previous);
 }
 
+#[test_case]
+fn test_scancode_overflow() {
+    use super::sync::test_util::{TestWaker, poll_fn};
+
+    init_scancode_queue(DEFAULT_SCANCODE_QUEUE_SIZE);
+    let before = scancode_stats();
+    for _ in before.queued..=before.capacity {
+        add_scancode(0x1E);
+    }
+    let after = scancode_stats();
+    assert_eq!(after.queued, after.capacity);
+    assert_eq!(after.dropped_full, before.dropped_full + 1);
 
+    // Drain it through the stream, with a full poll budget for every scancode
+    let mut scancodes = ScancodeStream::new();
+    let waker = TestWaker::new();
+    while poll_fn(&waker, |cx| scancodes.poll_next_unpin(cx)).is_ready() {}
+    assert_eq!(scancode_stats().queued, 0);
+}
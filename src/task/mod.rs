@@ -1,18 +1,55 @@
-use core::{pin::Pin, sync::atomic::{AtomicU64, Ordering}, task::{Context, Poll}};
-use alloc::boxed::Box;
+use core::{pin::Pin, sync::atomic::{AtomicU32, AtomicU64, AtomicUsize, Ordering}, task::{Context, Poll}};
+use alloc::{boxed::Box, string::String, sync::Arc};
+use conquer_once::spin::OnceCell;
+
+use executor::Spawner;
+use info::TaskInfo;
+use join::JoinHandle;
+
+pub use info::{TaskSnapshot, TaskState, tasks};
 
 pub mod simple_executor;
 pub mod keyboard;
+pub mod mouse;
+pub mod serial;
 pub mod executor;
+pub mod join;
+pub mod info;
+pub mod sync;
+pub mod channel;
+pub mod irq;
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
-struct TaskId(u64);
+pub struct TaskId(u64);
 
 impl TaskId {
     fn new() -> Self {
         static NEXT_ID: AtomicU64 = AtomicU64::new(0);
         TaskId(NEXT_ID.fetch_add(1, Ordering::Relaxed))
     }
+
+    pub fn as_u64(self) -> u64 {
+        self.0
+    }
+}
+
+/*
+    Scheduling classes, the executor polls ready tasks of a higher class first.
+    A class passed over too often gets a turn anyway, see executor::PRIORITY_BUDGET.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Priority {
+    BottomHalf,  // the work interrupt handlers hand over, e.g. decoding scancodes
+    Interactive, // anything a user waits for, the default
+    Background,
+}
+
+pub const PRIORITY_COUNT: usize = 3;
+
+impl Priority {
+    fn index(self) -> usize {
+        self as usize
+    }
 }
 
 pub struct Task {
@@ -23,20 +60,181 @@ pub struct Task {
 
         Pin<Box> ensures value cannot be moved in memory by placing it on the heap
         and preventing the creation of &mut references to it.(Async/await might be self refential)
+
+        Send lets a Spawner hand tasks over from anywhere, interrupt handlers included.
     */
-    future: Pin<Box<dyn Future<Output = ()>>>, 
+    future: Pin<Box<dyn Future<Output = ()> + Send>>,
     id: TaskId,
+    priority: Priority,
+    info: Arc<TaskInfo>,
+}
+
+// Tasks which exist right now, the executor drops them once they finish
+static LIVE_TASKS: AtomicUsize = AtomicUsize::new(0);
+
+pub fn task_count() -> usize {
+    LIVE_TASKS.load(Ordering::Relaxed)
 }
 
 impl Task {
-    pub fn new(future: impl Future<Output = ()> + 'static) -> Self {
+    pub fn new(future: impl Future<Output = ()> + Send + 'static) -> Self {
+        Self::with_priority(Priority::Interactive, future)
+    }
+
+    pub fn with_priority(priority: Priority, future: impl Future<Output = ()> + Send + 'static) -> Self {
+        LIVE_TASKS.fetch_add(1, Ordering::Relaxed);
+        let id = TaskId::new();
         Self {
-            id: TaskId::new(),
+            id,
             future: Box::pin(future),
+            priority,
+            info: Arc::new(TaskInfo::new(id, priority)),
         }
     }
 
+    // The name shown by tasks(), set before spawning
+    pub fn with_name(mut self, name: impl Into<String>) -> Self {
+        Arc::get_mut(&mut self.info).expect("task already spawned").name = Some(name.into());
+        self
+    }
+
+    pub fn id(&self) -> TaskId {
+        self.id
+    }
+
+    pub fn priority(&self) -> Priority {
+        self.priority
+    }
+
     fn poll(&mut self, context: &mut Context) -> Poll<()> {
         self.future.as_mut().poll(context)
     }
-}
\ No newline at end of file
+}
+
+/*
+    Let the other ready tasks run before continuing
+ */
+pub fn yield_now() -> YieldNow {
+    YieldNow { yielded: false }
+}
+
+pub struct YieldNow {
+    yielded: bool,
+}
+
+impl Future for YieldNow {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        if self.yielded {
+            return Poll::Ready(());
+        }
+        self.yielded = true;
+        cx.waker().wake_by_ref();
+        Poll::Pending
+    }
+}
+
+/*
+    Streams which are always ready would let a task loop without ever returning to the executor.
+    They take one unit of this budget per item, once it is used up the task has to yield.
+    The executors refill it before every poll. Each thread has its own budget, the scheduler swaps it
+    on every thread switch (see thread), so executors in other threads neither refill nor use up this one.
+ */
+pub(crate) const POLL_BUDGET: u32 = 64;
+
+// The budget of the running thread
+static BUDGET: AtomicU32 = AtomicU32::new(POLL_BUDGET);
+
+fn reset_budget() {
+    set_budget(POLL_BUDGET);
+}
+
+// For the scheduler, which saves the budget of the thread it switches out and restores the one it switches to
+pub(crate) fn budget() -> u32 {
+    BUDGET.load(Ordering::Relaxed)
+}
+
+pub(crate) fn set_budget(budget: u32) {
+    BUDGET.store(budget, Ordering::Relaxed);
+}
+
+pub fn consume_budget(cx: &mut Context) -> Poll<()> {
+    match BUDGET.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |budget| budget.checked_sub(1)) {
+        Ok(_) => Poll::Ready(()),
+        Err(_) => {
+            cx.waker().wake_by_ref();
+            Poll::Pending
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SpawnError {
+    QueueFull,
+    NoExecutor, // spawn() before an executor runs
+}
+
+// Set by the first executor which runs, see spawn()
+static SPAWNER: OnceCell<Spawner> = OnceCell::uninit();
+
+/*
+    Add a task to the running executor, from a task or from interrupt-adjacent code
+ */
+pub fn spawn(task: Task) -> Result<(), SpawnError> {
+    SPAWNER.try_get().map_err(|_| SpawnError::NoExecutor)?.spawn(task)
+}
+
+// spawn() for a future whose output is wanted
+pub fn spawn_joinable<F>(future: F) -> Result<JoinHandle<F::Output>, SpawnError>
+where
+    F: Future + Send + 'static,
+    F::Output: Send + 'static,
+{
+    SPAWNER.try_get().map_err(|_| SpawnError::NoExecutor)?.spawn_joinable(future)
+}
+
+impl Drop for Task {
+    fn drop(&mut self) {
+        info::unregister(self.id);
+        LIVE_TASKS.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+#[test_case]
+fn test_consume_budget() {
+    use core::task::Waker;
+
+    let mut context = Context::from_waker(Waker::noop());
+    reset_budget();
+    let mut ready = 0;
+    while consume_budget(&mut context).is_ready() {
+        ready += 1;
+    }
+    assert_eq!(ready, POLL_BUDGET);
+    reset_budget();
+}
+
+#[test_case]
+fn test_budget_per_thread() {
+    use core::task::Waker;
+
+    let mut context = Context::from_waker(Waker::noop());
+    reset_budget();
+    assert!(consume_budget(&mut context).is_ready());
+
+    // Another thread using up and refilling its budget leaves this one alone
+    let other = crate::thread::spawn("budget", || {
+        let mut context = Context::from_waker(Waker::noop());
+        assert_eq!(budget(), POLL_BUDGET);
+        while consume_budget(&mut context).is_ready() {}
+        crate::thread::yield_now();
+        assert_eq!(budget(), 0);
+        reset_budget();
+    }).expect("spawn");
+    crate::thread::yield_now();
+    assert_eq!(budget(), POLL_BUDGET - 1);
+    other.join();
+    assert_eq!(budget(), POLL_BUDGET - 1);
+    reset_budget();
+}
@@ -0,0 +1,174 @@
+use core::{pin::Pin, task::{Context, Poll}};
+
+use futures_util::{Stream, ready};
+
+use super::irq::{IrqQueue, IrqStream};
+use crate::ps2::{self, MouseKind};
+
+/*
+    Bytes from the mouse on the second PS/2 port, MouseStream puts them together to packets.
+    The queue exists once somebody reads from it, bytes before are dropped.
+ */
+const BYTE_QUEUE_SIZE: usize = 256;
+
+static BYTES: IrqQueue<u8> = IrqQueue::new("mouse", ps2::MOUSE_IRQ);
+
+pub(crate) fn add_byte(byte: u8) {
+    // A full queue means the reader is too slow, the decoder resyncs on the lost bytes
+    let _ = BYTES.push(byte);
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct MouseButtons {
+    pub left: bool,
+    pub right: bool,
+    pub middle: bool,
+}
+
+/*
+    One packet: the movement since the previous one in screen direction (dy grows downwards),
+    the wheel steps (positive is towards the user) and the buttons held now
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct MouseEvent {
+    pub dx: i16,
+    pub dy: i16,
+    pub wheel: i8,
+    pub buttons: MouseButtons,
+}
+
+// First packet byte
+const LEFT_BUTTON: u8 = 0x01;
+const RIGHT_BUTTON: u8 = 0x02;
+const MIDDLE_BUTTON: u8 = 0x04;
+const ALWAYS_SET: u8 = 0x08;
+const X_SIGN: u8 = 0x10;
+const Y_SIGN: u8 = 0x20;
+const X_OVERFLOW: u8 = 0x40;
+const Y_OVERFLOW: u8 = 0x80;
+
+struct PacketDecoder {
+    packet: [u8; 4],
+    len: usize,
+    size: usize, // 3 for a standard mouse, 4 with a wheel
+}
+
+impl PacketDecoder {
+    fn new(kind: MouseKind) -> Self {
+        let size = match kind {
+            MouseKind::Standard => 3,
+            MouseKind::IntelliMouse => 4,
+        };
+        PacketDecoder { packet: [0; 4], len: 0, size }
+    }
+
+    fn advance(&mut self, byte: u8) -> Option<MouseEvent> {
+        // A first byte without the always set bit means we lost track of the packets, wait for the next one
+        if self.len == 0 && byte & ALWAYS_SET == 0 {
+            return None;
+        }
+        self.packet[self.len] = byte;
+        self.len += 1;
+        if self.len < self.size {
+            return None;
+        }
+        self.len = 0;
+        Some(self.decode())
+    }
+
+    fn decode(&self) -> MouseEvent {
+        let [flags, x, y, extra] = self.packet;
+        // 9 bit two's complement values, the sign bit lives in the first byte
+        let movement = |value: u8, sign: u8, overflow: u8| {
+            if flags & overflow != 0 {
+                0 // too fast to measure, the value is garbage
+            } else if flags & sign != 0 {
+                i16::from(value) - 256
+            } else {
+                i16::from(value)
+            }
+        };
+        // The wheel is a 4 bit two's complement value
+        let wheel = if self.size == 4 { ((extra << 4) as i8) >> 4 } else { 0 };
+
+        MouseEvent {
+            dx: movement(x, X_SIGN, X_OVERFLOW),
+            dy: -movement(y, Y_SIGN, Y_OVERFLOW),
+            wheel,
+            buttons: MouseButtons {
+                left: flags & LEFT_BUTTON != 0,
+                right: flags & RIGHT_BUTTON != 0,
+                middle: flags & MIDDLE_BUTTON != 0,
+            },
+        }
+    }
+}
+
+/*
+    Movement and button events of the PS/2 mouse, nothing arrives if ps2::init() found none
+ */
+pub struct MouseStream {
+    bytes: IrqStream<u8>,
+    decoder: PacketDecoder,
+}
+
+impl MouseStream {
+    pub fn new() -> Self {
+        assert!(BYTES.init(BYTE_QUEUE_SIZE), "MouseStream::new should only be called once");
+        MouseStream { bytes: BYTES.stream(), decoder: PacketDecoder::new(ps2::mouse().unwrap_or(MouseKind::Standard)) }
+    }
+}
+
+impl Default for MouseStream {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Stream for MouseStream {
+    type Item = MouseEvent;
+
+    // Every byte takes from the poll budget, not just the complete packets
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<MouseEvent>> {
+        let stream = self.get_mut();
+        loop {
+            let Some(byte) = ready!(Pin::new(&mut stream.bytes).poll_next(cx)) else {
+                return Poll::Ready(None);
+            };
+            if let Some(event) = stream.decoder.advance(byte) {
+                return Poll::Ready(Some(event));
+            }
+        }
+    }
+}
+
+#[test_case]
+fn test_standard_packets() {
+    let mut decoder = PacketDecoder::new(MouseKind::Standard);
+    assert_eq!(decoder.advance(0x09), None);
+    assert_eq!(decoder.advance(5), None);
+    let event = decoder.advance(3).expect("packet complete");
+    assert_eq!((event.dx, event.dy), (5, -3));
+    assert!(event.buttons.left && !event.buttons.right);
+
+    // Negative movement, left and down
+    let event = [0x38, 0xFE, 0xF0].into_iter().filter_map(|byte| decoder.advance(byte)).next().unwrap();
+    assert_eq!((event.dx, event.dy), (-2, 16));
+
+    // Overflowing movement is dropped
+    let event = [0x48, 0x80, 0x01].into_iter().filter_map(|byte| decoder.advance(byte)).next().unwrap();
+    assert_eq!((event.dx, event.dy), (0, -1));
+}
+
+#[test_case]
+fn test_wheel_and_resync() {
+    let mut decoder = PacketDecoder::new(MouseKind::IntelliMouse);
+    // A stray byte without the always set bit is skipped
+    assert_eq!(decoder.advance(0x01), None);
+    let events: alloc::vec::Vec<MouseEvent> = [0x0C, 0, 0, 0x0F, 0x08, 0, 0, 0x01]
+        .into_iter().filter_map(|byte| decoder.advance(byte)).collect();
+    assert_eq!(events.len(), 2);
+    assert_eq!(events[0].wheel, -1);
+    assert!(events[0].buttons.middle);
+    assert_eq!(events[1].wheel, 1);
+}
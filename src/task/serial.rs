@@ -0,0 +1,71 @@
+use core::{pin::Pin, sync::atomic::{AtomicBool, Ordering}, task::{Context, Poll}};
+
+use futures_util::{Stream, ready};
+
+use super::irq::{IrqQueue, IrqStream};
+use crate::serial::{self, ComPort, COM_PORT_COUNT};
+
+/*
+    Bytes received by the interrupt handler, one queue per COM port.
+    The queue exists once somebody reads from it, see SerialStream.
+ */
+const RECEIVE_QUEUE_SIZE: usize = 256;
+
+// With RTS/CTS flow control the sender is paused above HIGH_WATER bytes and resumed below LOW_WATER
+const HIGH_WATER: usize = RECEIVE_QUEUE_SIZE * 3 / 4;
+const LOW_WATER: usize = RECEIVE_QUEUE_SIZE / 4;
+
+// Indexed by ComPort::index(), COM1 and COM3 share IRQ 4, COM2 and COM4 IRQ 3
+static RECEIVE_QUEUES: [IrqQueue<u8>; COM_PORT_COUNT] = [
+    IrqQueue::new("com1", 4),
+    IrqQueue::new("com2", 3),
+    IrqQueue::new("com3", 4),
+    IrqQueue::new("com4", 3),
+];
+
+static PAUSED: [AtomicBool; COM_PORT_COUNT] = [const { AtomicBool::new(false) }; COM_PORT_COUNT];
+
+/*
+    Called by the interrupt handler, returns false when the queue fills up and the sender should pause.
+    Bytes nobody reads are dropped.
+ */
+pub(crate) fn add_received(port: ComPort, byte: u8) -> bool {
+    let queue = &RECEIVE_QUEUES[port.index()];
+    // A full queue means the reader is too slow, dropping the byte is all we can do
+    let _ = queue.push(byte);
+
+    if queue.len() < HIGH_WATER {
+        return true;
+    }
+    PAUSED[port.index()].store(true, Ordering::Relaxed);
+    false
+}
+
+/*
+    Bytes received on an interrupt driven COM port, see serial::enable_interrupts
+ */
+pub struct SerialStream {
+    port: ComPort,
+    bytes: IrqStream<u8>,
+}
+
+impl SerialStream {
+    pub fn new(port: ComPort) -> Self {
+        let queue = &RECEIVE_QUEUES[port.index()];
+        assert!(queue.init(RECEIVE_QUEUE_SIZE), "SerialStream::new should only be called once per port");
+        SerialStream { port, bytes: queue.stream() }
+    }
+}
+
+impl Stream for SerialStream {
+    type Item = u8;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<u8>> {
+        let stream = self.get_mut();
+        let byte = ready!(Pin::new(&mut stream.bytes).poll_next(cx));
+        if stream.bytes.queue().len() < LOW_WATER && PAUSED[stream.port.index()].swap(false, Ordering::Relaxed) {
+            serial::resume_receive(stream.port);
+        }
+        Poll::Ready(byte)
+    }
+}
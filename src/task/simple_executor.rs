@@ -22,6 +22,7 @@ impl SimpleExecutor {
         while let Some(mut task) = self.task_queue.pop_front() {
             let waker = dummy_waker();
             let mut context = Context::from_waker(&waker);
+            super::reset_budget();
             match task.poll(&mut context) {
                 Poll::Ready(()) => {}
                 Poll::Pending => self.task_queue.push_back(task),
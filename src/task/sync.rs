@@ -0,0 +1,172 @@
+/*
+    Synchronization for tasks
+
+    A spin::Mutex held across an await point blocks every other task which wants it, and the executor with them.
+    These park the waiting task's Waker instead and let the executor run something else meanwhile.
+    Semaphore is the building block: Mutex and RwLock are semaphores guarding a value,
+    and like the semaphore they hand out access in arrival order.
+
+    The internal state is only locked with interrupts disabled, so interrupt handlers may release
+    permits and notify, waiting is for tasks only.
+ */
+use alloc::collections::BTreeMap;
+use core::task::Waker;
+
+mod barrier;
+mod mutex;
+mod notify;
+mod rwlock;
+mod semaphore;
+
+pub use barrier::{Barrier, BarrierWaitResult};
+pub use mutex::{Mutex, MutexGuard};
+pub use notify::{Notified, Notify};
+pub use rwlock::{RwLock, RwLockReadGuard, RwLockWriteGuard};
+pub use semaphore::{Acquire, Semaphore, SemaphorePermit};
+
+/*
+    Waiting futures in arrival order. A future gets a ticket when it first has to wait
+    and gives it back once it is done, dropping the future has to give it back too.
+    The channels use it as well.
+ */
+pub(super) struct Waiters {
+    next_ticket: u64,
+    wakers: BTreeMap<u64, Waker>,
+}
+
+impl Waiters {
+    pub(super) const fn new() -> Self {
+        Waiters { next_ticket: 0, wakers: BTreeMap::new() }
+    }
+
+    pub(super) fn register(&mut self, ticket: &mut Option<u64>, waker: &Waker) {
+        let ticket = *ticket.get_or_insert_with(|| {
+            self.next_ticket += 1;
+            self.next_ticket
+        });
+        match self.wakers.get_mut(&ticket) {
+            Some(registered) if registered.will_wake(waker) => {}
+            Some(registered) => registered.clone_from(waker),
+            None => {
+                self.wakers.insert(ticket, waker.clone());
+            }
+        }
+    }
+
+    pub(super) fn remove(&mut self, ticket: u64) {
+        self.wakers.remove(&ticket);
+    }
+
+    pub(super) fn first(&self) -> Option<u64> {
+        self.wakers.keys().next().copied()
+    }
+
+    pub(super) fn is_empty(&self) -> bool {
+        self.wakers.is_empty()
+    }
+
+    // The first waiter keeps its place, it removes itself once it got what it waited for
+    pub(super) fn wake_first(&self) {
+        if let Some(waker) = self.wakers.values().next() {
+            waker.wake_by_ref();
+        }
+    }
+
+    pub(super) fn wake_all(&mut self) {
+        for (_, waker) in core::mem::take(&mut self.wakers) {
+            waker.wake();
+        }
+    }
+
+    /*
+        Wake everybody but keep the entries, each waiter removes its own once it got what it waited for.
+        Frees nothing, unlike wake_all(), so interrupt handlers may call it.
+     */
+    pub(super) fn wake_all_by_ref(&self) {
+        for waker in self.wakers.values() {
+            waker.wake_by_ref();
+        }
+    }
+
+    // Like wake_all_by_ref(), but only the waiters which got their ticket after `ticket`
+    pub(super) fn wake_newer_by_ref(&self, ticket: u64) {
+        for waker in self.wakers.range(ticket + 1..).map(|(_, waker)| waker) {
+            waker.wake_by_ref();
+        }
+    }
+
+    // The ticket handed out last, 0 if none was
+    pub(super) fn last_ticket(&self) -> u64 {
+        self.next_ticket
+    }
+}
+
+#[test_case]
+fn test_wake_all_by_ref() {
+    use test_util::TestWaker;
+
+    let other = Waker::from(TestWaker::new());
+    let counter = TestWaker::new();
+    let counting = Waker::from(counter.clone());
+    let mut waiters = Waiters::new();
+    let (mut first, mut second) = (None, None);
+    waiters.register(&mut first, &counting);
+    waiters.register(&mut second, &other);
+
+    waiters.wake_all_by_ref();
+    assert_eq!(counter.wakeups(), 1);
+    assert_eq!(waiters.first(), first);
+    waiters.remove(first.expect("ticket"));
+    waiters.wake_all_by_ref();
+    assert_eq!(counter.wakeups(), 1);
+    assert_eq!(waiters.first(), second);
+
+    // Only the waiters registered since the last wake
+    let woken = waiters.last_ticket();
+    let mut third = None;
+    waiters.register(&mut third, &counting);
+    waiters.wake_newer_by_ref(woken);
+    assert_eq!(counter.wakeups(), 2);
+    waiters.wake_newer_by_ref(waiters.last_ticket());
+    assert_eq!(counter.wakeups(), 2);
+}
+
+#[cfg(test)]
+pub(super) mod test_util {
+    use alloc::{sync::Arc, task::Wake};
+    use core::{pin::Pin, sync::atomic::{AtomicUsize, Ordering}, task::{Context, Poll, Waker}};
+
+    // Counts its wakeups
+    pub struct TestWaker(AtomicUsize);
+
+    impl TestWaker {
+        pub fn new() -> Arc<TestWaker> {
+            Arc::new(TestWaker(AtomicUsize::new(0)))
+        }
+
+        pub fn wakeups(&self) -> usize {
+            self.0.load(Ordering::Relaxed)
+        }
+    }
+
+    impl Wake for TestWaker {
+        fn wake(self: Arc<Self>) {
+            self.wake_by_ref();
+        }
+
+        fn wake_by_ref(self: &Arc<Self>) {
+            self.0.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    pub fn poll<F: Future>(future: Pin<&mut F>, waker: &Arc<TestWaker>) -> Poll<F::Output> {
+        poll_fn(waker, |cx| future.poll(cx))
+    }
+
+    // Like an executor, with a full poll budget
+    pub fn poll_fn<R>(waker: &Arc<TestWaker>, f: impl FnOnce(&mut Context) -> Poll<R>) -> Poll<R> {
+        let waker = Waker::from(waker.clone());
+        crate::task::reset_budget();
+        f(&mut Context::from_waker(&waker))
+    }
+}
@@ -0,0 +1,90 @@
+use core::{future::poll_fn, task::Poll};
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+use super::Waiters;
+
+/*
+    Lets a fixed number of tasks wait for each other. Once the last one arrives all of them go on,
+    and the barrier can be used again for the next round.
+
+    wait() is not cancel safe: a task dropping it still counts as arrived for this round.
+ */
+pub struct Barrier {
+    count: usize,
+    state: Mutex<State>,
+}
+
+struct State {
+    arrived: usize,
+    generation: u64,
+    waiters: Waiters,
+}
+
+impl Barrier {
+    pub fn new(count: usize) -> Self {
+        Barrier { count, state: Mutex::new(State { arrived: 0, generation: 0, waiters: Waiters::new() }) }
+    }
+
+    pub async fn wait(&self) -> BarrierWaitResult {
+        let generation = interrupts::without_interrupts(|| {
+            let mut state = self.state.lock();
+            state.arrived += 1;
+            if state.arrived < self.count {
+                return Some(state.generation);
+            }
+            state.arrived = 0;
+            state.generation += 1;
+            state.waiters.wake_all();
+            None
+        });
+        let Some(generation) = generation else {
+            return BarrierWaitResult(true);
+        };
+
+        let mut ticket = None;
+        poll_fn(|cx| {
+            interrupts::without_interrupts(|| {
+                let mut state = self.state.lock();
+                if state.generation != generation {
+                    return Poll::Ready(());
+                }
+                state.waiters.register(&mut ticket, cx.waker());
+                Poll::Pending
+            })
+        })
+        .await;
+        BarrierWaitResult(false)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BarrierWaitResult(bool);
+
+impl BarrierWaitResult {
+    // Exactly one task per round is the leader, the one which arrived last
+    pub fn is_leader(&self) -> bool {
+        self.0
+    }
+}
+
+#[test_case]
+fn test_barrier() {
+    use core::pin::pin;
+    use super::test_util::{TestWaker, poll};
+
+    let barrier = Barrier::new(3);
+    let waker = TestWaker::new();
+
+    for _round in 0..2 {
+        let mut first = pin!(barrier.wait());
+        let mut second = pin!(barrier.wait());
+        assert!(poll(first.as_mut(), &waker).is_pending());
+        assert!(poll(second.as_mut(), &waker).is_pending());
+
+        assert_eq!(poll(pin!(barrier.wait()), &waker), Poll::Ready(BarrierWaitResult(true)));
+        assert_eq!(poll(first.as_mut(), &waker), Poll::Ready(BarrierWaitResult(false)));
+        assert_eq!(poll(second.as_mut(), &waker), Poll::Ready(BarrierWaitResult(false)));
+    }
+    assert_eq!(waker.wakeups(), 4);
+}
@@ -0,0 +1,92 @@
+use core::{cell::UnsafeCell, ops::{Deref, DerefMut}};
+
+use super::Semaphore;
+
+/*
+    A mutex whose guard may be held across await points, lock() waits without blocking the executor
+ */
+pub struct Mutex<T: ?Sized> {
+    semaphore: Semaphore,
+    value: UnsafeCell<T>,
+}
+
+// The semaphore hands out one guard at a time, like spin::Mutex
+unsafe impl<T: ?Sized + Send> Send for Mutex<T> {}
+unsafe impl<T: ?Sized + Send> Sync for Mutex<T> {}
+
+impl<T> Mutex<T> {
+    pub const fn new(value: T) -> Self {
+        Mutex { semaphore: Semaphore::new(1), value: UnsafeCell::new(value) }
+    }
+
+    pub fn into_inner(self) -> T {
+        self.value.into_inner()
+    }
+}
+
+impl<T: ?Sized> Mutex<T> {
+    pub async fn lock(&self) -> MutexGuard<'_, T> {
+        self.semaphore.acquire().await.forget();
+        MutexGuard { mutex: self }
+    }
+
+    pub fn try_lock(&self) -> Option<MutexGuard<'_, T>> {
+        self.semaphore.try_acquire()?.forget();
+        Some(MutexGuard { mutex: self })
+    }
+
+    // No locking needed, &mut proves nobody else has access
+    pub fn get_mut(&mut self) -> &mut T {
+        self.value.get_mut()
+    }
+}
+
+pub struct MutexGuard<'a, T: ?Sized> {
+    mutex: &'a Mutex<T>,
+}
+
+// Sharing the guard shares &T
+unsafe impl<T: ?Sized + Sync> Sync for MutexGuard<'_, T> {}
+
+impl<T: ?Sized> Deref for MutexGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.mutex.value.get() }
+    }
+}
+
+impl<T: ?Sized> DerefMut for MutexGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.mutex.value.get() }
+    }
+}
+
+impl<T: ?Sized> Drop for MutexGuard<'_, T> {
+    fn drop(&mut self) {
+        self.mutex.semaphore.add_permits(1);
+    }
+}
+
+#[test_case]
+fn test_mutex() {
+    use core::pin::pin;
+    use super::test_util::{TestWaker, poll};
+
+    let mutex = Mutex::new(0);
+    let waker = TestWaker::new();
+
+    let mut guard = mutex.try_lock().expect("mutex free");
+    *guard += 1;
+    assert!(mutex.try_lock().is_none());
+
+    let mut waiting = pin!(mutex.lock());
+    assert!(poll(waiting.as_mut(), &waker).is_pending());
+    drop(guard);
+    assert_eq!(waker.wakeups(), 1);
+    match poll(waiting.as_mut(), &waker) {
+        core::task::Poll::Ready(mut guard) => *guard += 1,
+        core::task::Poll::Pending => panic!("lock not handed over"),
+    }
+    assert_eq!(*mutex.try_lock().expect("guard dropped"), 2);
+}
@@ -0,0 +1,174 @@
+use alloc::collections::BTreeMap;
+use core::{pin::Pin, task::{Context, Poll, Waker}};
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+/*
+    Wakes waiting tasks without passing data.
+    notify_one() wakes the longest waiting task, or lets the next notified() return right away if none waits.
+    notify_waiters() wakes everybody waiting now and leaves nothing behind.
+ */
+pub struct Notify {
+    state: Mutex<State>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Notification {
+    One,
+    All,
+}
+
+struct Waiter {
+    waker: Option<Waker>,
+    notification: Option<Notification>,
+}
+
+struct State {
+    permit: bool, // a notify_one() nobody waited for
+    next_ticket: u64,
+    waiters: BTreeMap<u64, Waiter>,
+}
+
+impl Notify {
+    pub const fn new() -> Self {
+        Notify { state: Mutex::new(State { permit: false, next_ticket: 0, waiters: BTreeMap::new() }) }
+    }
+
+    pub fn notified(&self) -> Notified<'_> {
+        Notified { notify: self, ticket: None }
+    }
+
+    pub fn notify_one(&self) {
+        interrupts::without_interrupts(|| self.state.lock().notify_one());
+    }
+
+    pub fn notify_waiters(&self) {
+        interrupts::without_interrupts(|| {
+            let mut state = self.state.lock();
+            for waiter in state.waiters.values_mut().filter(|waiter| waiter.notification.is_none()) {
+                waiter.notification = Some(Notification::All);
+                if let Some(waker) = waiter.waker.take() {
+                    waker.wake();
+                }
+            }
+        });
+    }
+}
+
+impl Default for Notify {
+    fn default() -> Self {
+        Notify::new()
+    }
+}
+
+impl State {
+    fn notify_one(&mut self) {
+        let waiter = self.waiters.values_mut().find(|waiter| waiter.notification.is_none());
+        match waiter {
+            Some(waiter) => {
+                waiter.notification = Some(Notification::One);
+                if let Some(waker) = waiter.waker.take() {
+                    waker.wake();
+                }
+            }
+            None => self.permit = true,
+        }
+    }
+}
+
+pub struct Notified<'a> {
+    notify: &'a Notify,
+    ticket: Option<u64>,
+}
+
+impl Future for Notified<'_> {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        let this = self.get_mut();
+        interrupts::without_interrupts(|| {
+            let mut state = this.notify.state.lock();
+            let ticket = match this.ticket {
+                Some(ticket) => ticket,
+                None if state.permit => {
+                    state.permit = false;
+                    return Poll::Ready(());
+                }
+                None => {
+                    state.next_ticket += 1;
+                    let ticket = state.next_ticket;
+                    state.waiters.insert(ticket, Waiter { waker: None, notification: None });
+                    this.ticket = Some(ticket);
+                    ticket
+                }
+            };
+
+            let waiter = state.waiters.get_mut(&ticket).expect("waiter removed");
+            if waiter.notification.is_some() {
+                state.waiters.remove(&ticket);
+                this.ticket = None;
+                return Poll::Ready(());
+            }
+            waiter.waker = Some(cx.waker().clone());
+            Poll::Pending
+        })
+    }
+}
+
+impl Drop for Notified<'_> {
+    fn drop(&mut self) {
+        let Some(ticket) = self.ticket else {
+            return;
+        };
+        interrupts::without_interrupts(|| {
+            let mut state = self.notify.state.lock();
+            // A notify_one() meant for us must not get lost
+            if let Some(Waiter { notification: Some(Notification::One), .. }) = state.waiters.remove(&ticket) {
+                state.notify_one();
+            }
+        });
+    }
+}
+
+#[test_case]
+fn test_notify() {
+    use core::pin::pin;
+    use super::test_util::{TestWaker, poll};
+
+    let notify = Notify::new();
+    let waker = TestWaker::new();
+
+    // A notification without waiters is kept for the next one
+    notify.notify_one();
+    assert!(poll(pin!(notify.notified()), &waker).is_ready());
+
+    let mut first = pin!(notify.notified());
+    let mut second = pin!(notify.notified());
+    assert!(poll(first.as_mut(), &waker).is_pending());
+    assert!(poll(second.as_mut(), &waker).is_pending());
+    notify.notify_one();
+    assert!(poll(second.as_mut(), &waker).is_pending());
+    assert!(poll(first.as_mut(), &waker).is_ready());
+
+    notify.notify_waiters();
+    assert!(poll(second.as_mut(), &waker).is_ready());
+    assert!(poll(pin!(notify.notified()), &waker).is_pending());
+}
+
+#[test_case]
+fn test_notify_cancel() {
+    use core::pin::pin;
+    use super::test_util::{TestWaker, poll};
+
+    let notify = Notify::new();
+    let waker = TestWaker::new();
+    let mut second = pin!(notify.notified());
+    {
+        let mut first = pin!(notify.notified());
+        assert!(poll(first.as_mut(), &waker).is_pending());
+        assert!(poll(second.as_mut(), &waker).is_pending());
+        notify.notify_one();
+    }
+    // The notification went on to the next waiter
+    assert!(poll(second.as_mut(), &waker).is_ready());
+}
@@ -0,0 +1,124 @@
+use core::{cell::UnsafeCell, ops::{Deref, DerefMut}};
+
+use super::Semaphore;
+
+// A reader takes one permit, a writer all of them
+const MAX_READERS: usize = u32::MAX as usize;
+
+/*
+    Many readers or one writer. Waiting is in arrival order,
+    so a writer waits for the readers before it but new readers queue up behind it.
+ */
+pub struct RwLock<T: ?Sized> {
+    semaphore: Semaphore,
+    value: UnsafeCell<T>,
+}
+
+unsafe impl<T: ?Sized + Send> Send for RwLock<T> {}
+unsafe impl<T: ?Sized + Send + Sync> Sync for RwLock<T> {}
+
+impl<T> RwLock<T> {
+    pub const fn new(value: T) -> Self {
+        RwLock { semaphore: Semaphore::new(MAX_READERS), value: UnsafeCell::new(value) }
+    }
+
+    pub fn into_inner(self) -> T {
+        self.value.into_inner()
+    }
+}
+
+impl<T: ?Sized> RwLock<T> {
+    pub async fn read(&self) -> RwLockReadGuard<'_, T> {
+        self.semaphore.acquire().await.forget();
+        RwLockReadGuard { lock: self }
+    }
+
+    pub async fn write(&self) -> RwLockWriteGuard<'_, T> {
+        self.semaphore.acquire_many(MAX_READERS).await.forget();
+        RwLockWriteGuard { lock: self }
+    }
+
+    pub fn try_read(&self) -> Option<RwLockReadGuard<'_, T>> {
+        self.semaphore.try_acquire()?.forget();
+        Some(RwLockReadGuard { lock: self })
+    }
+
+    pub fn try_write(&self) -> Option<RwLockWriteGuard<'_, T>> {
+        self.semaphore.try_acquire_many(MAX_READERS)?.forget();
+        Some(RwLockWriteGuard { lock: self })
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        self.value.get_mut()
+    }
+}
+
+pub struct RwLockReadGuard<'a, T: ?Sized> {
+    lock: &'a RwLock<T>,
+}
+
+impl<T: ?Sized> Deref for RwLockReadGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.value.get() }
+    }
+}
+
+impl<T: ?Sized> Drop for RwLockReadGuard<'_, T> {
+    fn drop(&mut self) {
+        self.lock.semaphore.add_permits(1);
+    }
+}
+
+pub struct RwLockWriteGuard<'a, T: ?Sized> {
+    lock: &'a RwLock<T>,
+}
+
+impl<T: ?Sized> Deref for RwLockWriteGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.value.get() }
+    }
+}
+
+impl<T: ?Sized> DerefMut for RwLockWriteGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.lock.value.get() }
+    }
+}
+
+impl<T: ?Sized> Drop for RwLockWriteGuard<'_, T> {
+    fn drop(&mut self) {
+        self.lock.semaphore.add_permits(MAX_READERS);
+    }
+}
+
+#[test_case]
+fn test_rwlock() {
+    use core::pin::pin;
+    use super::test_util::{TestWaker, poll};
+
+    let lock = RwLock::new(1);
+    let waker = TestWaker::new();
+
+    let first = lock.try_read().expect("no writer");
+    let second = lock.try_read().expect("readers share");
+    assert_eq!(*first + *second, 2);
+    assert!(lock.try_write().is_none());
+
+    // A waiting writer keeps new readers out
+    let mut writer = pin!(lock.write());
+    assert!(poll(writer.as_mut(), &waker).is_pending());
+    assert!(lock.try_read().is_none());
+
+    drop(first);
+    assert!(poll(writer.as_mut(), &waker).is_pending());
+    drop(second);
+    match poll(writer.as_mut(), &waker) {
+        core::task::Poll::Ready(mut guard) => *guard = 5,
+        core::task::Poll::Pending => panic!("writer not served"),
+    }
+    assert_eq!(*lock.try_read().expect("writer gone"), 5);
+}
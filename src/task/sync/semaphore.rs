@@ -0,0 +1,182 @@
+use core::{pin::Pin, task::{Context, Poll}};
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+use super::Waiters;
+
+/*
+    Counts permits, acquire() waits until enough are available.
+    Waiting tasks are served in arrival order: a big request is not overtaken by small ones.
+ */
+pub struct Semaphore {
+    state: Mutex<State>,
+}
+
+struct State {
+    permits: usize,
+    waiters: Waiters,
+}
+
+impl Semaphore {
+    pub const fn new(permits: usize) -> Self {
+        Semaphore { state: Mutex::new(State { permits, waiters: Waiters::new() }) }
+    }
+
+    pub fn available_permits(&self) -> usize {
+        interrupts::without_interrupts(|| self.state.lock().permits)
+    }
+
+    // Also what a dropped permit does
+    pub fn add_permits(&self, permits: usize) {
+        interrupts::without_interrupts(|| {
+            let mut state = self.state.lock();
+            state.permits += permits;
+            state.waiters.wake_first();
+        });
+    }
+
+    pub fn acquire(&self) -> Acquire<'_> {
+        self.acquire_many(1)
+    }
+
+    // Waits forever if more permits are asked for than there will ever be
+    pub fn acquire_many(&self, permits: usize) -> Acquire<'_> {
+        Acquire { semaphore: self, permits, ticket: None }
+    }
+
+    pub fn try_acquire(&self) -> Option<SemaphorePermit<'_>> {
+        self.try_acquire_many(1)
+    }
+
+    // Fails while tasks are waiting, they came first
+    pub fn try_acquire_many(&self, permits: usize) -> Option<SemaphorePermit<'_>> {
+        interrupts::without_interrupts(|| {
+            let mut state = self.state.lock();
+            if !state.waiters.is_empty() || state.permits < permits {
+                return None;
+            }
+            state.permits -= permits;
+            Some(SemaphorePermit { semaphore: self, permits })
+        })
+    }
+}
+
+pub struct Acquire<'a> {
+    semaphore: &'a Semaphore,
+    permits: usize,
+    ticket: Option<u64>, // our place in the queue once we had to wait
+}
+
+impl<'a> Future for Acquire<'a> {
+    type Output = SemaphorePermit<'a>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<SemaphorePermit<'a>> {
+        let this = self.get_mut();
+        interrupts::without_interrupts(|| {
+            let mut state = this.semaphore.state.lock();
+            let first = match this.ticket {
+                Some(ticket) => state.waiters.first() == Some(ticket),
+                None => state.waiters.is_empty(),
+            };
+            if !first || state.permits < this.permits {
+                state.waiters.register(&mut this.ticket, cx.waker());
+                return Poll::Pending;
+            }
+
+            state.permits -= this.permits;
+            if let Some(ticket) = this.ticket.take() {
+                state.waiters.remove(ticket);
+            }
+            // What is left may be enough for the next one
+            if state.permits > 0 {
+                state.waiters.wake_first();
+            }
+            Poll::Ready(SemaphorePermit { semaphore: this.semaphore, permits: this.permits })
+        })
+    }
+}
+
+impl Drop for Acquire<'_> {
+    fn drop(&mut self) {
+        let Some(ticket) = self.ticket else {
+            return;
+        };
+        interrupts::without_interrupts(|| {
+            let mut state = self.semaphore.state.lock();
+            let first = state.waiters.first() == Some(ticket);
+            state.waiters.remove(ticket);
+            // We might have been woken for permits we no longer take
+            if first && state.permits > 0 {
+                state.waiters.wake_first();
+            }
+        });
+    }
+}
+
+// Gives the permits back when dropped
+pub struct SemaphorePermit<'a> {
+    semaphore: &'a Semaphore,
+    permits: usize,
+}
+
+impl SemaphorePermit<'_> {
+    // Keep the permits taken, add_permits() can return them later
+    pub fn forget(mut self) {
+        self.permits = 0;
+    }
+}
+
+impl Drop for SemaphorePermit<'_> {
+    fn drop(&mut self) {
+        if self.permits > 0 {
+            self.semaphore.add_permits(self.permits);
+        }
+    }
+}
+
+#[test_case]
+fn test_semaphore_order() {
+    use core::pin::pin;
+    use super::test_util::{TestWaker, poll};
+
+    let semaphore = Semaphore::new(2);
+    let waker = TestWaker::new();
+    let permit = semaphore.try_acquire().expect("permit available");
+
+    // The big request waits, and the small one behind it must not overtake it
+    let mut big = pin!(semaphore.acquire_many(2));
+    assert!(poll(big.as_mut(), &waker).is_pending());
+    let mut small = pin!(semaphore.acquire());
+    assert!(poll(small.as_mut(), &waker).is_pending());
+    assert!(semaphore.try_acquire().is_none());
+
+    drop(permit);
+    assert_eq!(waker.wakeups(), 1);
+    let big_permit = match poll(big.as_mut(), &waker) {
+        Poll::Ready(permit) => permit,
+        Poll::Pending => panic!("first waiter not served"),
+    };
+    assert!(poll(small.as_mut(), &waker).is_pending());
+    drop(big_permit);
+    assert!(poll(small.as_mut(), &waker).is_ready());
+    assert_eq!(semaphore.available_permits(), 2);
+}
+
+#[test_case]
+fn test_semaphore_cancel() {
+    use core::pin::pin;
+    use super::test_util::{TestWaker, poll};
+
+    let semaphore = Semaphore::new(0);
+    let waker = TestWaker::new();
+    let mut second = pin!(semaphore.acquire());
+    {
+        let mut first = pin!(semaphore.acquire());
+        assert!(poll(first.as_mut(), &waker).is_pending());
+        assert!(poll(second.as_mut(), &waker).is_pending());
+        semaphore.add_permits(1);
+        // first is dropped without taking the permit
+    }
+    assert_eq!(waker.wakeups(), 2);
+    assert!(poll(second.as_mut(), &waker).is_ready());
+}
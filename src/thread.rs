@@ -0,0 +1,536 @@
+/*
+    Preemptive kernel threads
+
+    A thread runs until it blocks, yields or the next timer tick (about 55 ms, see interrupts),
+    then the next ready thread gets its turn, round robin. init() turns the code that booted into the first thread,
+    and adds an idle thread which halts the CPU while no other thread is ready.
+
+    Every other thread has its own stack with a guard page, see stack. Switching threads saves the callee saved
+    registers on the old stack and restores them from the new one, the other registers are saved by the caller:
+    the compiler around a call, the x86-interrupt ABI in the timer handler. There is no FPU state to save,
+    the kernel is built with soft floats. The task poll budget is saved and restored too, see task::consume_budget.
+
+    The scheduler only runs with interrupts disabled and does not allocate on the timer path,
+    the interrupted thread might hold the heap lock. A panicking thread halts the kernel, like a panicking task.
+ */
+use alloc::{boxed::Box, collections::{BTreeMap, VecDeque}, string::String, sync::Arc, vec::Vec};
+use core::{fmt, sync::atomic::{AtomicU64, Ordering}, time::Duration};
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+use stack::{STACK_SLOTS, Stack, StackError};
+
+pub mod stack;
+pub mod sync;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct ThreadId(u64);
+
+impl ThreadId {
+    fn new() -> Self {
+        static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+        ThreadId(NEXT_ID.fetch_add(1, Ordering::Relaxed))
+    }
+
+    pub fn as_u64(self) -> u64 {
+        self.0
+    }
+}
+
+impl fmt::Display for ThreadId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ThreadState {
+    Running,
+    Ready,
+    Sleeping, // until a timer tick
+    Blocked,  // until another thread wakes it
+    Finished, // until joined
+}
+
+#[derive(Debug)]
+pub enum SpawnError {
+    NotInitialized,
+    Stack(StackError),
+}
+
+struct Thread {
+    id: ThreadId,
+    name: String,
+    state: ThreadState,
+    rsp: u64, // saved stack pointer while it is not running
+    wake_at: u64, // timer tick to wake up at while sleeping
+    stack: Option<Stack>, // None for the boot thread, it keeps the bootloader's stack
+    entry: Option<Box<dyn FnOnce() + Send>>,
+    joiner: Option<ThreadId>, // blocked in join() on this thread
+    detached: bool, // nobody will join it
+    poll_budget: u32, // its task poll budget while it is not running, see task::consume_budget
+}
+
+impl Thread {
+    fn new(name: String, stack: Option<Stack>, entry: Option<Box<dyn FnOnce() + Send>>) -> Thread {
+        Thread {
+            id: ThreadId::new(),
+            name,
+            state: ThreadState::Ready,
+            rsp: 0,
+            wake_at: 0,
+            stack,
+            entry,
+            joiner: None,
+            detached: false,
+            poll_budget: crate::task::POLL_BUDGET,
+        }
+    }
+}
+
+struct Scheduler {
+    threads: BTreeMap<ThreadId, Thread>,
+    ready: VecDeque<ThreadId>, // never grows past its initial capacity, it holds each thread once at most
+    current: ThreadId,
+    idle: ThreadId, // runs only when no other thread is ready
+}
+
+static SCHEDULER: Mutex<Option<Scheduler>> = Mutex::new(None);
+
+impl Scheduler {
+    fn thread(&mut self, id: ThreadId) -> &mut Thread {
+        self.threads.get_mut(&id).expect("unknown thread")
+    }
+
+    // Threads which are not blocked, or gone already, are left alone
+    fn unblock(&mut self, id: ThreadId) {
+        if let Some(thread) = self.threads.get_mut(&id) && thread.state == ThreadState::Blocked {
+            thread.state = ThreadState::Ready;
+            self.ready.push_back(id);
+        }
+    }
+
+    /*
+        Pick the next thread and mark the current one `state`, Running means it is preempted and stays ready.
+        Returns where to save the current stack pointer and the one to switch to, None to keep running.
+     */
+    fn switch(&mut self, state: ThreadState) -> Option<(*mut u64, u64)> {
+        let current = self.current;
+        let next = match self.ready.pop_front() {
+            Some(next) => next,
+            None if state == ThreadState::Running => return None,
+            None => self.idle,
+        };
+        if next == current {
+            return None;
+        }
+
+        let idle = self.idle;
+        let old = self.thread(current);
+        old.state = match state {
+            ThreadState::Running => ThreadState::Ready,
+            state => state,
+        };
+        let requeue = old.state == ThreadState::Ready && current != idle;
+        old.poll_budget = crate::task::budget();
+        let old_rsp = &raw mut old.rsp;
+        if requeue {
+            self.ready.push_back(current);
+        }
+
+        let new = self.thread(next);
+        new.state = ThreadState::Running;
+        crate::task::set_budget(new.poll_budget);
+        let new_rsp = new.rsp;
+        self.current = next;
+        Some((old_rsp, new_rsp))
+    }
+}
+
+/*
+    Let the next ready thread run and make the current one `state`.
+    Interrupts must be disabled, they still are when the current thread runs again.
+ */
+fn reschedule(state: ThreadState) {
+    let mut scheduler = SCHEDULER.lock();
+    let Some((old_rsp, new_rsp)) = scheduler.as_mut().and_then(|scheduler| scheduler.switch(state)) else {
+        return;
+    };
+    // The lock must not be held by a thread which is switched out.
+    // With interrupts disabled nothing changes the thread table before old_rsp is written.
+    drop(scheduler);
+    unsafe { switch_stacks(old_rsp, new_rsp) };
+}
+
+/*
+    Save the callee saved registers and the stack pointer of the current thread and continue the other one
+    where it left off: in its own call of switch_stacks, or in thread_start for a new thread.
+ */
+#[unsafe(naked)]
+unsafe extern "C" fn switch_stacks(old_rsp: *mut u64, new_rsp: u64) {
+    core::arch::naked_asm!(
+        "push rbp",
+        "push rbx",
+        "push r12",
+        "push r13",
+        "push r14",
+        "push r15",
+        "mov [rdi], rsp",
+        "mov rsp, rsi",
+        "pop r15",
+        "pop r14",
+        "pop r13",
+        "pop r12",
+        "pop rbx",
+        "pop rbp",
+        "ret",
+    )
+}
+
+// What switch_stacks finds on the stack of a new thread
+fn initial_stack(stack: &Stack) -> u64 {
+    let top = stack.top().as_u64();
+    // Six registers to pop, the address to return to and, as if thread_start had been called, a return address.
+    // That leaves the stack 8 bytes off 16 byte alignment at the function entry, as the ABI expects.
+    let rsp = top - 8 * 8;
+    let frame = rsp as *mut u64;
+    unsafe {
+        for register in 0..6 {
+            frame.add(register).write(0);
+        }
+        frame.add(6).write(thread_start as *const () as u64);
+        frame.add(7).write(0); // thread_start never returns
+    }
+    rsp
+}
+
+// Where a new thread starts, switched to with interrupts disabled
+extern "C" fn thread_start() -> ! {
+    let entry = with_current(|thread| thread.entry.take()).flatten().expect("thread without entry");
+    interrupts::enable();
+    entry();
+    exit();
+}
+
+fn with_current<R>(f: impl FnOnce(&mut Thread) -> R) -> Option<R> {
+    interrupts::without_interrupts(|| {
+        let mut scheduler = SCHEDULER.lock();
+        let scheduler = scheduler.as_mut()?;
+        let current = scheduler.current;
+        Some(f(scheduler.thread(current)))
+    })
+}
+
+/*
+    Make the running code the first thread and start the idle thread.
+    The heap and memory::install() are needed for the stacks, the scheduler runs from the next timer tick on.
+ */
+pub fn init() -> Result<(), SpawnError> {
+    let idle_entry: Box<dyn FnOnce() + Send> = Box::new(|| loop {
+        x86_64::instructions::hlt();
+    });
+    let mut idle = Thread::new(String::from("idle"), Some(Stack::allocate().map_err(SpawnError::Stack)?), Some(idle_entry));
+    let mut boot = Thread::new(String::from("boot"), None, None);
+    boot.state = ThreadState::Running;
+    boot.detached = true;
+
+    let mut scheduler = Scheduler {
+        threads: BTreeMap::new(),
+        ready: VecDeque::with_capacity(STACK_SLOTS + 1),
+        current: boot.id,
+        idle: idle.id,
+    };
+    idle.rsp = initial_stack(idle.stack.as_ref().expect("idle stack"));
+    scheduler.threads.insert(boot.id, boot);
+    scheduler.threads.insert(idle.id, idle);
+    interrupts::without_interrupts(|| *SCHEDULER.lock() = Some(scheduler));
+    Ok(())
+}
+
+// Called by the timer interrupt handler after the EOI
+pub(crate) fn tick(now: u64) {
+    {
+        let mut scheduler = SCHEDULER.lock();
+        let Some(Scheduler { threads, ready, .. }) = scheduler.as_mut() else {
+            return;
+        };
+        for thread in threads.values_mut() {
+            if thread.state == ThreadState::Sleeping && thread.wake_at <= now {
+                thread.state = ThreadState::Ready;
+                ready.push_back(thread.id);
+            }
+        }
+    }
+    reschedule(ThreadState::Running);
+}
+
+/*
+    Start `f` in a new thread, it is ready to run at once.
+    The thread is not stopped by dropping the handle, it just cannot be joined anymore.
+ */
+pub fn spawn<F, T>(name: impl Into<String>, f: F) -> Result<JoinHandle<T>, SpawnError>
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+{
+    let stack = Stack::allocate().map_err(SpawnError::Stack)?;
+    let result = Arc::new(Mutex::new(None));
+    let entry: Box<dyn FnOnce() + Send> = {
+        let result = result.clone();
+        Box::new(move || {
+            let value = f();
+            interrupts::without_interrupts(|| *result.lock() = Some(value));
+        })
+    };
+    let mut thread = Thread::new(name.into(), Some(stack), Some(entry));
+    thread.rsp = initial_stack(thread.stack.as_ref().expect("new thread stack"));
+    let id = thread.id;
+
+    let reaped = interrupts::without_interrupts(|| {
+        let mut scheduler = SCHEDULER.lock();
+        let scheduler = scheduler.as_mut().ok_or(SpawnError::NotInitialized)?;
+        let reaped = reap_detached(scheduler);
+        scheduler.threads.insert(id, thread);
+        scheduler.ready.push_back(id);
+        Ok(reaped)
+    })?;
+    // Freed without the scheduler lock held
+    drop(reaped);
+    Ok(JoinHandle { id, result })
+}
+
+// Finished threads nobody will join
+fn reap_detached(scheduler: &mut Scheduler) -> Vec<Thread> {
+    let finished: Vec<ThreadId> = scheduler.threads.values()
+        .filter(|thread| thread.detached && thread.state == ThreadState::Finished)
+        .map(|thread| thread.id)
+        .collect();
+    finished.iter().filter_map(|id| scheduler.threads.remove(id)).collect()
+}
+
+/*
+    Let the other ready threads run first
+ */
+pub fn yield_now() {
+    might_sleep();
+    interrupts::without_interrupts(|| reschedule(ThreadState::Running));
+}
+
+/*
+    Block the current thread for at least `duration`, rounded up to timer ticks.
+    Before init() this halts until the time has passed.
+ */
+pub fn sleep(duration: Duration) {
+    might_sleep();
+    let wake_at = crate::interrupts::ticks() + crate::interrupts::duration_to_ticks(duration);
+    let scheduled = interrupts::without_interrupts(|| {
+        if with_current(|thread| thread.wake_at = wake_at).is_none() {
+            return false;
+        }
+        reschedule(ThreadState::Sleeping);
+        true
+    });
+    if !scheduled {
+        while crate::interrupts::ticks() < wake_at {
+            x86_64::instructions::hlt();
+        }
+    }
+}
+
+/*
+    Block the current thread until unblock() is called for it.
+    Interrupts must be disabled from before the thread lets the one which will unblock it know,
+    otherwise the wakeup might come first and get lost. See sync::WaitQueue, which does that.
+ */
+pub fn block() {
+    assert!(!interrupts::are_enabled(), "block() with interrupts enabled");
+    might_sleep();
+    assert!(SCHEDULER.lock().is_some(), "block() before thread::init()");
+    reschedule(ThreadState::Blocked);
+}
+
+/*
+    Make a blocked thread ready to run again, other threads are left alone. Interrupt handlers may call it.
+ */
+pub fn unblock(id: ThreadId) {
+    interrupts::without_interrupts(|| {
+        if let Some(scheduler) = SCHEDULER.lock().as_mut() {
+            scheduler.unblock(id);
+        }
+    });
+}
+
+/*
+    In debug builds, panic if the current thread holds a sync::SpinLock: nobody else could take it
+    until the thread runs again. Everything which might block calls it, whether it blocks or not.
+ */
+#[track_caller]
+pub fn might_sleep() {
+    #[cfg(debug_assertions)]
+    {
+        let held = sync::held_spin_locks();
+        assert!(held == 0, "might sleep while holding {} spin lock(s)", held);
+    }
+}
+
+/*
+    End the current thread, a thread also ends by returning from its function
+ */
+pub fn exit() -> ! {
+    interrupts::disable();
+    {
+        let mut scheduler = SCHEDULER.lock();
+        let scheduler = scheduler.as_mut().expect("exit() before thread::init()");
+        let current = scheduler.current;
+        if let Some(joiner) = scheduler.thread(current).joiner.take() {
+            scheduler.unblock(joiner);
+        }
+    }
+    reschedule(ThreadState::Finished);
+    unreachable!("a finished thread was scheduled");
+}
+
+/*
+    Wait while the executor has nothing to do: let the ready threads run, or halt until the next interrupt.
+    Like enable_and_hlt, it is called with interrupts disabled and returns with them enabled.
+ */
+pub fn idle() {
+    let others_ready = SCHEDULER.lock().as_ref().is_some_and(|scheduler| !scheduler.ready.is_empty());
+    if others_ready {
+        reschedule(ThreadState::Running);
+        interrupts::enable();
+    } else {
+        interrupts::enable_and_hlt();
+    }
+}
+
+pub fn current() -> Option<ThreadId> {
+    with_current(|thread| thread.id)
+}
+
+pub struct JoinHandle<T> {
+    id: ThreadId,
+    result: Arc<Mutex<Option<T>>>,
+}
+
+impl<T> JoinHandle<T> {
+    pub fn id(&self) -> ThreadId {
+        self.id
+    }
+
+    pub fn is_finished(&self) -> bool {
+        interrupts::without_interrupts(|| {
+            let scheduler = SCHEDULER.lock();
+            let thread = scheduler.as_ref().and_then(|scheduler| scheduler.threads.get(&self.id));
+            thread.is_none_or(|thread| thread.state == ThreadState::Finished)
+        })
+    }
+
+    // Block until the thread finished and return what its function returned
+    pub fn join(self) -> T {
+        might_sleep();
+        let finished = interrupts::without_interrupts(|| loop {
+            {
+                let mut scheduler = SCHEDULER.lock();
+                let scheduler = scheduler.as_mut().expect("threads not initialized");
+                assert_ne!(scheduler.current, self.id, "a thread cannot join itself");
+                let current = scheduler.current;
+                let thread = scheduler.thread(self.id);
+                if thread.state == ThreadState::Finished {
+                    break scheduler.threads.remove(&self.id);
+                }
+                thread.joiner = Some(current);
+            }
+            reschedule(ThreadState::Blocked);
+        });
+        // Freed without the scheduler lock held
+        drop(finished);
+        interrupts::without_interrupts(|| self.result.lock().take()).expect("thread finished without a result")
+    }
+}
+
+impl<T> Drop for JoinHandle<T> {
+    fn drop(&mut self) {
+        // Nothing to do after join(), the thread is gone
+        interrupts::without_interrupts(|| {
+            if let Some(thread) = SCHEDULER.lock().as_mut().and_then(|scheduler| scheduler.threads.get_mut(&self.id)) {
+                thread.detached = true;
+            }
+        });
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ThreadSnapshot {
+    pub id: ThreadId,
+    pub name: String,
+    pub state: ThreadState,
+}
+
+/*
+    The threads which were not joined yet, ordered by id
+ */
+pub fn threads() -> Vec<ThreadSnapshot> {
+    interrupts::without_interrupts(|| {
+        let scheduler = SCHEDULER.lock();
+        let Some(scheduler) = scheduler.as_ref() else {
+            return Vec::new();
+        };
+        scheduler.threads.values()
+            .map(|thread| ThreadSnapshot { id: thread.id, name: thread.name.clone(), state: thread.state })
+            .collect()
+    })
+}
+
+#[test_case]
+fn test_spawn_join() {
+    let handle = spawn("adder", || (1..=10).sum::<u32>()).expect("spawn");
+    let id = handle.id();
+    assert!(threads().iter().any(|thread| thread.id == id));
+    assert_eq!(handle.join(), 55);
+    assert!(!threads().iter().any(|thread| thread.id == id));
+}
+
+#[test_case]
+fn test_yield() {
+    // Each thread yields after every step, so the other one takes the next step
+    static STEPS: Mutex<Vec<usize>> = Mutex::new(Vec::new());
+    let handles: Vec<_> = (0..2).map(|index| {
+        spawn("yielder", move || {
+            for _ in 0..3 {
+                interrupts::without_interrupts(|| STEPS.lock().push(index));
+                yield_now();
+            }
+        }).expect("spawn")
+    }).collect();
+    for handle in handles {
+        handle.join();
+    }
+    let steps = interrupts::without_interrupts(|| core::mem::take(&mut *STEPS.lock()));
+    assert_eq!(steps.len(), 6);
+    assert_ne!(steps[0], steps[1]);
+}
+
+#[test_case]
+fn test_preemption_and_sleep() {
+    use core::sync::atomic::AtomicBool;
+
+    // Only a timer tick gets the spinning thread off the CPU
+    static SPINNING: AtomicBool = AtomicBool::new(false);
+    static STOP: AtomicBool = AtomicBool::new(false);
+    let spinner = spawn("spinner", || {
+        SPINNING.store(true, Ordering::Relaxed);
+        while !STOP.load(Ordering::Relaxed) {
+            core::hint::spin_loop();
+        }
+    }).expect("spawn");
+    while !SPINNING.load(Ordering::Relaxed) {
+        core::hint::spin_loop();
+    }
+
+    let start = crate::interrupts::ticks();
+    sleep(Duration::from_millis(100));
+    assert!(crate::interrupts::ticks() >= start + crate::interrupts::duration_to_ticks(Duration::from_millis(100)));
+    STOP.store(true, Ordering::Relaxed);
+    spinner.join();
+}
@@ -0,0 +1,130 @@
+/*
+    Thread stacks, in slots of a fixed virtual memory area
+
+    Every slot holds a stack and, below it, a guard page which is never mapped:
+    running off the end of a stack page faults there instead of overwriting the slot below.
+    A frame larger than the guard page could skip it, the target spec has stack probes touch every page of a frame.
+    The frame allocator cannot take frames back, so a slot keeps its pages once mapped and the next thread reuses them.
+ */
+use x86_64::{
+    VirtAddr,
+    structures::paging::{FrameAllocator, Mapper, Page, PageTableFlags, Size4KiB, mapper::MapToError},
+};
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+pub const STACKS_START: u64 = 0x_5555_5555_0000;
+pub const STACK_PAGES: u64 = 8; // 32 KiB, debug builds format on the stack generously
+pub const STACK_SLOTS: usize = 32;
+
+const PAGE_SIZE: u64 = 4096;
+const SLOT_SIZE: u64 = (STACK_PAGES + 1) * PAGE_SIZE;
+
+#[derive(Debug)]
+pub enum StackError {
+    NoSlot,
+    NoMemory, // memory::install() was not called
+    Map(MapToError<Size4KiB>),
+}
+
+struct Slots {
+    mapped: [bool; STACK_SLOTS],
+    in_use: [bool; STACK_SLOTS],
+}
+
+static SLOTS: Mutex<Slots> = Mutex::new(Slots { mapped: [false; STACK_SLOTS], in_use: [false; STACK_SLOTS] });
+
+/*
+    A stack slot, free again when dropped
+ */
+#[derive(Debug)]
+pub struct Stack {
+    slot: usize,
+}
+
+impl Stack {
+    pub fn allocate() -> Result<Stack, StackError> {
+        let (slot, mapped) = interrupts::without_interrupts(|| {
+            let mut slots = SLOTS.lock();
+            let slot = slots.in_use.iter().position(|in_use| !in_use).ok_or(StackError::NoSlot)?;
+            slots.in_use[slot] = true;
+            Ok((slot, slots.mapped[slot]))
+        })?;
+        let stack = Stack { slot };
+        if !mapped {
+            // On error the slot is given back by dropping the stack
+            stack.map()?;
+            interrupts::without_interrupts(|| SLOTS.lock().mapped[slot] = true);
+        }
+        Ok(stack)
+    }
+
+    fn map(&self) -> Result<(), StackError> {
+        crate::memory::with_mapper(|mapper, frame_allocator| {
+            let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+            let first: Page<Size4KiB> = Page::containing_address(self.bottom());
+            for page in Page::range(first, first + STACK_PAGES) {
+                // A slot stays mapped once mapped, a page already there comes from an earlier attempt
+                if mapper.translate_page(page).is_ok() {
+                    continue;
+                }
+                let frame = frame_allocator.allocate_frame().ok_or(StackError::Map(MapToError::FrameAllocationFailed))?;
+                unsafe {
+                    mapper.map_to(page, frame, flags, frame_allocator).map_err(StackError::Map)?.flush();
+                }
+            }
+            Ok(())
+        })
+        .unwrap_or(Err(StackError::NoMemory))
+    }
+
+    pub fn slot(&self) -> usize {
+        self.slot
+    }
+
+    // Lowest mapped address, the guard page is right below
+    pub fn bottom(&self) -> VirtAddr {
+        VirtAddr::new(STACKS_START + self.slot as u64 * SLOT_SIZE + PAGE_SIZE)
+    }
+
+    // Stacks grow down from here, 16 byte aligned
+    pub fn top(&self) -> VirtAddr {
+        self.bottom() + STACK_PAGES * PAGE_SIZE
+    }
+}
+
+impl Drop for Stack {
+    fn drop(&mut self) {
+        interrupts::without_interrupts(|| SLOTS.lock().in_use[self.slot] = false);
+    }
+}
+
+/*
+    The slot whose guard page contains `addr`, for the double fault handler: takes no locks
+ */
+pub fn guard_page_slot(addr: VirtAddr) -> Option<usize> {
+    let offset = addr.as_u64().checked_sub(STACKS_START)?;
+    let slot = (offset / SLOT_SIZE) as usize;
+    (slot < STACK_SLOTS && offset % SLOT_SIZE < PAGE_SIZE).then_some(slot)
+}
+
+#[test_case]
+fn test_stack_slots() {
+    let stack = Stack::allocate().expect("stack");
+    let other = Stack::allocate().expect("second stack");
+    assert_ne!(stack.slot(), other.slot());
+    assert_eq!(stack.top().as_u64() % 16, 0);
+    assert_eq!(guard_page_slot(stack.bottom() - 1u64), Some(stack.slot()));
+    assert_eq!(guard_page_slot(stack.bottom()), None);
+
+    // The pages are there to write to
+    let top: *mut u64 = (stack.top() - 8u64).as_mut_ptr();
+    unsafe {
+        top.write_volatile(42);
+        assert_eq!(top.read_volatile(), 42);
+    }
+
+    let slot = stack.slot();
+    drop(stack);
+    assert_eq!(Stack::allocate().expect("reused stack").slot(), slot);
+}
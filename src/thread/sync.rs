@@ -0,0 +1,23 @@
+/*
+    Synchronization for threads
+
+    A spin::Mutex spins while its holder might be switched out, for a whole timer tick or until the holder is woken.
+    These block the waiting thread instead and let the others run meanwhile.
+    WaitQueue is the building block: Mutex and Condvar keep their waiting threads in one,
+    and like it they wake them in arrival order.
+
+    SpinLock is for state shared with interrupt handlers and for short critical sections, it disables interrupts
+    while held. Debug builds check that nothing which might block is called with one held, see thread::might_sleep.
+ */
+mod condvar;
+mod mutex;
+mod spin_lock;
+mod wait_queue;
+
+pub use condvar::Condvar;
+pub use mutex::{Mutex, MutexGuard};
+pub use spin_lock::SpinLock;
+pub use wait_queue::WaitQueue;
+
+#[cfg(debug_assertions)]
+pub(super) use spin_lock::held_spin_locks;
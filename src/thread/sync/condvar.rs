@@ -0,0 +1,73 @@
+use super::{MutexGuard, WaitQueue};
+
+/*
+    Lets threads wait for a change to the value behind a Mutex.
+    wait() unlocks the mutex and blocks in one go, so a notification sent after the unlock reaches the waiter.
+    A waiter might find the value unchanged when it wakes, wait_while() checks again.
+ */
+pub struct Condvar {
+    waiters: WaitQueue,
+}
+
+impl Condvar {
+    pub const fn new() -> Self {
+        Condvar { waiters: WaitQueue::new() }
+    }
+
+    // Unlock, block until notified, then lock again
+    pub fn wait<'a, T: ?Sized>(&self, guard: MutexGuard<'a, T>) -> MutexGuard<'a, T> {
+        let mutex = guard.mutex;
+        // Unlocked below, with the thread already among the waiters
+        core::mem::forget(guard);
+        self.waiters.wait_after(|| mutex.unlock());
+        mutex.lock()
+    }
+
+    // Wait until `condition` is false for the value
+    pub fn wait_while<'a, T: ?Sized>(&self, mut guard: MutexGuard<'a, T>, mut condition: impl FnMut(&mut T) -> bool) -> MutexGuard<'a, T> {
+        while condition(&mut guard) {
+            guard = self.wait(guard);
+        }
+        guard
+    }
+
+    // Interrupt handlers may notify, see WaitQueue
+    pub fn notify_one(&self) -> bool {
+        self.waiters.notify_one()
+    }
+
+    pub fn notify_all(&self) -> usize {
+        self.waiters.notify_all()
+    }
+}
+
+impl Default for Condvar {
+    fn default() -> Self {
+        Condvar::new()
+    }
+}
+
+#[test_case]
+fn test_condvar() {
+    use alloc::{sync::Arc, vec::Vec};
+    use super::Mutex;
+    use crate::thread;
+
+    let shared = Arc::new((Mutex::new(Vec::new()), Condvar::new()));
+    let consumer = {
+        let shared = shared.clone();
+        thread::spawn("consumer", move || {
+            let (items, changed) = &*shared;
+            let items = changed.wait_while(items.lock(), |items| items.len() < 3);
+            items.iter().sum::<u32>()
+        }).expect("spawn")
+    };
+
+    let (items, changed) = &*shared;
+    for item in 1..=3 {
+        items.lock().push(item);
+        changed.notify_one();
+        thread::yield_now();
+    }
+    assert_eq!(consumer.join(), 6);
+}
@@ -0,0 +1,116 @@
+use core::{cell::UnsafeCell, ops::{Deref, DerefMut}, sync::atomic::{AtomicBool, Ordering}};
+use x86_64::instructions::interrupts;
+
+use super::WaitQueue;
+use crate::thread;
+
+/*
+    A mutex whose waiting threads block instead of spinning. The guard may be held while the thread sleeps or blocks.
+    Unlocking hands the mutex straight to the thread waiting longest, so nobody can take it in between.
+ */
+pub struct Mutex<T: ?Sized> {
+    locked: AtomicBool,
+    waiters: WaitQueue,
+    value: UnsafeCell<T>,
+}
+
+// One guard at a time, like spin::Mutex
+unsafe impl<T: ?Sized + Send> Send for Mutex<T> {}
+unsafe impl<T: ?Sized + Send> Sync for Mutex<T> {}
+
+impl<T> Mutex<T> {
+    pub const fn new(value: T) -> Self {
+        Mutex { locked: AtomicBool::new(false), waiters: WaitQueue::new(), value: UnsafeCell::new(value) }
+    }
+
+    pub fn into_inner(self) -> T {
+        self.value.into_inner()
+    }
+}
+
+impl<T: ?Sized> Mutex<T> {
+    // Not from interrupt handlers, they cannot block
+    pub fn lock(&self) -> MutexGuard<'_, T> {
+        thread::might_sleep();
+        interrupts::without_interrupts(|| {
+            if self.locked.swap(true, Ordering::Acquire) {
+                // Still locked when woken, handed over by unlock()
+                self.waiters.wait();
+            }
+        });
+        MutexGuard { mutex: self }
+    }
+
+    pub fn try_lock(&self) -> Option<MutexGuard<'_, T>> {
+        // Waiting threads only exist while it is locked, so this does not jump the queue
+        (!self.locked.swap(true, Ordering::Acquire)).then_some(MutexGuard { mutex: self })
+    }
+
+    pub fn is_locked(&self) -> bool {
+        self.locked.load(Ordering::Relaxed)
+    }
+
+    // No locking needed, &mut proves nobody else has access
+    pub fn get_mut(&mut self) -> &mut T {
+        self.value.get_mut()
+    }
+
+    pub(super) fn unlock(&self) {
+        interrupts::without_interrupts(|| {
+            if !self.waiters.notify_one() {
+                self.locked.store(false, Ordering::Release);
+            }
+        });
+    }
+}
+
+pub struct MutexGuard<'a, T: ?Sized> {
+    pub(super) mutex: &'a Mutex<T>,
+}
+
+// Sharing the guard shares &T
+unsafe impl<T: ?Sized + Sync> Sync for MutexGuard<'_, T> {}
+
+impl<T: ?Sized> Deref for MutexGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.mutex.value.get() }
+    }
+}
+
+impl<T: ?Sized> DerefMut for MutexGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.mutex.value.get() }
+    }
+}
+
+impl<T: ?Sized> Drop for MutexGuard<'_, T> {
+    fn drop(&mut self) {
+        self.mutex.unlock();
+    }
+}
+
+#[test_case]
+fn test_mutex() {
+    use alloc::{sync::Arc, vec::Vec};
+
+    // Each thread yields while holding the lock, the others have to block on it
+    let counter = Arc::new(Mutex::new(0));
+    let handles: Vec<_> = (0..3).map(|_| {
+        let counter = counter.clone();
+        thread::spawn("counter", move || {
+            for _ in 0..10 {
+                let mut count = counter.lock();
+                let before = *count;
+                thread::yield_now();
+                *count = before + 1;
+            }
+        }).expect("spawn")
+    }).collect();
+    for handle in handles {
+        handle.join();
+    }
+    assert!(!counter.is_locked());
+    assert_eq!(*counter.try_lock().expect("mutex free"), 30);
+}
@@ -0,0 +1,76 @@
+#[cfg(debug_assertions)]
+use core::sync::atomic::{AtomicUsize, Ordering};
+use x86_64::instructions::interrupts;
+
+/*
+    A spin::Mutex locked with interrupts disabled, only for the duration of a closure.
+    No thread switch happens while it is held, unless the holder blocks: debug builds panic then.
+ */
+pub struct SpinLock<T: ?Sized> {
+    value: spin::Mutex<T>,
+}
+
+impl<T> SpinLock<T> {
+    pub const fn new(value: T) -> Self {
+        SpinLock { value: spin::Mutex::new(value) }
+    }
+
+    pub fn into_inner(self) -> T {
+        self.value.into_inner()
+    }
+}
+
+impl<T: ?Sized> SpinLock<T> {
+    pub fn lock<R>(&self, f: impl FnOnce(&mut T) -> R) -> R {
+        interrupts::without_interrupts(|| {
+            let mut value = self.value.lock();
+            let _held = Held::new();
+            f(&mut value)
+        })
+    }
+}
+
+// Interrupts are disabled while a lock is held, so the held locks belong to the running thread or interrupt handler
+#[cfg(debug_assertions)]
+static HELD: AtomicUsize = AtomicUsize::new(0);
+
+#[cfg(debug_assertions)]
+pub fn held_spin_locks() -> usize {
+    HELD.load(Ordering::Relaxed)
+}
+
+// Counts a held lock in debug builds
+struct Held;
+
+impl Held {
+    fn new() -> Self {
+        #[cfg(debug_assertions)]
+        HELD.fetch_add(1, Ordering::Relaxed);
+        Held
+    }
+}
+
+impl Drop for Held {
+    fn drop(&mut self) {
+        #[cfg(debug_assertions)]
+        HELD.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+#[test_case]
+fn test_spin_lock() {
+    let lock = SpinLock::new(1);
+    let other = SpinLock::new(2);
+    let sum = lock.lock(|value| {
+        assert!(!interrupts::are_enabled());
+        other.lock(|other| {
+            #[cfg(debug_assertions)]
+            assert_eq!(held_spin_locks(), 2);
+            *value + *other
+        })
+    });
+    assert_eq!(sum, 3);
+    assert!(interrupts::are_enabled());
+    #[cfg(debug_assertions)]
+    assert_eq!(held_spin_locks(), 0);
+}
@@ -0,0 +1,106 @@
+use alloc::collections::VecDeque;
+use x86_64::instructions::interrupts;
+
+use super::SpinLock;
+use crate::thread::{self, ThreadId};
+
+/*
+    Threads blocked until notified, woken in arrival order.
+    Notifying never blocks or allocates, interrupt handlers may do it: a driver's thread waits for
+    its device that way. A thread left waiting when the queue is dropped never wakes up.
+ */
+pub struct WaitQueue {
+    threads: SpinLock<VecDeque<ThreadId>>,
+}
+
+impl WaitQueue {
+    pub const fn new() -> Self {
+        WaitQueue { threads: SpinLock::new(VecDeque::new()) }
+    }
+
+    // Block until notified
+    pub fn wait(&self) {
+        self.wait_after(|| {});
+    }
+
+    /*
+        Block until `condition` is false, it is checked with interrupts disabled:
+        a notification between the check and blocking is not lost. The condition must not block.
+     */
+    pub fn wait_while(&self, mut condition: impl FnMut() -> bool) {
+        interrupts::without_interrupts(|| {
+            while condition() {
+                self.wait();
+            }
+        });
+    }
+
+    // Join the queue, run `release`, and block, all without an interrupt in between
+    pub(super) fn wait_after(&self, release: impl FnOnce()) {
+        thread::might_sleep();
+        interrupts::without_interrupts(|| {
+            let current = thread::current().expect("waiting before thread::init()");
+            self.threads.lock(|threads| threads.push_back(current));
+            release();
+            thread::block();
+        });
+    }
+
+    // Wake the thread waiting longest, false if none is waiting
+    pub fn notify_one(&self) -> bool {
+        match self.threads.lock(|threads| threads.pop_front()) {
+            Some(id) => {
+                thread::unblock(id);
+                true
+            }
+            None => false,
+        }
+    }
+
+    // Wake all waiting threads and return how many there were
+    pub fn notify_all(&self) -> usize {
+        let mut woken = 0;
+        while self.notify_one() {
+            woken += 1;
+        }
+        woken
+    }
+
+    pub fn len(&self) -> usize {
+        self.threads.lock(|threads| threads.len())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl Default for WaitQueue {
+    fn default() -> Self {
+        WaitQueue::new()
+    }
+}
+
+#[test_case]
+fn test_wait_queue() {
+    use core::sync::atomic::{AtomicBool, Ordering};
+
+    static QUEUE: WaitQueue = WaitQueue::new();
+    static READY: AtomicBool = AtomicBool::new(false);
+    let waiter = thread::spawn("waiter", || QUEUE.wait_while(|| !READY.load(Ordering::Relaxed))).expect("spawn");
+    while QUEUE.is_empty() {
+        thread::yield_now();
+    }
+
+    // Woken without the condition met, it waits again
+    assert_eq!(QUEUE.notify_all(), 1);
+    while QUEUE.is_empty() {
+        thread::yield_now();
+    }
+    assert!(!waiter.is_finished());
+
+    READY.store(true, Ordering::Relaxed);
+    assert!(QUEUE.notify_one());
+    waiter.join();
+    assert!(!QUEUE.notify_one());
+}
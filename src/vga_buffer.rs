@@ -1,6 +1,12 @@
 use volatile::Volatile;
 use core::fmt;
 
+pub(crate) mod ansi;
+pub(crate) mod cp437;
+mod scrollback;
+use ansi::{Action, Csi, Parser};
+use scrollback::{Line, Scrollback};
+
 #[allow(dead_code)] //SyntaxTip: Avoid warning of this piece of code if it is unused
 #[derive(Debug, Clone, Copy, PartialEq, Eq)] //SyntaxTip: Make the enum printable and comparable
 #[repr(u8)]//SyntaxTip:  Indicate each enum variant is stored as u8, u4 is enough, but Rust has no u4
@@ -28,11 +34,28 @@ pub enum Color {
 struct ColorCode(u8);
 
 impl ColorCode {
-    fn new(foreground: Color, background: Color) -> ColorCode {
+    const fn new(foreground: Color, background: Color) -> ColorCode {
         ColorCode((background as u8) << 4 | (foreground as u8))
     }
+
+    fn with_foreground(self, foreground: u8) -> ColorCode {
+        ColorCode((self.0 & 0xf0) | (foreground & 0x0f))
+    }
+
+    fn with_background(self, background: u8) -> ColorCode {
+        ColorCode((self.0 & 0x0f) | (background & 0x0f) << 4)
+    }
 }
 
+const DEFAULT_COLOR_CODE: ColorCode = ColorCode::new(Color::Yellow, Color::Black);
+
+// ANSI color numbers 0..=7 (black, red, green, yellow, blue, magenta, cyan, white) in VGA order
+const ANSI_COLORS: [Color; 8] = [
+    Color::Black, Color::Red, Color::Green, Color::Brown,
+    Color::Blue, Color::Magenta, Color::Cyan, Color::LightGray,
+];
+const BRIGHT: u8 = 0x08; // the 4th color bit selects the light variant
+
 /*
     Screen character
  */
@@ -53,69 +76,482 @@ struct  Buffer {
 }
 
 /*
-    Write to the last line and shift lines up when it is full(or on \n) of VGA buffer
+    SyntaxTip:
+        we cast the integer 0xb8000 as a mutable raw pointer, every access dereferences it
+        for a single volatile read or write, so that no long living `&mut` to the VGA memory exists
+
+        ┌─────────────────┐ 0x00000000
+        │                 │
+        │                 │
+        ├─────────────────┤ 0x000B8000  ←─── 0xb8000
+        │  [VGA Display]  │     │
+        │  [[ScreenChar;80];25] │      │ as *mut Buffer
+        │                 │      ↓
+        └─────────────────┘ 0x000B8FA0
+ */
+const VGA_BUFFER: *mut Buffer = 0xb8000 as *mut Buffer;
+
+fn vga_write(row: usize, col: usize, character: ScreenChar) {
+    unsafe { (*VGA_BUFFER).chars[row][col].write(character) }
+}
+
+fn vga_read(row: usize, col: usize) -> ScreenChar {
+    unsafe { (*VGA_BUFFER).chars[row][col].read() }
+}
+
+const TAB_WIDTH: usize = 8;
+
+/*
+    A text terminal, one per virtual console.
+    Output starts on the last line, lines inside the scroll region [scroll_top, scroll_bottom]
+    are shifted up when the cursor moves past scroll_bottom (on \n or when a line is full),
+    rows outside the region (e.g. a status bar) stay where they are.
+    ANSI escape sequences in the written strings are interpreted (see ansi.rs), so the same
+    colored output can be sent to the serial console and to the screen.
+    Everything is drawn into the off-screen `screen`, the active console mirrors it to VGA memory.
  */
 pub struct  Writer {
+    row_position: usize,
     column_position: usize,
     color_code: ColorCode,
-    buffer: &'static mut Buffer, //SyntaxTip: A program life time('static), changeable reference(&mut) of VGA buffer but not ownership(just borrow)
+    scroll_top: usize,
+    scroll_bottom: usize,
+    bold: bool, // SGR 1, colors set afterwards use the light variant
+    saved_cursor: (usize, usize),
+    parser: Parser,
+    scrollback: Option<Scrollback>, // None until the heap is ready, see init_scrollback
+    screen: [Line; BUFFER_HEIGHT],
+    active: bool, // shown on the physical screen
 }
 
 impl Writer {
+    /*
+        The console which is active at boot takes over what is on the screen,
+        so that the bootloader messages are not lost
+     */
+    fn new(active: bool) -> Self {
+        let blank = ScreenChar {
+            ascii_character: b' ',
+            color_code: DEFAULT_COLOR_CODE,
+        };
+        let mut screen = [[blank; BUFFER_WIDTH]; BUFFER_HEIGHT];
+        if active {
+            for (row, line) in screen.iter_mut().enumerate() {
+                for (col, character) in line.iter_mut().enumerate() {
+                    *character = vga_read(row, col);
+                }
+            }
+        }
+
+        Writer {
+            row_position: BUFFER_HEIGHT - 1,
+            column_position: 0,
+            color_code: DEFAULT_COLOR_CODE,
+            scroll_top: 0,
+            scroll_bottom: BUFFER_HEIGHT - 1,
+            bold: false,
+            saved_cursor: (0, 0),
+            parser: Parser::new(),
+            scrollback: None,
+            screen,
+            active,
+        }
+    }
+
     pub fn write_byte(&mut self, byte: u8) {
+        self.snap_to_live();
         match byte {
             b'\n' => self.new_line(),
-            byte => {
-                if self.column_position >= BUFFER_WIDTH {
-                    self.new_line();
-                }
+            b'\r' => self.column_position = 0,
+            b'\t' => {
+                // Jump to the next tab stop without erasing what is under it
+                let next_stop = (self.column_position / TAB_WIDTH + 1) * TAB_WIDTH;
+                self.column_position = next_stop.min(BUFFER_WIDTH);
+            }
+            0x08 => self.column_position = self.column_position.saturating_sub(1), // backspace, like a real terminal it does not erase
+            byte => self.write_glyph(byte),
+        }
+    }
+
+    /*
+        Put a code page 437 glyph at the cursor, even the ones living in the control range
+     */
+    pub fn write_glyph(&mut self, glyph: u8) {
+        self.snap_to_live();
+        if self.column_position >= BUFFER_WIDTH {
+            self.new_line();
+        }
 
-                let row = BUFFER_HEIGHT - 1;
-                let col = self.column_position;
+        let row = self.row_position;
+        let col = self.column_position;
 
-                let color_code = self.color_code;
-                self.buffer.chars[row][col].write(ScreenChar {
-                    ascii_character: byte,
-                    color_code,
-                });
-                self.column_position += 1;
+        let color_code = self.color_code;
+        self.put(row, col, ScreenChar {
+            ascii_character: glyph,
+            color_code,
+        });
+        self.column_position += 1;
+    }
+
+    pub fn write_string(&mut self, s: &str) {
+        self.snap_to_live();
+        for c in s.chars() {
+            match self.parser.advance(c) {
+                // for characters the VGA font can not display, print a ■ instead
+                Some(Action::Print(c)) => self.write_glyph(cp437::from_char(c).unwrap_or(0xfe)),
+                Some(Action::Execute(byte)) => match byte {
+                    b'\n' | b'\r' | b'\t' | 0x08 => self.write_byte(byte),
+                    _ => {} // other control characters (bell, ...) have no visible effect
+                },
+                Some(Action::Csi(csi)) => self.apply_csi(&csi),
+                Some(Action::Esc(byte)) => self.apply_esc(byte),
+                None => {}
             }
         }
+        self.update_cursor();
     }
 
-    pub fn write_string(&mut self, s: &str) {
-        for byte in s.bytes() {
-            match byte {
-                // printable ASCII byte or newline
-                0x20..=0x7e | b'\n' => self.write_byte(byte),
-                _ => self.write_byte(0xfe), // for unprintable bytes, print a ■ instead
+    pub fn set_color(&mut self, foreground: Color, background: Color) {
+        self.color_code = ColorCode::new(foreground, background);
+    }
+
+    /*
+        Blank the whole screen with the current color and move the cursor to the top left corner
+     */
+    pub fn clear(&mut self) {
+        self.snap_to_live();
+        for row in 0..BUFFER_HEIGHT {
+            self.clear_row(row);
+        }
+        self.set_cursor(0, 0);
+    }
+
+    /*
+        Move the cursor, out of range positions are clamped to the screen
+     */
+    pub fn set_cursor(&mut self, row: usize, col: usize) {
+        self.row_position = row.min(BUFFER_HEIGHT - 1);
+        self.column_position = col.min(BUFFER_WIDTH - 1);
+        self.update_cursor();
+    }
+
+    // (row, column) of the cursor
+    pub fn cursor(&self) -> (usize, usize) {
+        (self.row_position, self.column_position)
+    }
+
+    /*
+        Restrict scrolling to rows top..=bottom, the rows outside are kept intact
+     */
+    pub fn set_scroll_region(&mut self, top: usize, bottom: usize) {
+        assert!(top < bottom && bottom < BUFFER_HEIGHT, "invalid scroll region");
+        self.scroll_top = top;
+        self.scroll_bottom = bottom;
+    }
+
+    pub fn reset_scroll_region(&mut self) {
+        self.set_scroll_region(0, BUFFER_HEIGHT - 1);
+    }
+
+    fn apply_csi(&mut self, csi: &Csi) {
+        let n = csi.param_or(0, 1) as usize;
+        let (row, col) = self.cursor();
+        match (csi.private, csi.final_byte) {
+            (false, b'A') => self.set_cursor(row.saturating_sub(n), col),
+            (false, b'B') => self.set_cursor(row + n, col),
+            (false, b'C') => self.set_cursor(row, col + n),
+            (false, b'D') => self.set_cursor(row, col.saturating_sub(n)),
+            (false, b'E') => self.set_cursor(row + n, 0),
+            (false, b'F') => self.set_cursor(row.saturating_sub(n), 0),
+            (false, b'G') => self.set_cursor(row, n - 1),
+            (false, b'd') => self.set_cursor(n - 1, col),
+            // positions are 1-based in escape sequences
+            (false, b'H') | (false, b'f') => self.set_cursor(n - 1, csi.param_or(1, 1) as usize - 1),
+            (false, b'J') => self.erase_display(csi.param_or(0, 0)),
+            (false, b'K') => self.erase_line(csi.param_or(0, 0)),
+            (false, b'm') => self.apply_sgr(csi.params()),
+            (false, b'r') => {
+                let top = n - 1;
+                let bottom = csi.param_or(1, BUFFER_HEIGHT as u16) as usize - 1;
+                if top < bottom && bottom < BUFFER_HEIGHT {
+                    self.set_scroll_region(top, bottom);
+                }
             }
+            (false, b's') => self.saved_cursor = self.cursor(),
+            (false, b'u') => self.set_cursor(self.saved_cursor.0, self.saved_cursor.1),
+            (true, b'h') if csi.params() == [25] => enable_cursor(14, 15),
+            (true, b'l') if csi.params() == [25] => disable_cursor(),
+            _ => {} // unsupported sequence, ignore it
         }
     }
 
-    fn new_line(&mut self) { 
-        for row in 1..BUFFER_HEIGHT {
-            for col in 0..BUFFER_WIDTH {
-                let character = self.buffer.chars[row][col].read();
-                self.buffer.chars[row - 1][col].write(character);
+    fn apply_esc(&mut self, byte: u8) {
+        match byte {
+            b'7' => self.saved_cursor = self.cursor(),
+            b'8' => self.set_cursor(self.saved_cursor.0, self.saved_cursor.1),
+            b'c' => {
+                self.color_code = DEFAULT_COLOR_CODE;
+                self.bold = false;
+                self.reset_scroll_region();
+                self.clear();
             }
+            _ => {}
         }
+    }
 
-        self.clear_row(BUFFER_HEIGHT - 1);
-        self.column_position = 0;        
+    /*
+        Select Graphic Rendition, e.g. ESC [ 1 ; 31 m for light red text
+     */
+    fn apply_sgr(&mut self, params: &[u16]) {
+        if params.is_empty() {
+            self.apply_sgr(&[0]);
+            return;
+        }
+
+        let bright = |bold: bool| if bold { BRIGHT } else { 0 };
+        for &param in params {
+            self.color_code = match param {
+                0 => {
+                    self.bold = false;
+                    DEFAULT_COLOR_CODE
+                }
+                1 => {
+                    self.bold = true;
+                    self.color_code.with_foreground(self.color_code.0 | BRIGHT)
+                }
+                22 => {
+                    self.bold = false;
+                    self.color_code.with_foreground(self.color_code.0 & !BRIGHT)
+                }
+                30..=37 => self.color_code.with_foreground(ANSI_COLORS[param as usize - 30] as u8 | bright(self.bold)),
+                39 => self.color_code.with_foreground(DEFAULT_COLOR_CODE.0),
+                40..=47 => self.color_code.with_background(ANSI_COLORS[param as usize - 40] as u8),
+                49 => self.color_code.with_background(DEFAULT_COLOR_CODE.0 >> 4),
+                90..=97 => self.color_code.with_foreground(ANSI_COLORS[param as usize - 90] as u8 | BRIGHT),
+                100..=107 => self.color_code.with_background(ANSI_COLORS[param as usize - 100] as u8 | BRIGHT),
+                _ => self.color_code, // underline, blink, ... are not supported in text mode
+            };
+        }
     }
 
-    fn clear_row(&mut self, row: usize) {
+    /*
+        0: from cursor to end of screen, 1: from start of screen to cursor, 2 or 3: whole screen
+        The cursor does not move
+     */
+    fn erase_display(&mut self, mode: u16) {
+        let (row, _) = self.cursor();
+        match mode {
+            0 => {
+                self.erase_line(0);
+                for r in row + 1..BUFFER_HEIGHT {
+                    self.clear_row(r);
+                }
+            }
+            1 => {
+                for r in 0..row {
+                    self.clear_row(r);
+                }
+                self.erase_line(1);
+            }
+            2 | 3 => {
+                for r in 0..BUFFER_HEIGHT {
+                    self.clear_row(r);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /*
+        0: from cursor to end of line, 1: from start of line to cursor, 2: whole line
+     */
+    fn erase_line(&mut self, mode: u16) {
+        let (row, col) = self.cursor();
+        let col = col.min(BUFFER_WIDTH - 1);
+        match mode {
+            0 => self.clear_cells(row, col..BUFFER_WIDTH),
+            1 => self.clear_cells(row, 0..col + 1),
+            2 => self.clear_row(row),
+            _ => {}
+        }
+    }
+
+    fn clear_cells(&mut self, row: usize, cols: core::ops::Range<usize>) {
         let blank = ScreenChar {
             ascii_character: b' ',
             color_code: self.color_code,
         };
 
-        for col in 0..BUFFER_WIDTH {
-            self.buffer.chars[row][col].write(blank);
+        for col in cols {
+            self.put(row, col, blank);
+        }
+    }
+
+    fn put(&mut self, row: usize, col: usize, character: ScreenChar) {
+        self.screen[row][col] = character;
+        if self.active {
+            vga_write(row, col, character);
+        }
+    }
+
+    fn new_line(&mut self) { 
+        if self.row_position == self.scroll_bottom {
+            self.scroll_up();
+        } else if self.row_position < BUFFER_HEIGHT - 1 {
+            self.row_position += 1;
+        }
+        self.column_position = 0;        
+    }
+
+    fn scroll_up(&mut self) {
+        if self.scroll_top == 0 && let Some(scrollback) = self.scrollback.as_mut() {
+            // The top line leaves the screen, keep it in the history
+            scrollback.push(self.screen[0]);
+        }
+
+        self.screen.copy_within(self.scroll_top + 1..=self.scroll_bottom, self.scroll_top);
+        if self.active {
+            for row in self.scroll_top..self.scroll_bottom {
+                for col in 0..BUFFER_WIDTH {
+                    vga_write(row, col, self.screen[row][col]);
+                }
+            }
+        }
+
+        self.clear_row(self.scroll_bottom);
+    }
+
+    /*
+        Keep up to `lines` lines which scroll off the top of the screen,
+        needs the heap, so it can not be done when the console is created
+     */
+    pub fn enable_scrollback(&mut self, lines: usize) {
+        self.snap_to_live();
+        self.scrollback = Some(Scrollback::new(lines));
+    }
+
+    /*
+        Move the view `lines` lines back into the history, the live screen is restored
+        on the next output or when scrolling forward again
+     */
+    pub fn scroll_back(&mut self, lines: usize) {
+        if let Some(scrollback) = self.scrollback.as_mut() {
+            if scrollback.len() == 0 {
+                return;
+            }
+            let offset = scrollback.offset().saturating_add(lines);
+            scrollback.set_offset(offset);
+            self.render_view();
         }
     }
 
+    pub fn scroll_forward(&mut self, lines: usize) {
+        if let Some(scrollback) = self.scrollback.as_mut() && scrollback.is_scrolled() {
+            let offset = scrollback.offset().saturating_sub(lines);
+            scrollback.set_offset(offset);
+            self.render_view();
+        }
+    }
+
+    // Number of lines the view is moved back into the history, 0 for the live screen
+    pub fn scrollback_offset(&self) -> usize {
+        self.scrollback.as_ref().map_or(0, |scrollback| scrollback.offset())
+    }
+
+    fn snap_to_live(&mut self) {
+        if self.scrollback_offset() != 0 {
+            self.scroll_forward(usize::MAX);
+        }
+    }
+
+    // Line shown on screen row `row`, taken from the history while scrolled back
+    fn view_line(&self, row: usize) -> &Line {
+        match self.scrollback.as_ref() {
+            Some(scrollback) => scrollback.view_line(row, &self.screen),
+            None => &self.screen[row],
+        }
+    }
+
+    fn render_view(&self) {
+        if !self.active {
+            return;
+        }
+        for row in 0..BUFFER_HEIGHT {
+            for (col, &character) in self.view_line(row).iter().enumerate() {
+                vga_write(row, col, character);
+            }
+        }
+    }
+
+    /*
+        Copy the whole console to VGA memory, used when it becomes the active one
+     */
+    fn redraw(&self) {
+        self.render_view();
+        self.update_cursor();
+    }
+
+    fn clear_row(&mut self, row: usize) {
+        self.clear_cells(row, 0..BUFFER_WIDTH);
+    }
+
+    // Move the blinking hardware cursor to our position
+    fn update_cursor(&self) {
+        if !self.active {
+            return;
+        }
+        let col = self.column_position.min(BUFFER_WIDTH - 1);
+        let position = (self.row_position * BUFFER_WIDTH + col) as u16;
+        crtc_write(CRTC_CURSOR_LOCATION_LOW, (position & 0xff) as u8);
+        crtc_write(CRTC_CURSOR_LOCATION_HIGH, (position >> 8) as u8);
+    }
+
+}
+
+/*
+    The CRT controller is accessed through an index/data port pair:
+    write the register index to 0x3D4, then read/write its value through 0x3D5
+ */
+const CRTC_ADDRESS_PORT: u16 = 0x3D4;
+const CRTC_DATA_PORT: u16 = 0x3D5;
+const CRTC_CURSOR_START: u8 = 0x0A;
+const CRTC_CURSOR_END: u8 = 0x0B;
+const CRTC_CURSOR_LOCATION_HIGH: u8 = 0x0E;
+const CRTC_CURSOR_LOCATION_LOW: u8 = 0x0F;
+const CURSOR_DISABLE: u8 = 1 << 5;
+
+fn crtc_write(index: u8, value: u8) {
+    use x86_64::instructions::port::Port;
+
+    let mut address: Port<u8> = Port::new(CRTC_ADDRESS_PORT);
+    let mut data: Port<u8> = Port::new(CRTC_DATA_PORT);
+    unsafe {
+        address.write(index);
+        data.write(value);
+    }
+}
+
+fn crtc_read(index: u8) -> u8 {
+    use x86_64::instructions::port::Port;
+
+    let mut address: Port<u8> = Port::new(CRTC_ADDRESS_PORT);
+    let mut data: Port<u8> = Port::new(CRTC_DATA_PORT);
+    unsafe {
+        address.write(index);
+        data.read()
+    }
+}
+
+/*
+    Show the hardware cursor as scanlines start..=end of the character cell (0..=15), e.g. 14, 15 for an underline
+ */
+pub fn enable_cursor(start: u8, end: u8) {
+    crtc_write(CRTC_CURSOR_START, (crtc_read(CRTC_CURSOR_START) & 0xc0) | (start & 0x1f));
+    crtc_write(CRTC_CURSOR_END, (crtc_read(CRTC_CURSOR_END) & 0xe0) | (end & 0x1f));
+}
+
+pub fn disable_cursor() {
+    crtc_write(CRTC_CURSOR_START, CURSOR_DISABLE);
 }
 
 impl fmt::Write for Writer {
@@ -125,39 +561,57 @@ impl fmt::Write for Writer {
     }
 }
 
-use spin::Mutex; // OS free mutex
+use crate::print_lock::PrintLock; // OS free mutex which can not deadlock the print path
+use core::sync::atomic::{AtomicUsize, Ordering};
 use lazy_static::lazy_static;
+
+pub const CONSOLE_COUNT: usize = 6;
+// print!/println! go to this console, and the panic handler switches to it
+pub const LOG_CONSOLE: usize = 0;
+
+static ACTIVE_CONSOLE: AtomicUsize = AtomicUsize::new(LOG_CONSOLE);
+
 /*
     Use lazy_static instead of computing its value at compile time,  
     the static lazily initializes itself when accessed for the first time
  */
 lazy_static! {
     /*
-        Provide a global writer for other modules rather than carry a Writer instance everwhere
-        SyntaxTip 1:
-            first we cast the integer 0xb8000 as a mutable raw pointer, this step is a standard
-            then wr convert it to a mutable reference by dereferencing it (use *) 
-            and immediately borrowing it again (use &mut)
-
-            ┌─────────────────┐ 0x00000000
-            │                 │
-            │                 │
-            ├─────────────────┤ 0x000B8000  ←─── 0xb8000
-            │  [VGA Display]  │     │
-            │  [[ScreenChar;80];25] │      │ as *mut Buffer
-            │                 │      ↓
-            └─────────────────┘ 0x000B8FA0
-                │
-                │ *(ptr) deref
-                ↓
-            ┌─────────────────┐
-            │ Actual Buffer   │ ←─── &mut borrow
-            └─────────────────┘
+        Virtual consoles, each one owns its own screen, cursor and colors.
+        Only the active one is copied to the physical VGA buffer.
     */
-    pub static ref WRITER: Mutex<Writer> = Mutex::new(Writer {
-        column_position: 0,
-        color_code: ColorCode::new(Color::Yellow, Color::Black),
-        buffer: unsafe { &mut *(0xb8000 as *mut Buffer) } //SyntaxTip 1
+    static ref CONSOLES: [PrintLock<Writer>; CONSOLE_COUNT] =
+        core::array::from_fn(|index| PrintLock::new(Writer::new(index == LOG_CONSOLE)));
+}
+
+/*
+    Provide the writer of a virtual console for other modules rather than carry a Writer instance everwhere
+ */
+pub fn console(index: usize) -> &'static PrintLock<Writer> {
+    &CONSOLES[index]
+}
+
+pub fn active_console() -> usize {
+    ACTIVE_CONSOLE.load(Ordering::Relaxed)
+}
+
+/*
+    Show another virtual console on the screen
+ */
+pub fn switch_console(index: usize) {
+    use x86_64::instructions::interrupts;
+
+    assert!(index < CONSOLE_COUNT, "no such console");
+    interrupts::without_interrupts(|| {
+        let previous = ACTIVE_CONSOLE.swap(index, Ordering::Relaxed);
+        if previous == index {
+            return;
+        }
+        console(previous).lock().active = false;
+
+        let mut writer = console(index).lock();
+        writer.active = true;
+        writer.redraw();
     });
 }
 
@@ -172,16 +626,63 @@ macro_rules! println {
     ($($arg:tt)*) => ($crate::print!("{}\n", format_args!($($arg)*)))
 }
 
+// Print to a given virtual console instead of the log console
+#[macro_export]
+macro_rules! console_print {
+    ($console:expr, $($arg:tt)*) => ($crate::vga_buffer::_print_to($console, format_args!($($arg)*)))
+}
+
+#[macro_export]
+macro_rules! console_println {
+    ($console:expr) => ($crate::console_print!($console, "\n"));
+    ($console:expr, $($arg:tt)*) => ($crate::console_print!($console, "{}\n", format_args!($($arg)*)))
+}
+
 #[doc(hidden)]
 pub fn _print(args: fmt::Arguments) {
+    // Text mode memory is not shown any more once the framebuffer console is up
+    if crate::framebuffer::console::_print(args) {
+        return;
+    }
+    _print_to(LOG_CONSOLE, args);
+}
+
+#[doc(hidden)]
+pub fn _print_to(index: usize, args: fmt::Arguments) {
     use core::fmt::Write;
     use x86_64::instructions::interrupts;
 
+    // Safe to call from exception and panic context, lock() steals the lock from an interrupted print
     interrupts::without_interrupts(|| {
-         WRITER.lock().write_fmt(args).unwrap(); //SyntaxTip: The additional unwrap() at the end panics if printing isn’t successful
+         console(index).lock().write_fmt(args).unwrap(); //SyntaxTip: The additional unwrap() at the end panics if printing isn’t successful
     });
 }
 
+/*
+    Called once the heap is initialized, each console has its own history
+ */
+pub fn init_scrollback(index: usize, lines: usize) {
+    use x86_64::instructions::interrupts;
+
+    interrupts::without_interrupts(|| console(index).lock().enable_scrollback(lines));
+}
+
+// One screen minus a line of overlap, like most terminals
+pub const SCROLL_PAGE: usize = BUFFER_HEIGHT - 1;
+
+// Scroll the active console
+pub fn scroll_back(lines: usize) {
+    use x86_64::instructions::interrupts;
+
+    interrupts::without_interrupts(|| console(active_console()).lock().scroll_back(lines));
+}
+
+pub fn scroll_forward(lines: usize) {
+    use x86_64::instructions::interrupts;
+
+    interrupts::without_interrupts(|| console(active_console()).lock().scroll_forward(lines));
+}
+
 #[test_case]
 fn test_println_simple() {
     println!("test_println_simple output");
@@ -194,6 +695,25 @@ fn test_println_many() {
     }
 }
 
+#[test_case]
+fn test_println_while_locked() {
+    use x86_64::instructions::interrupts;
+
+    // Simulate an exception handler printing in the middle of a println!
+    let s = "test_println_while_locked output";
+    interrupts::without_interrupts(|| {
+        let writer = console(LOG_CONSOLE).lock();
+        _print_to(LOG_CONSOLE, format_args!("\n{}\n", s));
+        drop(writer);
+
+        let writer = console(LOG_CONSOLE).lock();
+        for (i, c) in s.chars().enumerate() {
+            let screen_char = writer.screen[BUFFER_HEIGHT - 2][i];
+            assert_eq!(char::from(screen_char.ascii_character), c);
+        }
+    });
+}
+
 #[test_case]
 fn test_println_output() {
     use core::fmt::Write;
@@ -201,12 +721,148 @@ fn test_println_output() {
 
     let s = "Some test string that fits on a single line";
     interrupts::without_interrupts(|| {
-        let mut writer = WRITER.lock();
+        let mut writer = console(LOG_CONSOLE).lock();
         writeln!(writer, "\n{}", s).expect("writeln failed");
         for (i, c) in s.chars().enumerate()  {
-            let screen_char = writer.buffer.chars[BUFFER_HEIGHT - 2][i].read();
+            let screen_char = writer.screen[BUFFER_HEIGHT - 2][i];
             assert_eq!(char::from(screen_char.ascii_character), c);
         }
     });
     
-}
\ No newline at end of file
+}
+
+#[test_case]
+fn test_carriage_return_and_tab() {
+    use core::fmt::Write;
+    use x86_64::instructions::interrupts;
+
+    interrupts::without_interrupts(|| {
+        let mut writer = console(LOG_CONSOLE).lock();
+        write!(writer, "\nxxxx\rab\tc").expect("write failed");
+        let row = writer.cursor().0;
+        let line: [u8; 9] = core::array::from_fn(|col| writer.screen[row][col].ascii_character);
+        assert_eq!(&line, b"abxx    c");
+        assert_eq!(writer.cursor().1, TAB_WIDTH + 1);
+    });
+}
+
+#[test_case]
+fn test_backspace_and_set_cursor() {
+    use core::fmt::Write;
+    use x86_64::instructions::interrupts;
+
+    interrupts::without_interrupts(|| {
+        let mut writer = console(LOG_CONSOLE).lock();
+        writer.set_cursor(3, 10);
+        write!(writer, "ab\x08c").expect("write failed");
+        assert_eq!(writer.screen[3][10].ascii_character, b'a');
+        assert_eq!(writer.screen[3][11].ascii_character, b'c');
+        assert_eq!(writer.cursor(), (3, 12));
+        writer.set_cursor(BUFFER_HEIGHT - 1, 0);
+    });
+}
+
+#[test_case]
+fn test_set_color() {
+    use core::fmt::Write;
+    use x86_64::instructions::interrupts;
+
+    interrupts::without_interrupts(|| {
+        let mut writer = console(LOG_CONSOLE).lock();
+        writer.set_color(Color::LightRed, Color::Blue);
+        write!(writer, "\nx").expect("write failed");
+        let screen_char = writer.screen[BUFFER_HEIGHT - 1][0];
+        assert_eq!(screen_char.color_code, ColorCode::new(Color::LightRed, Color::Blue));
+        writer.set_color(Color::Yellow, Color::Black);
+    });
+}
+
+#[test_case]
+fn test_ansi_colors() {
+    use core::fmt::Write;
+    use x86_64::instructions::interrupts;
+
+    interrupts::without_interrupts(|| {
+        let mut writer = console(LOG_CONSOLE).lock();
+        write!(writer, "\n\x1b[1;31;44mA\x1b[0mB").expect("write failed");
+        let a = writer.screen[BUFFER_HEIGHT - 1][0];
+        let b = writer.screen[BUFFER_HEIGHT - 1][1];
+        assert_eq!(a.ascii_character, b'A');
+        assert_eq!(a.color_code, ColorCode::new(Color::LightRed, Color::Blue));
+        assert_eq!(b.ascii_character, b'B');
+        assert_eq!(b.color_code, DEFAULT_COLOR_CODE);
+    });
+}
+
+#[test_case]
+fn test_ansi_cursor_and_erase() {
+    use core::fmt::Write;
+    use x86_64::instructions::interrupts;
+
+    interrupts::without_interrupts(|| {
+        let mut writer = console(LOG_CONSOLE).lock();
+        write!(writer, "\x1b[5;10Hxyz\x1b[2D\x1b[K").expect("write failed");
+        assert_eq!(writer.cursor(), (4, 10));
+        assert_eq!(writer.screen[4][9].ascii_character, b'x');
+        assert_eq!(writer.screen[4][10].ascii_character, b' ');
+        assert_eq!(writer.screen[4][11].ascii_character, b' ');
+        writer.set_cursor(BUFFER_HEIGHT - 1, 0);
+    });
+}
+
+#[test_case]
+fn test_println_unicode() {
+    use core::fmt::Write;
+    use x86_64::instructions::interrupts;
+
+    interrupts::without_interrupts(|| {
+        let mut writer = console(LOG_CONSOLE).lock();
+        write!(writer, "\n┌é→中").expect("write failed");
+        let line: [u8; 4] = core::array::from_fn(|col| writer.screen[BUFFER_HEIGHT - 1][col].ascii_character);
+        assert_eq!(line, [0xda, 0x82, 0x1a, 0xfe]);
+    });
+}
+
+#[test_case]
+fn test_scrollback() {
+    use core::fmt::Write;
+    use x86_64::instructions::interrupts;
+
+    interrupts::without_interrupts(|| {
+        let mut writer = console(LOG_CONSOLE).lock();
+        writer.enable_scrollback(100);
+        writer.set_cursor(BUFFER_HEIGHT - 1, 0);
+        write!(writer, "\nscrolled off").expect("write failed");
+        for _ in 0..BUFFER_HEIGHT {
+            writer.write_byte(b'\n');
+        }
+
+        // "scrolled off" is now the newest history line, one line above the screen
+        writer.scroll_back(1);
+        assert_eq!(writer.scrollback_offset(), 1);
+        assert_eq!(writer.view_line(0)[0].ascii_character, b's');
+
+        // 1 + BUFFER_HEIGHT lines were pushed out in total
+        writer.scroll_back(usize::MAX);
+        assert_eq!(writer.scrollback_offset(), BUFFER_HEIGHT + 1);
+
+        // New output snaps back to the live screen
+        write!(writer, "x").expect("write failed");
+        assert_eq!(writer.scrollback_offset(), 0);
+        assert_eq!(writer.view_line(BUFFER_HEIGHT - 1)[0].ascii_character, b'x');
+    });
+}
+
+#[test_case]
+fn test_virtual_consoles() {
+    console_print!(1, "\nconsole 1");
+    println!();
+    assert_eq!(vga_read(BUFFER_HEIGHT - 1, 0).ascii_character, b' ');
+
+    switch_console(1);
+    assert_eq!(active_console(), 1);
+    assert_eq!(vga_read(BUFFER_HEIGHT - 1, 0).ascii_character, b'c');
+
+    switch_console(LOG_CONSOLE);
+    assert_eq!(vga_read(BUFFER_HEIGHT - 1, 0).ascii_character, b' ');
+}
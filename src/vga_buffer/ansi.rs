@@ -0,0 +1,208 @@
+/*
+    ANSI/VT100 escape sequence parser
+
+    Only understands the subset we need for a text console:
+        ESC [ <params> <final>   control sequences (CSI), e.g. SGR colors, cursor movement, erase
+        ESC <final>              a few single character escapes, e.g. ESC 7 / ESC 8 / ESC c
+    Everything else is swallowed so that unknown sequences never show up as garbage on screen.
+ */
+
+const ESC: char = '\x1b';
+const CAN: char = '\x18';
+const SUB: char = '\x1a';
+const MAX_PARAMS: usize = 8;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    Ground,
+    Escape,
+    Csi,
+}
+
+/*
+    A complete control sequence: ESC [ (?) p1 ; p2 ; ... final
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Csi {
+    params: [u16; MAX_PARAMS],
+    len: usize,
+    pub private: bool, // '?' right after '[', e.g. ESC [ ? 25 l
+    pub final_byte: u8,
+}
+
+impl Csi {
+    pub fn params(&self) -> &[u16] {
+        &self.params[..self.len]
+    }
+
+    // Parameter at index, missing or 0 parameters take the default value
+    pub fn param_or(&self, index: usize, default: u16) -> u16 {
+        match self.params().get(index) {
+            Some(&value) if value != 0 => value,
+            _ => default,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Action {
+    Print(char),
+    Execute(u8), // C0 control character like \n, \r, \t, backspace
+    Csi(Csi),
+    Esc(u8),
+}
+
+pub struct Parser {
+    state: State,
+    csi: Csi,
+    param_started: bool,
+}
+
+impl Parser {
+    pub const fn new() -> Self {
+        Parser {
+            state: State::Ground,
+            csi: Csi {
+                params: [0; MAX_PARAMS],
+                len: 0,
+                private: false,
+                final_byte: 0,
+            },
+            param_started: false,
+        }
+    }
+
+    /*
+        Feed one character, returns what the terminal should do once a character or a sequence is complete
+     */
+    pub fn advance(&mut self, c: char) -> Option<Action> {
+        if c == CAN || c == SUB {
+            self.state = State::Ground;
+            return None;
+        }
+        if c == ESC {
+            self.state = State::Escape;
+            return None;
+        }
+
+        match self.state {
+            State::Ground => match c {
+                '\x00'..='\x1f' | '\x7f' => Some(Action::Execute(c as u8)),
+                c => Some(Action::Print(c)),
+            },
+            State::Escape => match c {
+                '[' => {
+                    self.start_csi();
+                    None
+                }
+                '\x20'..='\x2f' => None, // intermediate bytes, wait for the final one
+                '\x30'..='\x7e' => {
+                    self.state = State::Ground;
+                    Some(Action::Esc(c as u8))
+                }
+                _ => {
+                    self.state = State::Ground;
+                    None
+                }
+            },
+            State::Csi => self.advance_csi(c),
+        }
+    }
+
+    fn start_csi(&mut self) {
+        self.state = State::Csi;
+        self.csi.len = 0;
+        self.csi.private = false;
+        self.param_started = false;
+    }
+
+    fn advance_csi(&mut self, c: char) -> Option<Action> {
+        match c {
+            '0'..='9' => {
+                if !self.param_started {
+                    self.push_param();
+                }
+                if let Some(param) = self.csi.params[..self.csi.len].last_mut() {
+                    let digit = c as u16 - '0' as u16;
+                    *param = param.saturating_mul(10).saturating_add(digit);
+                }
+                None
+            }
+            ';' => {
+                if !self.param_started {
+                    self.push_param(); // empty parameter, e.g. ESC [ ; 5 H
+                }
+                self.param_started = false;
+                None
+            }
+            '?' if self.csi.len == 0 && !self.param_started => {
+                self.csi.private = true;
+                None
+            }
+            '\x40'..='\x7e' => {
+                self.state = State::Ground;
+                self.csi.final_byte = c as u8;
+                Some(Action::Csi(self.csi))
+            }
+            '\x00'..='\x1f' => Some(Action::Execute(c as u8)), // C0 controls are executed even inside a sequence
+            _ => None,
+        }
+    }
+
+    fn push_param(&mut self) {
+        self.param_started = true;
+        if self.csi.len < MAX_PARAMS {
+            self.csi.params[self.csi.len] = 0;
+            self.csi.len += 1;
+        }
+    }
+}
+
+#[test_case]
+fn test_parse_plain_text() {
+    let mut parser = Parser::new();
+    assert_eq!(parser.advance('a'), Some(Action::Print('a')));
+    assert_eq!(parser.advance('\n'), Some(Action::Execute(b'\n')));
+}
+
+#[test_case]
+fn test_parse_sgr() {
+    let mut parser = Parser::new();
+    let mut last = None;
+    for c in "\x1b[1;31m".chars() {
+        last = parser.advance(c);
+    }
+    match last {
+        Some(Action::Csi(csi)) => {
+            assert_eq!(csi.final_byte, b'm');
+            assert_eq!(csi.params(), &[1, 31]);
+            assert!(!csi.private);
+        }
+        other => panic!("unexpected action {:?}", other),
+    }
+    assert_eq!(parser.advance('x'), Some(Action::Print('x')));
+}
+
+#[test_case]
+fn test_parse_defaults_and_private() {
+    let mut parser = Parser::new();
+    let mut last = None;
+    for c in "\x1b[;7H".chars() {
+        last = parser.advance(c);
+    }
+    match last {
+        Some(Action::Csi(csi)) => {
+            assert_eq!(csi.param_or(0, 1), 1);
+            assert_eq!(csi.param_or(1, 1), 7);
+        }
+        other => panic!("unexpected action {:?}", other),
+    }
+
+    for c in "\x1b[?25".chars() {
+        assert_eq!(parser.advance(c), None);
+    }
+    match parser.advance('l') {
+        Some(Action::Csi(csi)) => assert!(csi.private && csi.params() == [25]),
+        other => panic!("unexpected action {:?}", other),
+    }
+}
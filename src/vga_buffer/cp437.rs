@@ -0,0 +1,71 @@
+/*
+    Unicode -> code page 437 translation
+
+    The VGA text mode font is code page 437: ASCII in 0x20..=0x7e, and graphical glyphs in
+    the control range and in 0x80..=0xff (accented letters, box drawing, blocks, greek, math).
+ */
+
+// Glyphs of the control range 0x00..=0x1f, 0x00 is a blank cell
+const LOW: [char; 32] = [
+    '\0', '☺', '☻', '♥', '♦', '♣', '♠', '•', '◘', '○', '◙', '♂', '♀', '♪', '♫', '☼',
+    '►', '◄', '↕', '‼', '¶', '§', '▬', '↨', '↑', '↓', '→', '←', '∟', '↔', '▲', '▼',
+];
+
+// Glyphs of 0x80..=0xff
+const HIGH: [char; 128] = [
+    'Ç', 'ü', 'é', 'â', 'ä', 'à', 'å', 'ç', 'ê', 'ë', 'è', 'ï', 'î', 'ì', 'Ä', 'Å', // 0x80
+    'É', 'æ', 'Æ', 'ô', 'ö', 'ò', 'û', 'ù', 'ÿ', 'Ö', 'Ü', '¢', '£', '¥', '₧', 'ƒ', // 0x90
+    'á', 'í', 'ó', 'ú', 'ñ', 'Ñ', 'ª', 'º', '¿', '⌐', '¬', '½', '¼', '¡', '«', '»', // 0xa0
+    '░', '▒', '▓', '│', '┤', '╡', '╢', '╖', '╕', '╣', '║', '╗', '╝', '╜', '╛', '┐', // 0xb0
+    '└', '┴', '┬', '├', '─', '┼', '╞', '╟', '╚', '╔', '╩', '╦', '╠', '═', '╬', '╧', // 0xc0
+    '╨', '╤', '╥', '╙', '╘', '╒', '╓', '╫', '╪', '┘', '┌', '█', '▄', '▌', '▐', '▀', // 0xd0
+    'α', 'ß', 'Γ', 'π', 'Σ', 'σ', 'µ', 'τ', 'Φ', 'Θ', 'Ω', 'δ', '∞', 'φ', 'ε', '∩', // 0xe0
+    '≡', '±', '≥', '≤', '⌠', '⌡', '÷', '≈', '°', '∙', '·', '√', 'ⁿ', '²', '■', '\u{a0}', // 0xf0
+];
+
+// Characters without their own glyph which look close enough to an existing one
+const ALIASES: [(char, u8); 6] = [
+    ('β', 0xe1), // ß is drawn as a beta in most VGA fonts
+    ('μ', 0xe6), // GREEK SMALL LETTER MU, CP437 has MICRO SIGN
+    ('∈', 0xee),
+    ('Ø', 0xed),
+    ('⌂', 0x7f),
+    ('∑', 0xe4),
+];
+
+/*
+    Glyph to display for `c`, None when CP437 has nothing which looks like it
+ */
+pub fn from_char(c: char) -> Option<u8> {
+    match c {
+        '\x20'..='\x7e' => Some(c as u8),
+        '\0' => None,
+        _ => LOW.iter().position(|&glyph| glyph == c).map(|index| index as u8)
+            .or_else(|| HIGH.iter().position(|&glyph| glyph == c).map(|index| 0x80 + index as u8))
+            .or_else(|| ALIASES.iter().find(|&&(alias, _)| alias == c).map(|&(_, glyph)| glyph)),
+    }
+}
+
+#[test_case]
+fn test_cp437_ascii() {
+    assert_eq!(from_char('A'), Some(b'A'));
+    assert_eq!(from_char('~'), Some(b'~'));
+}
+
+#[test_case]
+fn test_cp437_graphics() {
+    assert_eq!(from_char('é'), Some(0x82));
+    assert_eq!(from_char('Ñ'), Some(0xa5));
+    assert_eq!(from_char('┌'), Some(0xda));
+    assert_eq!(from_char('═'), Some(0xcd));
+    assert_eq!(from_char('→'), Some(0x1a));
+    assert_eq!(from_char('█'), Some(0xdb));
+    assert_eq!(from_char('░'), Some(0xb0));
+    assert_eq!(from_char('β'), Some(0xe1));
+}
+
+#[test_case]
+fn test_cp437_fallback() {
+    assert_eq!(from_char('中'), None);
+    assert_eq!(from_char('😀'), None);
+}
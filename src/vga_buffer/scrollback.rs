@@ -0,0 +1,63 @@
+use alloc::collections::VecDeque;
+use super::{ScreenChar, BUFFER_HEIGHT, BUFFER_WIDTH};
+
+pub type Line = [ScreenChar; BUFFER_WIDTH];
+
+/*
+    Lines which scrolled off the top of a console, oldest first.
+    The history grows on demand, up to `capacity` lines.
+ */
+pub struct Scrollback {
+    history: VecDeque<Line>,
+    capacity: usize,
+    offset: usize, // how many lines the view is moved up, 0 is the live screen
+}
+
+impl Scrollback {
+    pub fn new(capacity: usize) -> Self {
+        Scrollback {
+            history: VecDeque::new(),
+            capacity,
+            offset: 0,
+        }
+    }
+
+    pub fn push(&mut self, line: Line) {
+        if self.capacity == 0 {
+            return;
+        }
+        if self.history.len() == self.capacity {
+            self.history.pop_front();
+        }
+        self.history.push_back(line);
+    }
+
+    pub fn len(&self) -> usize {
+        self.history.len()
+    }
+
+    pub fn offset(&self) -> usize {
+        self.offset
+    }
+
+    pub fn is_scrolled(&self) -> bool {
+        self.offset != 0
+    }
+
+    // Clamped to the available history, returns the new offset
+    pub fn set_offset(&mut self, offset: usize) -> usize {
+        self.offset = offset.min(self.history.len());
+        self.offset
+    }
+
+    /*
+        Line shown on screen row `row` for the current offset, the lines below the history come from the live screen
+     */
+    pub fn view_line<'a>(&'a self, row: usize, screen: &'a [Line; BUFFER_HEIGHT]) -> &'a Line {
+        let index = self.history.len() - self.offset + row;
+        match self.history.get(index) {
+            Some(line) => line,
+            None => &screen[index - self.history.len()],
+        }
+    }
+}
@@ -0,0 +1,68 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(blog_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use blog_os::framebuffer::{self, console, Rgb};
+use blog_os::graphics::Surface;
+use blog_os::println;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use blog_os::memory::{self, BootInfoFrameAllocator};
+    use x86_64::VirtAddr;
+
+    blog_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe {
+        memory::init(phys_mem_offset)
+    };
+
+    let mut frame_allocator = unsafe {
+        BootInfoFrameAllocator::init(&boot_info.memory_map)
+    };
+
+    let framebuffer = framebuffer::init(1024, 768, &mut mapper, &mut frame_allocator)
+        .expect("framebuffer initialization failed");
+    console::init(framebuffer);
+
+    test_main();
+    blog_os::hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    blog_os::test_panic_handler(info)
+}
+
+#[test_case]
+fn test_resolution() {
+    let console = console::CONSOLE.lock();
+    let console = console.as_ref().expect("console not initialized");
+    assert_eq!(console.size(), (1024 / 8, 768 / 16));
+}
+
+#[test_case]
+fn test_println_draws_glyphs() {
+    use x86_64::instructions::interrupts;
+
+    println!("\x1b[2J\x1b[HA");
+    interrupts::without_interrupts(|| {
+        let mut console = console::CONSOLE.lock();
+        let framebuffer = console.as_mut().expect("console not initialized").framebuffer();
+        // The top of the 'A' glyph in the first cell: ...#....
+        assert_eq!(framebuffer.pixel(3, 2), Some(Rgb::new(0xff, 0xff, 0x55)));
+        assert_eq!(framebuffer.pixel(2, 2), Some(Rgb::BLACK));
+    });
+}
+
+#[test_case]
+fn test_println_many() {
+    for _ in 0..100 {
+        println!("test_println_many output, longer than the 80 columns of the text mode console..........");
+    }
+}
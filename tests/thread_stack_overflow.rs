@@ -0,0 +1,74 @@
+#![no_std]
+#![no_main]
+
+extern crate alloc;
+
+use bootloader::{BootInfo, entry_point};
+use core::{fmt::{self, Write}, panic::PanicInfo};
+use blog_os::{QemuExitCode, allocator, exit_qemu, memory::{self, BootInfoFrameAllocator}, serial_print, serial_println, thread};
+use x86_64::VirtAddr;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    serial_print!("thread_stack_overflow::large_frames...\t");
+
+    blog_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+    memory::install(mapper, frame_allocator);
+    thread::init().expect("thread initialization failed");
+
+    // A stack below the one which overflows, a frame jumping over the guard page would land in it
+    let _below = thread::spawn("below", || loop { thread::yield_now() }).expect("spawn");
+    let overflowing = thread::spawn("overflowing", || large_frames(0)).expect("spawn");
+    overflowing.join();
+
+    serial_println!("[thread stack did not overflow]");
+    exit_qemu(QemuExitCode::Failed);
+    blog_os::hlt_loop();
+}
+
+// Every frame is larger than the guard page, only stack probes make it touch the guard page
+#[allow(unconditional_recursion)]
+fn large_frames(depth: u64) -> u64 {
+    let mut frame = [0u8; 3 * 4096];
+    frame[0] = depth as u8;
+    core::hint::black_box(&mut frame);
+    large_frames(depth + 1) + u64::from(frame[4096])
+}
+
+// The double fault handler reports the overflow with a panic
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    let mut message = Message { buffer: [0; 128], len: 0 };
+    let _ = write!(message, "{}", info.message());
+    let message = core::str::from_utf8(&message.buffer[..message.len]).unwrap_or("");
+    if message.contains("thread stack") && message.contains("overflowed") {
+        serial_println!("[ok]");
+        exit_qemu(QemuExitCode::Success);
+    } else {
+        blog_os::test_panic_handler(info);
+    }
+    blog_os::hlt_loop();
+}
+
+// The start of the panic message, the rest is cut off
+struct Message {
+    buffer: [u8; 128],
+    len: usize,
+}
+
+impl Write for Message {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for &byte in s.as_bytes() {
+            if self.len < self.buffer.len() {
+                self.buffer[self.len] = byte;
+                self.len += 1;
+            }
+        }
+        Ok(())
+    }
+}